@@ -0,0 +1,411 @@
+use std::{
+  cell::RefCell,
+  collections::HashMap,
+  ffi::{CStr, CString},
+  path::PathBuf,
+  rc::Rc,
+  sync::Arc,
+};
+
+use anyhow::{anyhow, bail, Result};
+use gtk4::{gdk, gio, glib, prelude::*};
+use tracing::{info, warn};
+
+use crate::{
+  config::{Config, WallpaperMode},
+  locker::{LockEvent, Locker},
+  logging, wallpaper,
+};
+
+const ACCOUNTS_NAME: &str = "org.freedesktop.Accounts";
+const ACCOUNTS_PATH: &str = "/org/freedesktop/Accounts";
+const ACCOUNTS_MANAGER: &str = "org.freedesktop.Accounts";
+const ACCOUNTS_USER: &str = "org.freedesktop.Accounts.User";
+const PROPERTIES: &str = "org.freedesktop.DBus.Properties";
+/// Gives up on passwd entries larger than this
+const MAX_PASSWD_BUFFER: usize = 1 << 20;
+
+/// What the avatar shows once the image lookup finished
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Icon {
+  Pending,
+  Image(PathBuf),
+  Initials,
+}
+
+thread_local! {
+  /// Looked up off the main thread for each lock and shared by its windows
+  static ACCOUNT: RefCell<Option<Rc<Account>>> = const { RefCell::new(None) };
+  static ICON: RefCell<Icon> = const { RefCell::new(Icon::Pending) };
+  static AVATARS: RefCell<Vec<Avatar>> = const { RefCell::new(Vec::new()) };
+  static NAMES: RefCell<Vec<glib::WeakRef<gtk4::Label>>> = const { RefCell::new(Vec::new()) };
+  /// Circular avatars by image and size in physical pixels
+  static TEXTURES: RefCell<HashMap<(PathBuf, i32), gdk::Texture>> = RefCell::new(HashMap::new());
+}
+
+/// The user being authenticated, from the passwd database
+#[derive(Debug, PartialEq, Eq)]
+pub struct Account {
+  pub name: String,
+  /// The first field of GECOS, if set
+  pub real_name: Option<String>,
+  pub home: Option<PathBuf>,
+}
+
+impl Account {
+  /// Blocks on NSS, which may go over the network, so it is only called on
+  /// worker threads
+  fn lookup(name: &str) -> Account {
+    let mut account = Account {
+      name: name.to_string(),
+      real_name: None,
+      home: None,
+    };
+
+    let Ok(c_name) = CString::new(name) else {
+      return account;
+    };
+
+    let size = unsafe { libc::sysconf(libc::_SC_GETPW_R_SIZE_MAX) };
+    let mut buf = vec![0 as libc::c_char; usize::try_from(size).unwrap_or(1024).max(1024)];
+    let mut pw: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut result = std::ptr::null_mut();
+    loop {
+      let rc = unsafe {
+        libc::getpwnam_r(
+          c_name.as_ptr(),
+          &mut pw,
+          buf.as_mut_ptr(),
+          buf.len(),
+          &mut result,
+        )
+      };
+
+      match rc {
+        libc::ERANGE if buf.len() < MAX_PASSWD_BUFFER => buf.resize(buf.len() * 2, 0),
+        0 => break,
+        rc => {
+          warn!(
+            "failed to look up user {name}: {}",
+            std::io::Error::from_raw_os_error(rc)
+          );
+          return account;
+        }
+      }
+    }
+
+    if result.is_null() {
+      warn!("user {name} not found in passwd");
+      return account;
+    }
+
+    let gecos = unsafe { CStr::from_ptr(pw.pw_gecos) }.to_string_lossy();
+    account.real_name = gecos
+      .split(',')
+      .next()
+      .map(str::trim)
+      .filter(|real_name| !real_name.is_empty())
+      .map(str::to_string);
+
+    let home = unsafe { CStr::from_ptr(pw.pw_dir) }.to_string_lossy();
+    account.home = (!home.is_empty()).then(|| PathBuf::from(home.as_ref()));
+
+    account
+  }
+
+  /// The real name, or the user name if there is none
+  pub fn display_name(&self) -> &str {
+    self.real_name.as_deref().unwrap_or(&self.name)
+  }
+
+  /// The first letters of the first and last words of the display name
+  fn initials(&self) -> String {
+    let mut words = self.display_name().split_whitespace();
+    let first = words.next().and_then(|word| word.chars().next());
+    let last = words.last().and_then(|word| word.chars().next());
+    first
+      .into_iter()
+      .chain(last)
+      .flat_map(char::to_uppercase)
+      .collect()
+  }
+}
+
+async fn accounts_service_icon(user: &str) -> Result<PathBuf> {
+  let conn = gio::bus_get_future(gio::BusType::System).await?;
+  let reply = conn
+    .call_future(
+      Some(ACCOUNTS_NAME),
+      ACCOUNTS_PATH,
+      ACCOUNTS_MANAGER,
+      "FindUserByName",
+      Some(&(user,).to_variant()),
+      Some(glib::VariantTy::new("(o)")?),
+      gio::DBusCallFlags::NONE,
+      -1,
+    )
+    .await?;
+
+  let (path,) = reply
+    .get::<(glib::variant::ObjectPath,)>()
+    .ok_or_else(|| anyhow!("unexpected reply {reply}"))?;
+
+  let reply = conn
+    .call_future(
+      Some(ACCOUNTS_NAME),
+      path.as_str(),
+      PROPERTIES,
+      "Get",
+      Some(&(ACCOUNTS_USER, "IconFile").to_variant()),
+      Some(glib::VariantTy::new("(v)")?),
+      gio::DBusCallFlags::NONE,
+      -1,
+    )
+    .await?;
+
+  let (value,) = reply
+    .get::<(glib::Variant,)>()
+    .ok_or_else(|| anyhow!("unexpected reply {reply}"))?;
+  let icon = value
+    .get::<String>()
+    .ok_or_else(|| anyhow!("unexpected IconFile {value}"))?;
+
+  // Users without an icon get a path that doesn't exist
+  let icon = PathBuf::from(icon);
+  if !icon.is_file() {
+    bail!("{} does not exist", icon.display());
+  }
+
+  Ok(icon)
+}
+
+/// The configured avatar, then the AccountsService icon, then `~/.face`
+async fn find_icon(config: &Config, account: &Account) -> Option<PathBuf> {
+  if let Some(avatar) = &config.appearance.avatar {
+    return Some(avatar.get_ref().clone());
+  }
+
+  match accounts_service_icon(&account.name).await {
+    Ok(icon) => return Some(icon),
+    Err(err) => info!("no AccountsService icon for {}: {err}", account.name),
+  }
+
+  account
+    .home
+    .as_ref()
+    .map(|home| home.join(".face"))
+    .filter(|face| face.is_file())
+}
+
+struct Avatar {
+  stack: glib::WeakRef<gtk4::Stack>,
+  picture: glib::WeakRef<gtk4::Picture>,
+  initials: glib::WeakRef<gtk4::Label>,
+  /// Width and height in physical pixels
+  size: i32,
+}
+
+impl Avatar {
+  fn update(&self) {
+    let (Some(stack), Some(picture)) = (self.stack.upgrade(), self.picture.upgrade()) else {
+      return;
+    };
+
+    if let (Some(initials), Some(account)) = (self.initials.upgrade(), current()) {
+      initials.set_label(&account.initials());
+    }
+
+    match ICON.with_borrow(Clone::clone) {
+      Icon::Pending => {}
+      Icon::Initials => stack.set_visible_child_name("initials"),
+      Icon::Image(path) => {
+        stack.set_visible_child_name("image");
+        load(&picture, path, self.size);
+      }
+    }
+  }
+}
+
+/// The image scaled to cover a circle of `size` pixels, rendered in the
+/// background and cached
+async fn texture(path: PathBuf, size: i32) -> Option<gdk::Texture> {
+  let key = (path, size);
+  if let Some(texture) = TEXTURES.with_borrow(|textures| textures.get(&key).cloned()) {
+    return Some(texture);
+  }
+
+  let path = key.0.clone();
+  let rendered = gio::spawn_blocking(move || {
+    let mut pixels = wallpaper::render(&path, WallpaperMode::Fill, size, size, 0, 0.0)?;
+    pixels.mask_circle();
+    anyhow::Ok(pixels)
+  })
+  .await;
+
+  let pixels = match rendered {
+    Ok(Ok(pixels)) => pixels,
+    Ok(Err(err)) => {
+      warn!("failed to load avatar {}: {err}", key.0.display());
+      return None;
+    }
+    Err(_) => {
+      warn!("avatar renderer panicked on {}", key.0.display());
+      return None;
+    }
+  };
+
+  let texture = pixels.into_texture();
+  TEXTURES.with_borrow_mut(|textures| textures.insert(key, texture.clone()));
+  Some(texture)
+}
+
+/// Shows the image on `picture`, right away if it was rendered before
+fn load(picture: &gtk4::Picture, path: PathBuf, size: i32) {
+  let key = (path, size);
+  if let Some(texture) = TEXTURES.with_borrow(|textures| textures.get(&key).cloned()) {
+    picture.set_paintable(Some(&texture));
+    return;
+  }
+
+  let picture = picture.downgrade();
+  glib::spawn_future_local(async move {
+    let (path, size) = key;
+    if let (Some(texture), Some(picture)) = (texture(path, size).await, picture.upgrade()) {
+      picture.set_paintable(Some(&texture));
+    }
+  });
+}
+
+fn current() -> Option<Rc<Account>> {
+  ACCOUNT.with_borrow(Clone::clone)
+}
+
+fn update_names() {
+  let Some(account) = current() else {
+    return;
+  };
+
+  NAMES.with_borrow_mut(|names| {
+    names.retain(|name| name.upgrade().is_some());
+    for name in names.iter().filter_map(glib::WeakRef::upgrade) {
+      name.set_label(account.display_name());
+    }
+  });
+}
+
+fn update_all() {
+  AVATARS.with_borrow_mut(|avatars| {
+    avatars.retain(|avatar| avatar.stack.upgrade().is_some());
+    avatars.iter().for_each(Avatar::update);
+  });
+}
+
+/// Looks up the account off the main thread, then its avatar. Widgets update
+/// as each is found.
+pub async fn resolve(config: Arc<Config>) {
+  let lookup = {
+    let config = config.clone();
+    gio::spawn_blocking(move || Account::lookup(&config.auth.user()))
+  };
+  let Ok(account) = lookup.await else {
+    warn!("account lookup panicked");
+    return;
+  };
+
+  if let Some(real_name) = &account.real_name {
+    logging::redact(real_name);
+  }
+
+  let account = Rc::new(account);
+  let changed = ACCOUNT.with_borrow_mut(|current| {
+    let changed = current.as_deref() != Some(&*account);
+    *current = Some(account.clone());
+    changed
+  });
+  if changed {
+    update_names();
+    update_all();
+  }
+
+  let icon = match find_icon(&config, &account).await {
+    Some(path) => Icon::Image(path),
+    None => Icon::Initials,
+  };
+
+  let changed = ICON.with_borrow_mut(|current| std::mem::replace(current, icon.clone()) != icon);
+  if changed {
+    info!("using avatar {icon:?}");
+    update_all();
+  }
+}
+
+/// Renders the avatar for `size` logical pixels at `scale` ahead of time, so
+/// avatars created afterwards show it right away. Waits for nothing unless
+/// the account was resolved with an image.
+pub async fn preload_avatar(size: i32, scale: i32) {
+  if let Icon::Image(path) = ICON.with_borrow(Clone::clone) {
+    texture(path, size * scale.max(1)).await;
+  }
+}
+
+/// Looks up the account and avatar now and again for each lock, so a changed
+/// name or icon shows up without restarting
+pub fn follow(locker: &Locker, config: Arc<Config>) {
+  glib::spawn_future_local(resolve(config.clone()));
+
+  let events = locker.subscribe();
+  glib::spawn_future_local(async move {
+    while let Ok(event) = events.recv_async().await {
+      if matches!(event, LockEvent::Locking) {
+        glib::spawn_future_local(resolve(config.clone()));
+      }
+    }
+  });
+}
+
+/// The real name of the user, or the user name if there is none. Filled in
+/// once the account was looked up.
+pub fn name() -> gtk4::Label {
+  let label = gtk4::Label::builder().css_classes(["real-name"]).build();
+  if let Some(account) = current() {
+    label.set_label(account.display_name());
+  }
+
+  NAMES.with_borrow_mut(|names| names.push(label.downgrade()));
+  label
+}
+
+/// A circular avatar of `size` logical pixels, rendered for the output's
+/// `scale`. Users without an image get their initials instead.
+pub fn avatar(size: i32, scale: i32) -> gtk4::Widget {
+  let picture = gtk4::Picture::builder()
+    .can_shrink(true)
+    .content_fit(gtk4::ContentFit::Cover)
+    .build();
+
+  let initials = gtk4::Label::builder()
+    .css_classes(["avatar-initials"])
+    .build();
+
+  let stack = gtk4::Stack::builder()
+    .overflow(gtk4::Overflow::Hidden)
+    .halign(gtk4::Align::Center)
+    .width_request(size)
+    .height_request(size)
+    .css_classes(["avatar"])
+    .build();
+  stack.add_named(&picture, Some("image"));
+  stack.add_named(&initials, Some("initials"));
+
+  let avatar = Avatar {
+    stack: stack.downgrade(),
+    picture: picture.downgrade(),
+    initials: initials.downgrade(),
+    size: size * scale.max(1),
+  };
+
+  avatar.update();
+  AVATARS.with_borrow_mut(|avatars| avatars.push(avatar));
+
+  stack.upcast()
+}
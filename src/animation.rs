@@ -0,0 +1,299 @@
+use std::{
+  cell::{Cell, RefCell},
+  collections::HashSet,
+  path::Path,
+  rc::{Rc, Weak},
+  time::{Duration, SystemTime},
+};
+
+use gtk4::{
+  gdk,
+  gdk_pixbuf::{prelude::*, PixbufAnimation, PixbufAnimationIter},
+  gio, glib,
+  prelude::*,
+};
+use tracing::{info, warn};
+
+use crate::{
+  config::{AnimationConfig, WallpaperMode},
+  locker::idle::{self, IdleEvent},
+};
+
+/// Played through GTK's media backend
+const VIDEO_EXTENSIONS: [&str; 6] = ["mp4", "webm", "mkv", "mov", "avi", "ogv"];
+/// Decoded frame by frame with gdk-pixbuf
+const ANIMATION_EXTENSIONS: [&str; 2] = ["gif", "webp"];
+
+#[derive(Default)]
+struct Playback {
+  idle: bool,
+  /// Names of outputs the compositor reported as powered off
+  powered_off: HashSet<String>,
+  watching_idle: bool,
+}
+
+thread_local! {
+  static PLAYBACK: RefCell<Playback> = RefCell::new(Playback::default());
+  static ANIMATIONS: RefCell<Vec<Weak<Animation>>> = const { RefCell::new(Vec::new()) };
+}
+
+enum Kind {
+  Media(gtk4::MediaFile),
+  Frames(PixbufAnimationIter),
+}
+
+struct Animation {
+  kind: Kind,
+  output: Option<String>,
+  picture: glib::WeakRef<gtk4::Picture>,
+  /// Lower bound between frames of GIFs and WebPs, from the `max_fps`
+  /// budget. Videos are decoded by the media backend at their own rate.
+  min_interval: Option<Duration>,
+  mapped: Cell<bool>,
+  playing: Cell<bool>,
+  timer: RefCell<Option<glib::SourceId>>,
+}
+
+impl Animation {
+  fn should_play(&self) -> bool {
+    self.mapped.get()
+      && PLAYBACK.with_borrow(|playback| {
+        !playback.idle
+          && !self
+            .output
+            .as_ref()
+            .is_some_and(|output| playback.powered_off.contains(output))
+      })
+  }
+
+  fn update(self: &Rc<Self>) {
+    let play = self.should_play();
+    if self.playing.replace(play) == play {
+      return;
+    }
+
+    if let Kind::Media(media) = &self.kind {
+      if play {
+        media.play();
+      } else {
+        media.pause();
+      }
+    }
+
+    if !matches!(self.kind, Kind::Frames(_)) {
+      return;
+    }
+
+    if play {
+      self.schedule(Duration::ZERO);
+    } else if let Some(timer) = self.timer.take() {
+      timer.remove();
+    }
+  }
+
+  fn schedule(self: &Rc<Self>, delay: Duration) {
+    let animation = Rc::downgrade(self);
+    let timer = glib::timeout_add_local_once(delay, move || {
+      if let Some(animation) = animation.upgrade() {
+        animation.timer.take();
+        animation.tick();
+      }
+    });
+
+    if let Some(previous) = self.timer.replace(Some(timer)) {
+      previous.remove();
+    }
+  }
+
+  fn tick(self: &Rc<Self>) {
+    let Some(picture) = self.picture.upgrade() else {
+      return;
+    };
+
+    let Kind::Frames(iter) = &self.kind else {
+      return;
+    };
+
+    // Advancing to the current time skips any frames that are due
+    iter.advance(SystemTime::now());
+    picture.set_paintable(Some(&gdk::Texture::for_pixbuf(&iter.pixbuf())));
+
+    let Some(delay) = iter.delay_time() else {
+      // A single frame
+      return;
+    };
+
+    self.schedule(self.min_interval.map_or(delay, |min| delay.max(min)));
+  }
+}
+
+fn update_all() {
+  let animations: Vec<_> = ANIMATIONS.with_borrow_mut(|animations| {
+    animations.retain(|animation| animation.strong_count() > 0);
+    animations.iter().filter_map(Weak::upgrade).collect()
+  });
+
+  for animation in animations {
+    animation.update();
+  }
+}
+
+fn has_extension(path: &Path, extensions: &[&str]) -> bool {
+  path
+    .extension()
+    .and_then(|ext| ext.to_str())
+    .is_some_and(|ext| extensions.contains(&ext.to_ascii_lowercase().as_str()))
+}
+
+/// Whether the file should be played instead of shown as a still image
+pub fn is_animated(path: &Path) -> bool {
+  has_extension(path, &VIDEO_EXTENSIONS) || has_extension(path, &ANIMATION_EXTENSIONS)
+}
+
+/// Pauses animations on an output while it is powered off. Must be called on
+/// the main thread.
+pub fn set_output_power(output: &str, on: bool) {
+  let changed = PLAYBACK.with_borrow_mut(|playback| {
+    if on {
+      playback.powered_off.remove(output)
+    } else {
+      playback.powered_off.insert(output.to_string())
+    }
+  });
+
+  if changed {
+    info!("output {output} powered {}", if on { "on" } else { "off" });
+    update_all();
+  }
+}
+
+/// Pauses all animations once the user has been inactive for `timeout`
+fn watch_idle(timeout: Duration) {
+  let watching =
+    PLAYBACK.with_borrow_mut(|playback| std::mem::replace(&mut playback.watching_idle, true));
+  if watching {
+    return;
+  }
+
+  let (idle_tx, idle_rx) = flume::unbounded::<IdleEvent>();
+  if let Err(err) = idle::watch(timeout, idle_tx) {
+    warn!("failed to watch for inactivity, animations won't pause: {err}");
+    return;
+  }
+
+  glib::spawn_future_local(async move {
+    while let Ok(event) = idle_rx.recv_async().await {
+      PLAYBACK.with_borrow_mut(|playback| playback.idle = event == IdleEvent::Idle);
+      update_all();
+    }
+  });
+}
+
+fn register(animation: Animation, picture: &gtk4::Picture) {
+  let animation = Rc::new(animation);
+  animation.mapped.set(picture.is_mapped());
+  ANIMATIONS.with_borrow_mut(|animations| animations.push(Rc::downgrade(&animation)));
+
+  // The picture's handlers own the animation, so it stops with the window
+  {
+    let animation = animation.clone();
+    picture.connect_map(move |_| {
+      animation.mapped.set(true);
+      animation.update();
+    });
+  }
+
+  {
+    let animation = animation.clone();
+    picture.connect_unmap(move |_| {
+      animation.mapped.set(false);
+      animation.update();
+    });
+  }
+
+  animation.update();
+}
+
+/// Returns a picture playing a video or animated image. Animations pause
+/// while the output is powered off, when the user is inactive and when the
+/// picture is no longer shown.
+pub fn background(
+  path: &Path,
+  mode: WallpaperMode,
+  output: Option<&str>,
+  config: &AnimationConfig,
+) -> gtk4::Picture {
+  let content_fit = match mode {
+    WallpaperMode::Fill => gtk4::ContentFit::Cover,
+    WallpaperMode::Fit => gtk4::ContentFit::Contain,
+    WallpaperMode::Center => gtk4::ContentFit::ScaleDown,
+    WallpaperMode::Tile => {
+      warn!("animated wallpapers can't be tiled, filling the output instead");
+      gtk4::ContentFit::Cover
+    }
+  };
+
+  let picture = gtk4::Picture::builder()
+    .can_shrink(true)
+    .content_fit(content_fit)
+    .css_classes(["wallpaper"])
+    .build();
+
+  watch_idle(Duration::from_secs(config.pause_after()));
+
+  let output = output.map(str::to_string);
+  let min_interval = config
+    .max_fps
+    .as_ref()
+    .map(|fps| Duration::from_secs(1) / *fps.get_ref());
+
+  let picture_ref = picture.downgrade();
+  let animation = move |kind| Animation {
+    kind,
+    output,
+    picture: picture_ref,
+    min_interval,
+    mapped: Cell::new(false),
+    playing: Cell::new(false),
+    timer: RefCell::new(None),
+  };
+
+  if has_extension(path, &VIDEO_EXTENSIONS) {
+    let media = gtk4::MediaFile::for_filename(path);
+    media.set_loop(true);
+    media.set_muted(true);
+    picture.set_paintable(Some(&media));
+
+    register(animation(Kind::Media(media)), &picture);
+    return picture;
+  }
+
+  // Decode without blocking the main thread, the frames of a large GIF can
+  // take a while
+  let file = gio::File::for_path(path);
+  let path = path.to_path_buf();
+  let picture_ref = picture.downgrade();
+  glib::spawn_future_local(async move {
+    let loaded = match file.read_future(glib::Priority::DEFAULT).await {
+      Ok(stream) => PixbufAnimation::from_stream_future(&stream).await,
+      Err(err) => Err(err),
+    };
+
+    let frames = match loaded {
+      Ok(frames) => frames,
+      Err(err) => {
+        warn!("failed to load animation {}: {err}", path.display());
+        return;
+      }
+    };
+
+    if let Some(picture) = picture_ref.upgrade() {
+      register(
+        animation(Kind::Frames(frames.iter(Some(SystemTime::now())))),
+        &picture,
+      );
+    }
+  });
+
+  picture
+}
@@ -0,0 +1,8 @@
+pub const RESOURCE_PREFIX: &str = "/lol/happens/dash3";
+
+/// An image of a bundled icon. Icons are SVGs, so they are left to GTK to
+/// draw at the scale of each output rather than cached as a fixed size
+/// texture. Only the raster avatar is cached, see `account`.
+pub fn icon(name: &str) -> gtk4::Image {
+  gtk4::Image::from_resource(&format!("{RESOURCE_PREFIX}/{name}"))
+}
@@ -0,0 +1,363 @@
+use std::{cell::RefCell, path::Path, time::Duration};
+
+use anyhow::{anyhow, Result};
+use gtk4::{gio, glib, prelude::*};
+use tracing::{info, warn};
+
+use crate::config::BatteryConfig;
+
+const UPOWER_NAME: &str = "org.freedesktop.UPower";
+/// Combines all batteries into one device
+const UPOWER_DISPLAY_DEVICE: &str = "/org/freedesktop/UPower/devices/DisplayDevice";
+const UPOWER_DEVICE: &str = "org.freedesktop.UPower.Device";
+const PROPERTIES: &str = "org.freedesktop.DBus.Properties";
+/// UPower's device type for batteries
+const UPOWER_TYPE_BATTERY: u32 = 2;
+
+const POWER_SUPPLY_DIR: &str = "/sys/class/power_supply";
+/// sysfs has no change notifications worth relying on, so it is polled
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+  Charging,
+  Discharging,
+  Full,
+  /// Plugged in, but not charging, for example because of a charge limit
+  NotCharging,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Status {
+  percentage: f64,
+  state: State,
+  /// Until empty when discharging, or until full when charging
+  remaining: Option<Duration>,
+}
+
+thread_local! {
+  static STATUS: RefCell<Option<Status>> = const { RefCell::new(None) };
+  static WIDGETS: RefCell<Vec<Widget>> = const { RefCell::new(Vec::new()) };
+}
+
+impl State {
+  fn from_upower(state: u32) -> State {
+    match state {
+      1 => State::Charging,
+      2 | 3 | 6 => State::Discharging,
+      4 => State::Full,
+      // Pending charge is plugged in, but held back, like a charge limit
+      _ => State::NotCharging,
+    }
+  }
+
+  fn from_sysfs(status: &str) -> State {
+    match status {
+      "Charging" => State::Charging,
+      "Discharging" => State::Discharging,
+      "Full" => State::Full,
+      _ => State::NotCharging,
+    }
+  }
+}
+
+/// Like "1h 20m"
+fn format_duration(duration: Duration) -> String {
+  let minutes = duration.as_secs() / 60;
+  match (minutes / 60, minutes % 60) {
+    (0, minutes) => format!("{minutes}m"),
+    (hours, minutes) => format!("{hours}h {minutes}m"),
+  }
+}
+
+impl Status {
+  fn summary(&self) -> String {
+    let percentage = format!("{}%", self.percentage.round());
+    let detail = match (self.state, self.remaining) {
+      (State::Charging, Some(remaining)) => {
+        format!("charging, {} until full", format_duration(remaining))
+      }
+      (State::Charging, None) => "charging".to_string(),
+      (State::Discharging, Some(remaining)) => {
+        format!("{} remaining", format_duration(remaining))
+      }
+      (State::Discharging, None) => return percentage,
+      (State::Full, _) => "fully charged".to_string(),
+      (State::NotCharging, _) => "plugged in".to_string(),
+    };
+
+    format!("{percentage} · {detail}")
+  }
+}
+
+async fn upower_status(conn: &gio::DBusConnection) -> Result<Option<Status>> {
+  let reply = conn
+    .call_future(
+      Some(UPOWER_NAME),
+      UPOWER_DISPLAY_DEVICE,
+      PROPERTIES,
+      "GetAll",
+      Some(&(UPOWER_DEVICE,).to_variant()),
+      Some(glib::VariantTy::new("(a{sv})")?),
+      gio::DBusCallFlags::NONE,
+      -1,
+    )
+    .await?;
+
+  let properties = glib::VariantDict::new(Some(&reply.child_value(0)));
+  let get = |name: &str| {
+    properties
+      .lookup_value(name, None)
+      .ok_or_else(|| anyhow!("display device has no {name}"))
+  };
+
+  let is_present = get("IsPresent")?.get::<bool>().unwrap_or(false);
+  let kind = get("Type")?.get::<u32>().unwrap_or(0);
+  if !is_present || kind != UPOWER_TYPE_BATTERY {
+    return Ok(None);
+  }
+
+  let percentage = get("Percentage")?.get::<f64>().unwrap_or(0.0);
+  let state = State::from_upower(get("State")?.get::<u32>().unwrap_or(0));
+  let remaining = match state {
+    State::Charging => get("TimeToFull")?.get::<i64>(),
+    State::Discharging => get("TimeToEmpty")?.get::<i64>(),
+    State::Full | State::NotCharging => None,
+  };
+
+  Ok(Some(Status {
+    percentage,
+    state,
+    remaining: remaining
+      .filter(|secs| *secs > 0)
+      .map(|secs| Duration::from_secs(secs as u64)),
+  }))
+}
+
+fn read(dir: &Path, name: &str) -> Option<String> {
+  std::fs::read_to_string(dir.join(name))
+    .ok()
+    .map(|value| value.trim().to_string())
+}
+
+fn read_number(dir: &Path, name: &str) -> Option<f64> {
+  read(dir, name)?.parse().ok()
+}
+
+/// Combines all batteries in sysfs. Energy is reported in µWh and µW, or as
+/// charge in µAh and µA by some batteries.
+fn sysfs_status() -> Option<Status> {
+  let entries = std::fs::read_dir(POWER_SUPPLY_DIR).ok()?;
+
+  let (mut now, mut full, mut rate) = (0.0, 0.0, 0.0);
+  let mut capacities = Vec::new();
+  let mut states = Vec::new();
+  for entry in entries.filter_map(|entry| entry.ok()) {
+    let dir = entry.path();
+    if read(&dir, "type").as_deref() != Some("Battery")
+      || read(&dir, "present").as_deref() == Some("0")
+    {
+      continue;
+    }
+
+    if let Some(capacity) = read_number(&dir, "capacity") {
+      capacities.push(capacity);
+    }
+
+    states.push(State::from_sysfs(&read(&dir, "status").unwrap_or_default()));
+
+    let energy = ["energy", "charge"].into_iter().find_map(|kind| {
+      Some((
+        read_number(&dir, &format!("{kind}_now"))?,
+        read_number(&dir, &format!("{kind}_full"))?,
+      ))
+    });
+    if let Some((energy_now, energy_full)) = energy {
+      now += energy_now;
+      full += energy_full;
+    }
+
+    rate += read_number(&dir, "power_now")
+      .or_else(|| read_number(&dir, "current_now"))
+      .unwrap_or(0.0)
+      .abs();
+  }
+
+  if states.is_empty() {
+    return None;
+  }
+
+  let percentage = if full > 0.0 {
+    now / full * 100.0
+  } else {
+    capacities.iter().sum::<f64>() / capacities.len().max(1) as f64
+  };
+
+  // Any battery charging or discharging decides the overall state
+  let state = [State::Charging, State::Discharging, State::NotCharging]
+    .into_iter()
+    .find(|state| states.contains(state))
+    .unwrap_or(State::Full);
+
+  let hours = match state {
+    State::Charging if rate > 0.0 => Some((full - now).max(0.0) / rate),
+    State::Discharging if rate > 0.0 => Some(now / rate),
+    _ => None,
+  };
+
+  Some(Status {
+    percentage: percentage.clamp(0.0, 100.0),
+    state,
+    remaining: hours
+      .filter(|hours| *hours > 0.0 && full > 0.0)
+      .map(|hours| Duration::from_secs_f64(hours * 3600.0)),
+  })
+}
+
+/// The battery line and the critical warning of one window
+struct Widget {
+  container: glib::WeakRef<gtk4::Box>,
+  status: gtk4::Label,
+  warning: gtk4::Label,
+  low: u32,
+  critical: u32,
+}
+
+impl Widget {
+  fn update(&self) {
+    let Some(container) = self.container.upgrade() else {
+      return;
+    };
+
+    let Some(status) = STATUS.with_borrow(|status| *status) else {
+      container.set_visible(false);
+      return;
+    };
+
+    container.set_visible(true);
+    self.status.set_label(&status.summary());
+
+    let draining = status.state == State::Discharging;
+    let low = draining && status.percentage <= self.low as f64;
+    let critical = draining && status.percentage <= self.critical as f64;
+    for (class, enabled) in [
+      ("charging", status.state == State::Charging),
+      ("low", low),
+      ("critical", critical),
+    ] {
+      if enabled {
+        container.add_css_class(class);
+      } else {
+        container.remove_css_class(class);
+      }
+    }
+
+    self.warning.set_visible(critical);
+  }
+}
+
+fn set_status(status: Option<Status>) {
+  let changed = STATUS.with_borrow_mut(|current| std::mem::replace(current, status) != status);
+  if !changed {
+    return;
+  }
+
+  WIDGETS.with_borrow_mut(|widgets| {
+    widgets.retain(|widget| widget.container.upgrade().is_some());
+    widgets.iter().for_each(Widget::update);
+  });
+}
+
+/// Follows UPower's display device, or polls sysfs when UPower isn't running
+pub fn watch() {
+  glib::spawn_future_local(async move {
+    let conn = match gio::bus_get_future(gio::BusType::System).await {
+      Ok(conn) => conn,
+      Err(err) => {
+        warn!("failed to connect to the system bus: {err}");
+        poll_sysfs();
+        return;
+      }
+    };
+
+    match upower_status(&conn).await {
+      Ok(status) => set_status(status),
+      Err(err) => {
+        info!("UPower unavailable, reading batteries from sysfs: {err}");
+        poll_sysfs();
+        return;
+      }
+    }
+
+    let updates = conn.clone();
+    conn.signal_subscribe(
+      Some(UPOWER_NAME),
+      Some(PROPERTIES),
+      Some("PropertiesChanged"),
+      Some(UPOWER_DISPLAY_DEVICE),
+      None,
+      gio::DBusSignalFlags::NONE,
+      move |_, _, _, _, _, _| {
+        let conn = updates.clone();
+        glib::spawn_future_local(async move {
+          match upower_status(&conn).await {
+            Ok(status) => set_status(status),
+            Err(err) => warn!("failed to read battery status: {err}"),
+          }
+        });
+      },
+    );
+  });
+}
+
+fn poll_sysfs() {
+  set_status(sysfs_status());
+  glib::timeout_add_local(POLL_INTERVAL, || {
+    // Nothing to show without lock windows
+    let shown = WIDGETS.with_borrow(|widgets| {
+      widgets
+        .iter()
+        .any(|widget| widget.container.upgrade().is_some())
+    });
+
+    if shown {
+      set_status(sysfs_status());
+    }
+
+    glib::ControlFlow::Continue
+  });
+}
+
+/// The battery status, hidden on machines without a battery. The container
+/// gets the `charging`, `low` and `critical` style classes, and a warning is
+/// shown below it once the battery is critical.
+pub fn widget(config: &BatteryConfig) -> gtk4::Box {
+  let container = gtk4::Box::builder()
+    .orientation(gtk4::Orientation::Vertical)
+    .halign(gtk4::Align::Center)
+    .css_classes(["battery"])
+    .build();
+
+  let status = gtk4::Label::builder()
+    .css_classes(["battery-status"])
+    .build();
+  let warning = gtk4::Label::builder()
+    .label("Battery critically low, connect the charger")
+    .css_classes(["battery-warning"])
+    .build();
+  container.append(&status);
+  container.append(&warning);
+
+  let widget = Widget {
+    container: container.downgrade(),
+    status,
+    warning,
+    low: *config.low.get_ref(),
+    critical: *config.critical.get_ref(),
+  };
+
+  widget.update();
+  WIDGETS.with_borrow_mut(|widgets| widgets.push(widget));
+
+  container
+}
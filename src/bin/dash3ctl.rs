@@ -0,0 +1,65 @@
+use std::{
+  io::{BufRead, BufReader, Write},
+  net::Shutdown,
+  os::unix::net::UnixStream,
+  process::ExitCode,
+};
+
+use anyhow::{Context, Result};
+use clap::{Parser, Subcommand};
+
+#[path = "../socket.rs"]
+mod socket;
+
+#[derive(Debug, Parser)]
+#[command(version, about = "Control a running dash3 daemon")]
+struct Args {
+  #[command(subcommand)]
+  command: Command,
+}
+
+#[derive(Debug, Clone, Copy, Subcommand)]
+enum Command {
+  /// Lock the session
+  Lock,
+  /// Print the current lock state as JSON
+  Status,
+  /// Stream lock lifecycle events as JSON lines
+  Subscribe,
+}
+
+impl Command {
+  fn as_str(&self) -> &'static str {
+    match self {
+      Command::Lock => "lock",
+      Command::Status => "status",
+      Command::Subscribe => "subscribe",
+    }
+  }
+}
+
+fn run(command: Command) -> Result<()> {
+  let path = socket::path()?;
+  let mut stream = UnixStream::connect(&path)
+    .with_context(|| format!("failed to connect to {}, is dash3 running?", path.display()))?;
+
+  writeln!(stream, "{}", command.as_str())?;
+  stream.shutdown(Shutdown::Write)?;
+
+  for line in BufReader::new(stream).lines() {
+    println!("{}", line?);
+  }
+
+  Ok(())
+}
+
+fn main() -> ExitCode {
+  let args = Args::parse();
+  match run(args.command) {
+    Ok(()) => ExitCode::SUCCESS,
+    Err(err) => {
+      eprintln!("dash3ctl: {err:#}");
+      ExitCode::FAILURE
+    }
+  }
+}
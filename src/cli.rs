@@ -0,0 +1,134 @@
+use std::path::PathBuf;
+
+use clap::{builder::PossibleValuesParser, Parser, Subcommand};
+
+use crate::{
+  history::{self, Day},
+  hooks::Hook,
+  hygiene::LockAction,
+  locker::preview::PreviewMode,
+  render::{self, RenderState, Size},
+  theme,
+};
+
+#[derive(Debug, Parser)]
+#[command(version, about = "A GTK4 screen locker for Wayland")]
+pub struct Args {
+  #[command(subcommand)]
+  pub command: Option<Command>,
+
+  /// Config file to use instead of $XDG_CONFIG_HOME/dash3/config.toml
+  #[arg(long, value_name = "PATH")]
+  pub config: Option<PathBuf>,
+
+  /// Validate the config file and exit without locking
+  #[arg(long)]
+  pub check_config: bool,
+
+  /// Stay resident and lock on request instead of locking immediately
+  #[arg(long)]
+  pub daemon: bool,
+
+  /// Show the lock screen in a window without locking the session, for
+  /// working on themes. The password is "preview".
+  #[arg(
+    long,
+    value_name = "MODE",
+    value_enum,
+    num_args = 0..=1,
+    default_missing_value = "window",
+    conflicts_with = "daemon"
+  )]
+  pub preview: Option<PreviewMode>,
+
+  /// Lock after this many seconds of inactivity, unless inhibited
+  #[arg(long, value_name = "SECONDS", requires = "daemon")]
+  pub idle_timeout: Option<u64>,
+
+  /// Run a shell command on a lock event. Events are pre-lock, locked,
+  /// auth-failed, unlocked and finished. The session is locked once the
+  /// pre-lock commands exited or timed out.
+  #[arg(long = "hook", value_name = "EVENT=COMMAND")]
+  pub hooks: Vec<Hook>,
+
+  /// Kill hook commands still running after this many seconds
+  #[arg(long, value_name = "SECONDS")]
+  pub hook_timeout: Option<u64>,
+
+  /// Built-in actions to run once the session is locked
+  #[arg(
+    long = "on-lock",
+    value_name = "ACTION",
+    value_enum,
+    value_delimiter = ','
+  )]
+  pub lock_actions: Vec<LockAction>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+  /// Show the recorded locks, unlocks and failed attempts
+  History(HistoryArgs),
+  /// Render the lock screen to a PNG without locking the session
+  ///
+  /// GTK needs a display to build the lock screen, even though nothing is
+  /// shown on it: run this in a Wayland or X11 session, or under a headless
+  /// compositor like `weston --backend=headless`.
+  Render(RenderArgs),
+}
+
+#[derive(Debug, clap::Args)]
+pub struct HistoryArgs {
+  /// Only show days from this one on
+  #[arg(long, value_name = "YYYY-MM-DD", value_parser = history::parse_day)]
+  pub since: Option<Day>,
+
+  /// Only show days up to and including this one
+  #[arg(long, value_name = "YYYY-MM-DD", value_parser = history::parse_day)]
+  pub until: Option<Day>,
+
+  /// Print the total locked time per day instead of each record
+  #[arg(long)]
+  pub summary: bool,
+
+  /// History file to read instead of $XDG_STATE_HOME/dash3/history.jsonl
+  #[arg(long, value_name = "PATH")]
+  pub file: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, clap::Args)]
+pub struct RenderArgs {
+  /// Colour scheme to render instead of the configured one
+  #[arg(
+    long,
+    value_name = "SCHEME",
+    value_parser = PossibleValuesParser::new(theme::SCHEMES)
+  )]
+  pub theme: Option<String>,
+
+  /// Logical size of the screen
+  #[arg(
+    long,
+    value_name = "WIDTHxHEIGHT",
+    default_value = "1920x1080",
+    value_parser = render::parse_size
+  )]
+  pub size: Size,
+
+  /// Scale factor of the screen
+  #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..=4))]
+  pub scale: u32,
+
+  /// Authentication state to show
+  #[arg(long, value_enum, default_value_t = RenderState::Prompt)]
+  pub state: RenderState,
+
+  /// Show this local time instead of the current one, for reproducible
+  /// images
+  #[arg(long, value_name = "YYYY-MM-DDTHH:MM:SS")]
+  pub time: Option<String>,
+
+  /// PNG file to write
+  #[arg(short, long, value_name = "PATH")]
+  pub output: PathBuf,
+}
@@ -0,0 +1,223 @@
+use std::{
+  cell::RefCell,
+  rc::{Rc, Weak},
+  time::Duration,
+};
+
+use gtk4::{glib, prelude::*};
+use tracing::warn;
+
+use crate::{
+  config::ClockConfig,
+  locker::{LockEvent, Locker},
+};
+
+const DEFAULT_DATE_FORMAT: &str = "%A, %x";
+
+/// Conversions that change every second. Formats without them only need
+/// updating once a minute.
+const SECOND_CONVERSIONS: [&str; 6] = ["%S", "%T", "%X", "%r", "%c", "%s"];
+
+thread_local! {
+  /// When the compositor confirmed the current lock
+  static LOCKED_AT: RefCell<Option<glib::DateTime>> = const { RefCell::new(None) };
+  static CLOCKS: RefCell<Vec<Weak<Clock>>> = const { RefCell::new(Vec::new()) };
+  /// Shown instead of the current time, for reproducible renders
+  static FROZEN: RefCell<Option<glib::DateTime>> = const { RefCell::new(None) };
+}
+
+fn now() -> glib::DateTime {
+  FROZEN
+    .with_borrow(|frozen| frozen.clone())
+    .unwrap_or_else(|| glib::DateTime::now_local().expect("failed to get the local time"))
+}
+
+/// Whether GLib is able to format a date with `format`
+pub fn is_valid_format(format: &str) -> bool {
+  now().format(format).is_ok()
+}
+
+/// Locales using a 24 hour clock have no AM/PM designators
+fn locale_uses_12_hours() -> bool {
+  now().format("%p").is_ok_and(|am_pm| !am_pm.is_empty())
+}
+
+fn time_format(hours: Option<u32>, seconds: bool) -> String {
+  let twelve = hours.map_or_else(locale_uses_12_hours, |hours| hours == 12);
+  match (twelve, seconds) {
+    (true, true) => "%-I:%M:%S %p",
+    (true, false) => "%-I:%M %p",
+    (false, true) => "%H:%M:%S",
+    (false, false) => "%H:%M",
+  }
+  .to_string()
+}
+
+/// Rounds down to whole minutes, like "2h 12m"
+pub fn format_elapsed(elapsed: glib::TimeSpan) -> String {
+  let minutes = elapsed.as_minutes().max(0);
+  let (days, hours, minutes) = (minutes / (24 * 60), minutes / 60 % 24, minutes % 60);
+  match (days, hours) {
+    (0, 0) => format!("{minutes}m"),
+    (0, _) => format!("{hours}h {minutes}m"),
+    _ => format!("{days}d {hours}h"),
+  }
+}
+
+fn format(time: &glib::DateTime, format: &str) -> String {
+  match time.format(format) {
+    Ok(text) => text.into(),
+    Err(err) => {
+      warn!("failed to format time with {format:?}: {err}");
+      String::new()
+    }
+  }
+}
+
+/// Hours and minutes of `time`, in the configured or the locale's clock
+pub fn short_time(config: &ClockConfig, time: &glib::DateTime) -> String {
+  let hours = config.hours.as_ref().map(|hours| *hours.get_ref());
+  format(time, &time_format(hours, false))
+}
+
+struct Clock {
+  /// Updates stop once the container is destroyed
+  container: glib::WeakRef<gtk4::Box>,
+  time: Option<(gtk4::Label, String)>,
+  date: Option<(gtk4::Label, String)>,
+  locked_since: Option<(gtk4::Label, String)>,
+  every_second: bool,
+}
+
+impl Clock {
+  fn update(&self) {
+    let now = now();
+    for (label, pattern) in [&self.time, &self.date].into_iter().flatten() {
+      label.set_label(&format(&now, pattern));
+    }
+
+    if let Some((label, pattern)) = &self.locked_since {
+      let locked_at = LOCKED_AT.with_borrow(Clone::clone);
+      label.set_visible(locked_at.is_some());
+      if let Some(locked_at) = locked_at {
+        label.set_label(&format!(
+          "Locked since {} ({})",
+          format(&locked_at, pattern),
+          format_elapsed(now.difference(&locked_at)),
+        ));
+      }
+    }
+  }
+
+  /// Schedules the next update on the following second or minute boundary
+  fn schedule(self: Rc<Self>) {
+    let now = now();
+    let micros = if self.every_second {
+      1_000_000 - now.microsecond() as u64
+    } else {
+      (60 - now.second() as u64) * 1_000_000 - now.microsecond() as u64
+    };
+
+    // Waking slightly late keeps rounding from landing before the boundary
+    let delay = Duration::from_micros(micros) + Duration::from_millis(1);
+    glib::timeout_add_local_once(delay, move || {
+      if self.container.upgrade().is_some() {
+        self.update();
+        self.schedule();
+      }
+    });
+  }
+}
+
+fn update_all() {
+  let clocks: Vec<_> = CLOCKS.with_borrow_mut(|clocks| {
+    clocks.retain(|clock| clock.strong_count() > 0);
+    clocks.iter().filter_map(Weak::upgrade).collect()
+  });
+
+  for clock in clocks {
+    clock.update();
+  }
+}
+
+/// Shows a lock that started `locked_for` ago without following the locker.
+/// The clocks stop at `time` if given.
+pub fn simulate(time: Option<glib::DateTime>, locked_for: Duration) {
+  FROZEN.set(time);
+  LOCKED_AT.set(now().add_seconds(-(locked_for.as_secs() as f64)).ok());
+  update_all();
+}
+
+/// Records when each lock starts, for the locked since line
+pub fn follow(locker: &Locker) {
+  let events = locker.subscribe();
+  glib::spawn_future_local(async move {
+    while let Ok(event) = events.recv_async().await {
+      match event {
+        LockEvent::Locked => LOCKED_AT.set(Some(now())),
+        LockEvent::Unlocked | LockEvent::Finished => LOCKED_AT.set(None),
+        LockEvent::Locking | LockEvent::AuthFailed { .. } => continue,
+      }
+
+      update_all();
+    }
+  });
+}
+
+/// Returns the time, date and locked since lines that are enabled, or `None`
+/// if all of them are disabled
+pub fn widget(
+  config: &ClockConfig,
+  show_time: bool,
+  show_date: bool,
+  show_locked_since: bool,
+) -> Option<gtk4::Box> {
+  if !(show_time || show_date || show_locked_since) {
+    return None;
+  }
+
+  let container = gtk4::Box::builder()
+    .orientation(gtk4::Orientation::Vertical)
+    .halign(gtk4::Align::Center)
+    .css_classes(["clock"])
+    .build();
+
+  let line = |class: &str, format: String| {
+    let label = gtk4::Label::builder().css_classes([class]).build();
+    container.append(&label);
+    (label, format)
+  };
+
+  let hours = config.hours.as_ref().map(|hours| *hours.get_ref());
+  let time = config.time_format.as_ref().map_or_else(
+    || time_format(hours, config.seconds),
+    |format| format.get_ref().clone(),
+  );
+  let date = config
+    .date_format
+    .as_ref()
+    .map_or(DEFAULT_DATE_FORMAT, |format| format.get_ref().as_str())
+    .to_string();
+
+  let changes_every_second = |format: &str| {
+    SECOND_CONVERSIONS
+      .iter()
+      .any(|conversion| format.contains(conversion))
+  };
+  let every_second =
+    (show_time && changes_every_second(&time)) || (show_date && changes_every_second(&date));
+
+  let clock = Rc::new(Clock {
+    container: container.downgrade(),
+    time: show_time.then(|| line("clock-time", time)),
+    date: show_date.then(|| line("clock-date", date)),
+    locked_since: show_locked_since.then(|| line("locked-since", time_format(hours, false))),
+    every_second,
+  });
+
+  clock.update();
+  CLOCKS.with_borrow_mut(|clocks| clocks.push(Rc::downgrade(&clock)));
+  clock.schedule();
+
+  Some(container)
+}
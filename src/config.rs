@@ -0,0 +1,1136 @@
+use std::{
+  ffi::{CStr, CString},
+  ops::Range,
+  path::{Path, PathBuf},
+  ptr,
+};
+
+use serde::Deserialize;
+use thiserror::Error as ThisError;
+use toml::Spanned;
+
+use crate::{
+  clock,
+  hooks::{Hook, HookEvent},
+  hygiene::LockAction,
+  media::MediaControl,
+  power::PowerAction,
+  theme, wallpaper,
+};
+
+const CONFIG_FILE: &str = "dash3/config.toml";
+/// Used when `XDG_CONFIG_DIRS` is unset
+const SYSTEM_CONFIG_DIR: &str = "/etc/xdg";
+
+#[derive(Debug, ThisError)]
+pub enum ConfigError {
+  #[error("failed to read {}: {source}", path.display())]
+  Read {
+    path: PathBuf,
+    source: std::io::Error,
+  },
+  #[error("{}:{line}:{column}: {message}", path.display())]
+  Invalid {
+    path: PathBuf,
+    line: usize,
+    column: usize,
+    message: String,
+  },
+  #[error("{}: {message}", path.display())]
+  Other { path: PathBuf, message: String },
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+  pub auth: AuthConfig,
+  pub appearance: AppearanceConfig,
+  pub theme: ThemeConfig,
+  #[serde(rename = "wallpaper")]
+  pub wallpapers: Vec<WallpaperConfig>,
+  pub screenshot: ScreenshotConfig,
+  pub animation: AnimationConfig,
+  pub widgets: WidgetsConfig,
+  pub clock: ClockConfig,
+  pub battery: BatteryConfig,
+  pub media: MediaConfig,
+  pub keyboard: KeyboardConfig,
+  pub summary: SummaryConfig,
+  pub history: HistoryConfig,
+  pub logging: LoggingConfig,
+  pub power: PowerConfig,
+  pub hooks: HooksConfig,
+  pub timeouts: TimeoutsConfig,
+  pub lock: LockConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+  /// PAM service used to authenticate
+  pub service: String,
+  /// Defaults to the user running dash3
+  pub user: Option<String>,
+}
+
+impl Default for AuthConfig {
+  fn default() -> Self {
+    AuthConfig {
+      service: "dash3".to_string(),
+      user: None,
+    }
+  }
+}
+
+impl AuthConfig {
+  pub fn user(&self) -> String {
+    self.user.clone().unwrap_or_else(current_user)
+  }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AppearanceConfig {
+  /// Layered on top of the built-in theme. Defaults to
+  /// `$XDG_CONFIG_HOME/dash3/style.scss` if it exists.
+  pub stylesheet: Option<Spanned<PathBuf>>,
+  /// Defaults to the AccountsService icon, then `~/.face`, then the user's
+  /// initials
+  pub avatar: Option<Spanned<PathBuf>>,
+  pub avatar_size: Spanned<i32>,
+}
+
+impl Default for AppearanceConfig {
+  fn default() -> Self {
+    AppearanceConfig {
+      stylesheet: None,
+      avatar: None,
+      avatar_size: Spanned::new(0..0, 108),
+    }
+  }
+}
+
+/// Variables injected into the stylesheets. Unset values come from the
+/// colour scheme.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ThemeConfig {
+  /// Always use this scheme instead of following the system's light or dark
+  /// preference
+  pub scheme: Option<Spanned<String>>,
+  pub dark_scheme: Spanned<String>,
+  pub light_scheme: Spanned<String>,
+  pub accent: Option<Spanned<String>>,
+  pub background: Option<Spanned<String>>,
+  pub foreground: Option<Spanned<String>>,
+  /// In pixels
+  pub radius: Option<u32>,
+  pub font_family: Option<Spanned<String>>,
+  /// In points
+  pub font_size: Option<u32>,
+}
+
+impl Default for ThemeConfig {
+  fn default() -> Self {
+    ThemeConfig {
+      scheme: None,
+      dark_scheme: Spanned::new(0..0, "dark".to_string()),
+      light_scheme: Spanned::new(0..0, "light".to_string()),
+      accent: None,
+      background: None,
+      foreground: None,
+      radius: None,
+      font_family: None,
+      font_size: None,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WallpaperMode {
+  /// Scale to cover the output, cropping the edges
+  #[default]
+  Fill,
+  /// Scale to fit inside the output
+  Fit,
+  Center,
+  Tile,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WallpaperRotation {
+  /// Cycle through the images every `interval` seconds
+  #[default]
+  Slideshow,
+  /// Spread the images evenly over the day, in file name order
+  TimeOfDay,
+}
+
+/// A `[[wallpaper]]` entry. The first entry matching an output is used.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WallpaperConfig {
+  /// Output name like `DP-1`, or part of its description. Matches all
+  /// outputs if unset.
+  #[serde(default)]
+  pub output: Option<String>,
+  /// An image, or a directory of images to rotate through. Relative paths
+  /// are resolved against the directory of the config file.
+  pub path: Spanned<PathBuf>,
+  #[serde(default)]
+  pub mode: WallpaperMode,
+  #[serde(default)]
+  pub rotation: WallpaperRotation,
+  /// Seconds between images in a slideshow, defaults to 5 minutes
+  #[serde(default)]
+  pub interval: Option<Spanned<u64>>,
+  /// Blur radius in pixels
+  #[serde(default)]
+  pub blur: Option<Spanned<u32>>,
+  /// From 0, unchanged, to 1, black
+  #[serde(default)]
+  pub darken: Option<Spanned<f64>>,
+}
+
+impl WallpaperConfig {
+  pub fn interval(&self) -> u64 {
+    self.interval.as_ref().map_or(300, |i| *i.get_ref())
+  }
+
+  pub fn matches(&self, name: Option<&str>, description: Option<&str>) -> bool {
+    match &self.output {
+      None => true,
+      Some(output) => {
+        name == Some(output.as_str()) || description.is_some_and(|d| d.contains(output.as_str()))
+      }
+    }
+  }
+}
+
+/// Uses a capture of each output taken just before locking as its
+/// background. Outputs that can't be captured show their wallpaper instead.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScreenshotConfig {
+  pub enabled: bool,
+  /// Blur radius in pixels
+  pub blur: Option<Spanned<u32>>,
+  /// Block size in pixels
+  pub pixelate: Option<Spanned<u32>>,
+  /// From 0, none, to 1, black corners
+  pub vignette: Option<Spanned<f64>>,
+  /// From 0, unchanged, to 1, black
+  pub darken: Option<Spanned<f64>>,
+}
+
+/// Applies to wallpapers that are videos, GIFs or animated WebPs
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AnimationConfig {
+  /// Caps the frame rate of GIFs and animated WebPs, dropping frames to save
+  /// CPU. Videos play at their own frame rate, they are only paused.
+  pub max_fps: Option<Spanned<u32>>,
+  /// Pause after this many seconds without input, defaults to a minute
+  pub pause_after: Option<Spanned<u64>>,
+}
+
+impl AnimationConfig {
+  pub fn pause_after(&self) -> u64 {
+    self.pause_after.as_ref().map_or(60, |p| *p.get_ref())
+  }
+}
+
+/// Formats use strftime-style conversions, like `%H:%M`. Names and `%x`, `%X`
+/// and `%c` follow the locale.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClockConfig {
+  /// Overrides `hours` and `seconds`
+  pub time_format: Option<Spanned<String>>,
+  /// Defaults to the weekday followed by the locale's date
+  pub date_format: Option<Spanned<String>>,
+  /// 12 or 24, defaults to what the locale uses
+  pub hours: Option<Spanned<u32>>,
+  pub seconds: bool,
+}
+
+/// Charge levels in percent at which the battery widget warns while
+/// discharging
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BatteryConfig {
+  /// Adds the `low` style class
+  pub low: Spanned<u32>,
+  /// Adds the `critical` style class and shows a warning
+  pub critical: Spanned<u32>,
+}
+
+impl Default for BatteryConfig {
+  fn default() -> Self {
+    BatteryConfig {
+      low: Spanned::new(0..0, 20),
+      critical: Spanned::new(0..0, 10),
+    }
+  }
+}
+
+/// Controls for MPRIS players on the session bus
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MediaConfig {
+  /// Buttons usable without unlocking
+  pub controls: Vec<MediaControl>,
+  /// Pause all players when locking and resume them after unlocking
+  pub pause_on_lock: bool,
+}
+
+impl Default for MediaConfig {
+  fn default() -> Self {
+    MediaConfig {
+      controls: vec![
+        MediaControl::Previous,
+        MediaControl::PlayPause,
+        MediaControl::Next,
+      ],
+      pause_on_lock: false,
+    }
+  }
+}
+
+/// What happened while locked: failed attempts, PAM errors and how long the
+/// screen was locked
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SummaryConfig {
+  /// Show the summary on the lock screen right before unlocking
+  pub overlay: bool,
+  /// How long the overlay delays the unlock, in milliseconds
+  pub overlay_duration_ms: Spanned<u64>,
+  /// Send a desktop notification after unlocking
+  pub notify: bool,
+  /// Skip locks without failed attempts or PAM errors
+  pub only_on_failure: bool,
+}
+
+impl Default for SummaryConfig {
+  fn default() -> Self {
+    SummaryConfig {
+      overlay: true,
+      overlay_duration_ms: Spanned::new(0..0, 1500),
+      notify: true,
+      only_on_failure: true,
+    }
+  }
+}
+
+/// The record of locks, unlocks and failed attempts in
+/// `$XDG_STATE_HOME/dash3/history.jsonl`, shown by `dash3 history`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HistoryConfig {
+  pub enabled: bool,
+  /// The file is moved to `history.jsonl.1` once it grew past this size
+  pub max_size_kib: Spanned<u64>,
+  /// How many rotated files to keep
+  pub keep: Spanned<u32>,
+}
+
+impl Default for HistoryConfig {
+  fn default() -> Self {
+    HistoryConfig {
+      enabled: true,
+      max_size_kib: Spanned::new(0..0, 1024),
+      keep: Spanned::new(0..0, 3),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogTarget {
+  #[default]
+  Stderr,
+  /// The native journald protocol, with fields instead of formatted lines
+  Journald,
+  File,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+  #[default]
+  Text,
+  /// One JSON object per line
+  Json,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+  /// A level like "debug", or directives like "dash3=debug,warn".
+  /// `RUST_LOG` takes precedence.
+  pub level: Spanned<String>,
+  pub target: LogTarget,
+  /// Used with the file target. Defaults to `$XDG_STATE_HOME/dash3/dash3.log`.
+  pub file: Option<Spanned<PathBuf>>,
+  /// Of stderr and file logs
+  pub format: LogFormat,
+  /// Log the prompts and messages of PAM modules at debug level, with
+  /// digits, addresses and the user's names masked. Only their kind is
+  /// logged otherwise. Responses are never logged.
+  pub pam_transcript: bool,
+}
+
+impl Default for LoggingConfig {
+  fn default() -> Self {
+    LoggingConfig {
+      level: Spanned::new(0..0, "info".to_string()),
+      target: LogTarget::Stderr,
+      file: None,
+      format: LogFormat::Text,
+      pam_transcript: false,
+    }
+  }
+}
+
+/// Switching layouts while locked
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeyboardConfig {
+  /// Run with `sh -c` to switch to the next layout. Detected for sway,
+  /// Hyprland and niri.
+  pub switch_command: Option<Spanned<String>>,
+  /// Accelerator that switches to the next layout, empty to disable
+  pub switch_hotkey: Spanned<String>,
+  /// Also warn about Num Lock, for laptops where it turns letters into digits
+  pub warn_num_lock: bool,
+}
+
+impl Default for KeyboardConfig {
+  fn default() -> Self {
+    KeyboardConfig {
+      switch_command: None,
+      switch_hotkey: Spanned::new(0..0, "<Super>space".to_string()),
+      warn_num_lock: false,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WidgetsConfig {
+  pub avatar: bool,
+  pub power: bool,
+  /// The real name from GECOS, or the user name
+  pub name: bool,
+  pub clock: bool,
+  pub date: bool,
+  pub battery: bool,
+  /// The current track of an MPRIS player
+  pub media: bool,
+  /// Wi-Fi, wired and VPN connections, and whether the machine is offline
+  pub network: bool,
+  /// The active keyboard layout and the Caps Lock warning
+  pub keyboard: bool,
+  /// "Locked since 14:03 (2h 12m)"
+  pub locked_since: bool,
+}
+
+impl Default for WidgetsConfig {
+  fn default() -> Self {
+    WidgetsConfig {
+      avatar: true,
+      power: true,
+      name: true,
+      clock: true,
+      date: true,
+      battery: true,
+      media: true,
+      network: true,
+      keyboard: true,
+      locked_since: false,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PowerConfig {
+  pub buttons: Vec<PowerAction>,
+}
+
+impl Default for PowerConfig {
+  fn default() -> Self {
+    PowerConfig {
+      buttons: vec![
+        PowerAction::Reboot,
+        PowerAction::Suspend,
+        PowerAction::PowerOff,
+      ],
+    }
+  }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HooksConfig {
+  pub pre_lock: Vec<Spanned<String>>,
+  pub locked: Vec<Spanned<String>>,
+  pub auth_failed: Vec<Spanned<String>>,
+  pub unlocked: Vec<Spanned<String>>,
+  pub finished: Vec<Spanned<String>>,
+}
+
+impl HooksConfig {
+  fn by_event(&self) -> [(HookEvent, &Vec<Spanned<String>>); 5] {
+    [
+      (HookEvent::PreLock, &self.pre_lock),
+      (HookEvent::Locked, &self.locked),
+      (HookEvent::AuthFailed, &self.auth_failed),
+      (HookEvent::Unlocked, &self.unlocked),
+      (HookEvent::Finished, &self.finished),
+    ]
+  }
+
+  pub fn hooks(&self) -> Vec<Hook> {
+    let mut hooks = Vec::new();
+    for (event, commands) in self.by_event() {
+      for command in commands {
+        hooks.push(Hook {
+          event,
+          command: command.get_ref().clone(),
+        });
+      }
+    }
+
+    hooks
+  }
+}
+
+/// All timeouts are in seconds
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeoutsConfig {
+  /// Lock after this much inactivity when running as a daemon
+  pub idle: Option<Spanned<u64>>,
+  pub hook: Spanned<u64>,
+  /// How long a destructive power action stays armed after the first click
+  pub power_confirm: Spanned<u64>,
+  /// Refuse input this long after a failed attempt, doubling with each
+  /// consecutive failure. The delay requested by PAM is used when longer.
+  pub fail_delay: Option<Spanned<u64>>,
+  /// Upper bound of the doubling `fail_delay`
+  pub max_fail_delay: Spanned<u64>,
+}
+
+impl Default for TimeoutsConfig {
+  fn default() -> Self {
+    TimeoutsConfig {
+      idle: None,
+      hook: Spanned::new(0..0, 10),
+      power_confirm: Spanned::new(0..0, 5),
+      fail_delay: None,
+      max_fail_delay: Spanned::new(0..0, 60),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LockConfig {
+  /// Built-in actions to run once the session is locked
+  pub actions: Vec<LockAction>,
+}
+
+fn current_user() -> String {
+  let pw = unsafe { libc::getpwuid(libc::getuid()) };
+  if !pw.is_null() {
+    let name = unsafe { CStr::from_ptr((*pw).pw_name) };
+    return name.to_string_lossy().into_owned();
+  }
+
+  std::env::var("USER").unwrap_or_default()
+}
+
+/// Accepts hex colours, names and functions like `rgba(0, 0, 0, 0.5)`, but
+/// nothing that could end the SCSS declaration it is injected into
+fn is_color(value: &str) -> bool {
+  !value.is_empty()
+    && value
+      .chars()
+      .all(|c| c.is_ascii_alphanumeric() || "#(),.% -".contains(c))
+}
+
+fn expand_home(path: &Path) -> PathBuf {
+  match (path.strip_prefix("~"), std::env::var_os("HOME")) {
+    (Ok(rest), Some(home)) => PathBuf::from(home).join(rest),
+    _ => path.to_path_buf(),
+  }
+}
+
+/// Returns the 1-based line and column of a byte offset
+fn location(source: &str, offset: usize) -> (usize, usize) {
+  let before = &source[..offset.min(source.len())];
+  let line = before.matches('\n').count() + 1;
+  let column = before.rsplit('\n').next().map_or(0, |l| l.chars().count()) + 1;
+  (line, column)
+}
+
+pub fn config_home() -> Option<PathBuf> {
+  match std::env::var_os("XDG_CONFIG_HOME") {
+    Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir)),
+    _ => Some(PathBuf::from(std::env::var_os("HOME")?).join(".config")),
+  }
+}
+
+/// `$XDG_STATE_HOME`, which defaults to `~/.local/state`
+pub fn state_home() -> Option<PathBuf> {
+  match std::env::var_os("XDG_STATE_HOME") {
+    Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir)),
+    _ => Some(PathBuf::from(std::env::var_os("HOME")?).join(".local/state")),
+  }
+}
+
+/// `$XDG_STATE_HOME/dash3`, where the history and log file live
+pub fn state_dir() -> Option<PathBuf> {
+  Some(state_home()?.join("dash3"))
+}
+
+/// `$XDG_CONFIG_HOME/dash3`, where the config file and user stylesheet live
+pub fn user_dir() -> Option<PathBuf> {
+  Some(config_home()?.join("dash3"))
+}
+
+pub fn user_path() -> Option<PathBuf> {
+  Some(config_home()?.join(CONFIG_FILE))
+}
+
+/// `$XDG_CONFIG_DIRS`, which defaults to `/etc/xdg`
+fn config_dirs() -> Vec<PathBuf> {
+  let dirs = std::env::var_os("XDG_CONFIG_DIRS").unwrap_or_default();
+  let dirs = std::env::split_paths(&dirs)
+    .filter(|dir| dir.is_absolute())
+    .collect::<Vec<_>>();
+  if dirs.is_empty() {
+    return vec![PathBuf::from(SYSTEM_CONFIG_DIR)];
+  }
+
+  dirs
+}
+
+/// The config files in `$XDG_CONFIG_DIRS`, most important first
+fn system_paths() -> Vec<PathBuf> {
+  config_dirs()
+    .into_iter()
+    .map(|dir| dir.join(CONFIG_FILE))
+    .collect()
+}
+
+/// The user config if it exists, then the first system-wide one
+pub fn find() -> Option<PathBuf> {
+  user_path()
+    .into_iter()
+    .chain(system_paths())
+    .find(|path| path.exists())
+}
+
+/// Loads the config from `path`, or the default location. A missing config
+/// file is only an error when the path was given explicitly.
+pub fn load(path: Option<&Path>) -> Result<Option<(PathBuf, Config)>, ConfigError> {
+  let Some(path) = path.map(Path::to_path_buf).or_else(find) else {
+    return Ok(None);
+  };
+
+  let source = std::fs::read_to_string(&path).map_err(|source| ConfigError::Read {
+    path: path.clone(),
+    source,
+  })?;
+
+  let config = Config::parse(&path, &source)?;
+  Ok(Some((path, config)))
+}
+
+/// Turns validation failures into errors pointing into the config file
+struct Validator<'a> {
+  path: &'a Path,
+  source: &'a str,
+}
+
+impl Validator<'_> {
+  fn error(&self, span: Option<Range<usize>>, message: String) -> ConfigError {
+    match span {
+      Some(span) => {
+        let (line, column) = location(self.source, span.start);
+        ConfigError::Invalid {
+          path: self.path.to_path_buf(),
+          line,
+          column,
+          message,
+        }
+      }
+      None => ConfigError::Other {
+        path: self.path.to_path_buf(),
+        message,
+      },
+    }
+  }
+
+  fn check_max(
+    &self,
+    name: &str,
+    value: &Option<Spanned<u32>>,
+    max: u32,
+  ) -> Result<(), ConfigError> {
+    match value {
+      Some(value) if *value.get_ref() > max => {
+        Err(self.error(Some(value.span()), format!("{name} must be at most {max}")))
+      }
+      _ => Ok(()),
+    }
+  }
+
+  fn check_fraction(&self, name: &str, value: &Option<Spanned<f64>>) -> Result<(), ConfigError> {
+    match value {
+      Some(value) if !(0.0..=1.0).contains(value.get_ref()) => Err(self.error(
+        Some(value.span()),
+        format!("{name} must be between 0 and 1"),
+      )),
+      _ => Ok(()),
+    }
+  }
+
+  /// Replaces `~` in the path, resolves it against the directory of the
+  /// config file if it is relative, and checks that it exists
+  fn expand_path(&self, path: &mut Spanned<PathBuf>) -> Result<(), ConfigError> {
+    let span = path.span();
+    let expanded = expand_home(path.get_ref());
+    let expanded = match self.path.parent() {
+      Some(dir) if expanded.is_relative() => dir.join(expanded),
+      _ => expanded,
+    };
+    if !expanded.exists() {
+      return Err(self.error(Some(span), format!("{} does not exist", expanded.display())));
+    }
+
+    *path = Spanned::new(span, expanded);
+    Ok(())
+  }
+}
+
+/// Whether the value was read from the config file rather than a default
+fn is_set<T>(value: &Spanned<T>) -> bool {
+  value.span() != (0..0)
+}
+
+impl AppearanceConfig {
+  fn validate(&mut self, v: &Validator) -> Result<(), ConfigError> {
+    for file in [&mut self.stylesheet, &mut self.avatar]
+      .into_iter()
+      .flatten()
+    {
+      v.expand_path(file)?;
+      if !file.get_ref().is_file() {
+        return Err(v.error(
+          Some(file.span()),
+          format!("{} is not a file", file.get_ref().display()),
+        ));
+      }
+    }
+
+    let avatar_size = &self.avatar_size;
+    if !(16..=1024).contains(avatar_size.get_ref()) {
+      return Err(v.error(
+        Some(avatar_size.span()),
+        "avatar_size must be between 16 and 1024".to_string(),
+      ));
+    }
+
+    Ok(())
+  }
+}
+
+impl ThemeConfig {
+  fn validate(&self, v: &Validator) -> Result<(), ConfigError> {
+    for scheme in [&self.dark_scheme, &self.light_scheme]
+      .into_iter()
+      .chain(&self.scheme)
+    {
+      if !theme::SCHEMES.contains(&scheme.get_ref().as_str()) {
+        return Err(v.error(
+          Some(scheme.span()),
+          format!(
+            "unknown scheme {}, expected one of {}",
+            scheme.get_ref(),
+            theme::SCHEMES.join(", ")
+          ),
+        ));
+      }
+    }
+
+    for color in [&self.accent, &self.background, &self.foreground]
+      .into_iter()
+      .flatten()
+    {
+      if !is_color(color.get_ref()) {
+        return Err(v.error(
+          Some(color.span()),
+          format!("invalid colour {:?}", color.get_ref()),
+        ));
+      }
+    }
+
+    if let Some(family) = &self.font_family {
+      if family.get_ref().contains(['"', '\\', '\n']) {
+        return Err(v.error(
+          Some(family.span()),
+          "font_family must not contain quotes or backslashes".to_string(),
+        ));
+      }
+
+      if family
+        .get_ref()
+        .split(',')
+        .all(|name| name.trim().is_empty())
+      {
+        return Err(v.error(
+          Some(family.span()),
+          "font_family must name at least one font".to_string(),
+        ));
+      }
+    }
+
+    Ok(())
+  }
+}
+
+impl WallpaperConfig {
+  fn validate(&mut self, v: &Validator) -> Result<(), ConfigError> {
+    v.expand_path(&mut self.path)?;
+    let path = self.path.get_ref();
+    if path.is_dir() && wallpaper::images(path).is_empty() {
+      return Err(v.error(
+        Some(self.path.span()),
+        format!("{} contains no images", path.display()),
+      ));
+    }
+
+    if let Some(interval) = self.interval.as_ref().filter(|i| *i.get_ref() == 0) {
+      return Err(v.error(
+        Some(interval.span()),
+        "interval must be at least 1 second".to_string(),
+      ));
+    }
+
+    v.check_max("blur", &self.blur, 100)?;
+    v.check_fraction("darken", &self.darken)
+  }
+}
+
+impl ScreenshotConfig {
+  fn validate(&self, v: &Validator) -> Result<(), ConfigError> {
+    v.check_max("blur", &self.blur, 100)?;
+    v.check_max("pixelate", &self.pixelate, 256)?;
+    v.check_fraction("vignette", &self.vignette)?;
+    v.check_fraction("darken", &self.darken)
+  }
+}
+
+impl AnimationConfig {
+  fn validate(&self, v: &Validator) -> Result<(), ConfigError> {
+    if let Some(max_fps) = &self.max_fps {
+      if !(1..=240).contains(max_fps.get_ref()) {
+        return Err(v.error(
+          Some(max_fps.span()),
+          "max_fps must be between 1 and 240".to_string(),
+        ));
+      }
+    }
+
+    if let Some(pause_after) = self.pause_after.as_ref().filter(|p| *p.get_ref() == 0) {
+      return Err(v.error(
+        Some(pause_after.span()),
+        "pause_after must be at least 1 second".to_string(),
+      ));
+    }
+
+    Ok(())
+  }
+}
+
+impl ClockConfig {
+  fn validate(&self, v: &Validator) -> Result<(), ConfigError> {
+    if let Some(hours) = self
+      .hours
+      .as_ref()
+      .filter(|h| ![12, 24].contains(h.get_ref()))
+    {
+      return Err(v.error(Some(hours.span()), "hours must be 12 or 24".to_string()));
+    }
+
+    for format in [&self.time_format, &self.date_format].into_iter().flatten() {
+      if !clock::is_valid_format(format.get_ref()) {
+        return Err(v.error(
+          Some(format.span()),
+          format!("invalid format {:?}", format.get_ref()),
+        ));
+      }
+    }
+
+    Ok(())
+  }
+}
+
+impl BatteryConfig {
+  fn validate(&self, v: &Validator) -> Result<(), ConfigError> {
+    for level in [&self.low, &self.critical] {
+      if *level.get_ref() > 100 {
+        return Err(v.error(
+          Some(level.span()),
+          "battery levels must be between 0 and 100".to_string(),
+        ));
+      }
+    }
+
+    let (low, critical) = (*self.low.get_ref(), *self.critical.get_ref());
+    if critical > low {
+      // Points at the level that was set, the other one may be a default
+      return Err(if is_set(&self.critical) {
+        v.error(
+          Some(self.critical.span()),
+          format!("critical must not be above low ({low})"),
+        )
+      } else {
+        v.error(
+          Some(self.low.span()),
+          format!("low must not be below critical ({critical})"),
+        )
+      });
+    }
+
+    Ok(())
+  }
+}
+
+impl SummaryConfig {
+  fn validate(&self, v: &Validator) -> Result<(), ConfigError> {
+    let overlay_duration = &self.overlay_duration_ms;
+    if *overlay_duration.get_ref() > 10_000 {
+      return Err(v.error(
+        Some(overlay_duration.span()),
+        "overlay_duration_ms must be at most 10000".to_string(),
+      ));
+    }
+
+    Ok(())
+  }
+}
+
+impl HistoryConfig {
+  fn validate(&self, v: &Validator) -> Result<(), ConfigError> {
+    if *self.max_size_kib.get_ref() == 0 {
+      return Err(v.error(
+        Some(self.max_size_kib.span()),
+        "max_size_kib must be at least 1".to_string(),
+      ));
+    }
+
+    if *self.keep.get_ref() > 100 {
+      return Err(v.error(
+        Some(self.keep.span()),
+        "keep must be at most 100".to_string(),
+      ));
+    }
+
+    Ok(())
+  }
+}
+
+impl LoggingConfig {
+  fn validate(&self, v: &Validator) -> Result<(), ConfigError> {
+    let level = &self.level;
+    if let Err(err) = tracing_subscriber::EnvFilter::try_new(level.get_ref()) {
+      return Err(v.error(
+        Some(level.span()),
+        format!("invalid log level {:?}: {err}", level.get_ref()),
+      ));
+    }
+
+    Ok(())
+  }
+}
+
+impl KeyboardConfig {
+  fn validate(&self, v: &Validator) -> Result<(), ConfigError> {
+    if let Some(command) = self
+      .switch_command
+      .as_ref()
+      .filter(|command| command.get_ref().trim().is_empty())
+    {
+      return Err(v.error(
+        Some(command.span()),
+        "switch_command must not be empty".to_string(),
+      ));
+    }
+
+    let hotkey = self.switch_hotkey.get_ref();
+    if !hotkey.is_empty() && !is_accelerator(hotkey) {
+      return Err(v.error(
+        Some(self.switch_hotkey.span()),
+        format!("switch_hotkey {hotkey:?} is not an accelerator like \"<Super>space\""),
+      ));
+    }
+
+    Ok(())
+  }
+}
+
+/// Whether GTK parses `accelerator`. The config is read before GTK is
+/// initialized, which the safe binding asserts, but parsing needs no display.
+fn is_accelerator(accelerator: &str) -> bool {
+  let Ok(accelerator) = CString::new(accelerator) else {
+    return false;
+  };
+
+  unsafe {
+    gtk4::ffi::gtk_accelerator_parse(accelerator.as_ptr(), ptr::null_mut(), ptr::null_mut()) != 0
+  }
+}
+
+impl TimeoutsConfig {
+  fn validate(&self, v: &Validator) -> Result<(), ConfigError> {
+    for timeout in [&self.hook, &self.power_confirm, &self.max_fail_delay]
+      .into_iter()
+      .chain(&self.idle)
+      .chain(&self.fail_delay)
+    {
+      if *timeout.get_ref() == 0 {
+        return Err(v.error(
+          Some(timeout.span()),
+          "timeouts must be at least 1 second".to_string(),
+        ));
+      }
+    }
+
+    Ok(())
+  }
+}
+
+impl HooksConfig {
+  fn validate(&self, v: &Validator) -> Result<(), ConfigError> {
+    for (event, commands) in self.by_event() {
+      if let Some(command) = commands.iter().find(|c| c.get_ref().trim().is_empty()) {
+        return Err(v.error(
+          Some(command.span()),
+          format!("empty command for {} hook", event.name()),
+        ));
+      }
+    }
+
+    Ok(())
+  }
+}
+
+impl Config {
+  pub fn parse(path: &Path, source: &str) -> Result<Config, ConfigError> {
+    let v = Validator { path, source };
+    let mut config: Config =
+      toml::from_str(source).map_err(|err| v.error(err.span(), err.message().to_string()))?;
+
+    config.appearance.validate(&v)?;
+    config.theme.validate(&v)?;
+    for wallpaper in &mut config.wallpapers {
+      wallpaper.validate(&v)?;
+    }
+
+    config.screenshot.validate(&v)?;
+    config.animation.validate(&v)?;
+    config.clock.validate(&v)?;
+    config.battery.validate(&v)?;
+    config.summary.validate(&v)?;
+    config.history.validate(&v)?;
+    config.logging.validate(&v)?;
+    config.keyboard.validate(&v)?;
+    config.timeouts.validate(&v)?;
+    config.hooks.validate(&v)?;
+
+    Ok(config)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn parse(source: &str) -> Result<Config, ConfigError> {
+    Config::parse(Path::new("config.toml"), source)
+  }
+
+  fn error_line(source: &str) -> usize {
+    match parse(source) {
+      Err(ConfigError::Invalid { line, .. }) => line,
+      other => panic!("expected an error with a location, got {other:?}"),
+    }
+  }
+
+  #[test]
+  fn sections_and_values_are_snake_case() {
+    let config = parse(
+      r#"
+        [hooks]
+        pre_lock = ["true"]
+        auth_failed = ["true"]
+
+        [logging]
+        target = "journald"
+      "#,
+    )
+    .unwrap();
+
+    assert_eq!(config.hooks.pre_lock.len(), 1);
+    assert_eq!(config.hooks.auth_failed.len(), 1);
+    assert_eq!(config.logging.target, LogTarget::Journald);
+    assert!(parse("[hooks]\npre-lock = [\"true\"]\n").is_err());
+  }
+
+  #[test]
+  fn battery_levels_point_at_the_level_that_was_set() {
+    // critical defaults to 10
+    assert_eq!(error_line("[battery]\n\nlow = 5\n"), 3);
+    // low defaults to 20
+    assert_eq!(error_line("[battery]\ncritical = 30\n"), 2);
+  }
+
+  #[test]
+  fn switch_hotkey_must_be_an_accelerator() {
+    assert!(parse("[keyboard]\nswitch_hotkey = \"<Super>space\"\n").is_ok());
+    assert!(parse("[keyboard]\nswitch_hotkey = \"\"\n").is_ok());
+    assert_eq!(
+      error_line("[keyboard]\nwarn_num_lock = true\nswitch_hotkey = \"<Super>spcae\"\n"),
+      3
+    );
+  }
+
+  #[test]
+  fn relative_paths_are_next_to_the_config() {
+    let dir = std::env::temp_dir().join(format!("dash3-config-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("face.png"), b"").unwrap();
+
+    let config = Config::parse(
+      &dir.join("config.toml"),
+      "[appearance]\navatar = \"face.png\"\n",
+    );
+    let _ = std::fs::remove_dir_all(&dir);
+
+    let avatar = config.unwrap().appearance.avatar.unwrap();
+    assert_eq!(*avatar.get_ref(), dir.join("face.png"));
+  }
+
+  #[test]
+  fn errors_point_into_their_section() {
+    let source = "[clock]\nhours = 12\n\n[history]\nkeep = 1000\n";
+    assert_eq!(error_line(source), 5);
+  }
+}
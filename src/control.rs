@@ -0,0 +1,273 @@
+use std::{
+  io::{BufRead, BufReader, Write},
+  os::{
+    fd::AsRawFd,
+    unix::{
+      fs::{DirBuilderExt, MetadataExt, PermissionsExt},
+      net::{UnixListener, UnixStream},
+    },
+  },
+  path::{Path, PathBuf},
+  pin::pin,
+  str::FromStr,
+};
+
+use anyhow::{bail, Result};
+use futures::future::{select, Either};
+use gtk4::glib;
+use serde::Serialize;
+use serde_json::json;
+use tracing::{error, info, warn};
+
+use crate::{
+  locker::{LockReason, LockState, Locker},
+  socket,
+};
+
+#[derive(Debug, Clone, Copy)]
+enum Command {
+  Lock,
+  Status,
+  Subscribe,
+}
+
+impl FromStr for Command {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "lock" => Ok(Command::Lock),
+      "status" => Ok(Command::Status),
+      "subscribe" => Ok(Command::Subscribe),
+      _ => Err(format!("unknown command: {s}")),
+    }
+  }
+}
+
+/// A command from a client, handled on the main thread. Replies are sent as
+/// JSON lines until `reply_tx` is dropped. `closed` disconnects once the
+/// client hung up, which is only watched for subscriptions.
+struct Request {
+  command: Command,
+  reply_tx: flume::Sender<String>,
+  closed: flume::Receiver<()>,
+}
+
+#[derive(Serialize)]
+struct Status {
+  state: LockState,
+  failed_attempts: u32,
+  locked_secs: Option<u64>,
+  lock_latency_ms: Option<u128>,
+}
+
+/// The bound control socket, removed again on drop
+pub struct ControlSocket {
+  path: PathBuf,
+}
+
+impl Drop for ControlSocket {
+  fn drop(&mut self) {
+    if let Err(err) = std::fs::remove_file(&self.path) {
+      warn!("failed to remove {}: {err}", self.path.display());
+    }
+  }
+}
+
+/// Creates the socket directory, or makes sure an existing one is ours and
+/// private, so the socket is never reachable by others while it is created
+fn create_private_dir(dir: &Path) -> Result<()> {
+  match std::fs::DirBuilder::new().mode(0o700).create(dir) {
+    Ok(()) => {}
+    Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {
+      let metadata = std::fs::symlink_metadata(dir)?;
+      if !metadata.is_dir() || metadata.uid() != unsafe { libc::geteuid() } {
+        bail!("{} is not a directory owned by us", dir.display());
+      }
+
+      std::fs::set_permissions(dir, std::fs::Permissions::from_mode(0o700))?;
+    }
+    Err(err) => return Err(err.into()),
+  }
+
+  Ok(())
+}
+
+fn peer_uid(stream: &UnixStream) -> std::io::Result<u32> {
+  let mut cred: libc::ucred = unsafe { std::mem::zeroed() };
+  let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+  let rc = unsafe {
+    libc::getsockopt(
+      stream.as_raw_fd(),
+      libc::SOL_SOCKET,
+      libc::SO_PEERCRED,
+      &mut cred as *mut libc::ucred as *mut libc::c_void,
+      &mut len,
+    )
+  };
+
+  if rc != 0 {
+    return Err(std::io::Error::last_os_error());
+  }
+
+  Ok(cred.uid)
+}
+
+/// Blocks until the client closed its end of the connection, without
+/// reading what it sent
+fn wait_for_hangup(stream: &UnixStream) {
+  let mut fd = libc::pollfd {
+    fd: stream.as_raw_fd(),
+    events: libc::POLLRDHUP,
+    revents: 0,
+  };
+
+  loop {
+    let rc = unsafe { libc::poll(&mut fd, 1, -1) };
+    if rc >= 0 || std::io::Error::last_os_error().kind() != std::io::ErrorKind::Interrupted {
+      return;
+    }
+  }
+}
+
+fn handle_client(stream: UnixStream, requests: flume::Sender<Request>) -> Result<()> {
+  let uid = peer_uid(&stream)?;
+  if uid != unsafe { libc::geteuid() } {
+    bail!("rejecting connection from uid {uid}");
+  }
+
+  let reader = BufReader::new(stream.try_clone()?);
+  let mut writer = stream;
+
+  for line in reader.lines() {
+    let line = line?;
+    let command = match line.trim().parse::<Command>() {
+      Ok(command) => command,
+      Err(err) => {
+        writeln!(writer, "{}", json!({ "error": err }))?;
+        continue;
+      }
+    };
+
+    let (reply_tx, reply_rx) = flume::unbounded();
+    let (closed_tx, closed) = flume::bounded(0);
+    if matches!(command, Command::Subscribe) {
+      let stream = writer.try_clone()?;
+      std::thread::spawn(move || {
+        wait_for_hangup(&stream);
+        drop(closed_tx);
+      });
+    }
+
+    requests.send(Request {
+      command,
+      reply_tx,
+      closed,
+    })?;
+
+    // Subscriptions only end once the client disconnects
+    for reply in reply_rx.iter() {
+      writeln!(writer, "{reply}")?;
+    }
+  }
+
+  Ok(())
+}
+
+fn handle_request(locker: &Locker, request: Request) {
+  let Request {
+    command,
+    reply_tx,
+    closed,
+  } = request;
+  match command {
+    Command::Lock => {
+      locker.lock(LockReason::Control);
+      let _ = reply_tx.send(json!({ "ok": true }).to_string());
+    }
+    Command::Status => {
+      let status = Status {
+        state: locker.state(),
+        failed_attempts: locker.failed_attempts(),
+        locked_secs: locker.locked_at().map(|at| at.elapsed().as_secs()),
+        lock_latency_ms: locker.lock_latency().map(|latency| latency.as_millis()),
+      };
+
+      match serde_json::to_string(&status) {
+        Ok(status) => {
+          let _ = reply_tx.send(status);
+        }
+        Err(err) => error!("failed to serialize status: {err}"),
+      }
+    }
+    Command::Subscribe => {
+      let events = locker.subscribe();
+      glib::spawn_future_local(async move {
+        loop {
+          let event = match select(pin!(events.recv_async()), pin!(closed.recv_async())).await {
+            Either::Left((Ok(event), _)) => event,
+            // The client hung up, or the locker is gone
+            _ => break,
+          };
+
+          let Ok(event) = serde_json::to_string(&event) else {
+            continue;
+          };
+
+          if reply_tx.send(event).is_err() {
+            break;
+          }
+        }
+      });
+    }
+  }
+}
+
+/// Listens on a unix socket in $XDG_RUNTIME_DIR/dash3 for control commands.
+/// Only connections from our own user are accepted. The socket is removed
+/// once the returned handle is dropped.
+pub fn serve(locker: Locker) -> Result<ControlSocket> {
+  let path = socket::path()?;
+  if let Some(dir) = path.parent() {
+    create_private_dir(dir)?;
+  }
+
+  if path.exists() {
+    if UnixStream::connect(&path).is_ok() {
+      bail!("{} is in use, is dash3 already running?", path.display());
+    }
+
+    std::fs::remove_file(&path)?;
+  }
+
+  let listener = UnixListener::bind(&path)?;
+  info!("listening on {}", path.display());
+
+  let (request_tx, request_rx) = flume::unbounded::<Request>();
+  std::thread::spawn(move || {
+    for stream in listener.incoming() {
+      let stream = match stream {
+        Ok(stream) => stream,
+        Err(err) => {
+          error!("failed to accept control connection: {err}");
+          continue;
+        }
+      };
+
+      let request_tx = request_tx.clone();
+      std::thread::spawn(move || {
+        if let Err(err) = handle_client(stream, request_tx) {
+          warn!("control connection closed: {err}");
+        }
+      });
+    }
+  });
+
+  glib::spawn_future_local(async move {
+    while let Ok(request) = request_rx.recv_async().await {
+      handle_request(&locker, request);
+    }
+  });
+
+  Ok(ControlSocket { path })
+}
@@ -0,0 +1,281 @@
+use gtk4::{gdk, glib, prelude::*};
+
+/// An RGBA image in memory. Unlike textures, it can be created and processed
+/// off the main thread.
+pub struct Pixels {
+  pub width: i32,
+  pub height: i32,
+  pub stride: usize,
+  pub data: Vec<u8>,
+}
+
+/// Blurs `len` pixels starting at byte `start` and `step` bytes apart with a
+/// box filter. The original pixels are copied to `pixels` first, which is
+/// reused across rows and columns.
+fn box_blur(
+  data: &mut [u8],
+  pixels: &mut Vec<[u8; 4]>,
+  start: usize,
+  step: usize,
+  len: usize,
+  radius: usize,
+) {
+  pixels.clear();
+  pixels.extend((0..len).map(|i| {
+    let o = start + i * step;
+    [data[o], data[o + 1], data[o + 2], data[o + 3]]
+  }));
+
+  let at = |i: isize| pixels[i.clamp(0, len as isize - 1) as usize];
+  let radius = radius as isize;
+  let window = (2 * radius + 1) as u32;
+
+  let mut sum = [0u32; 4];
+  for i in -radius..=radius {
+    for (sum, c) in sum.iter_mut().zip(at(i)) {
+      *sum += c as u32;
+    }
+  }
+
+  for i in 0..len {
+    let o = start + i * step;
+    for (c, sum) in data[o..o + 4].iter_mut().zip(&sum) {
+      *c = (sum / window) as u8;
+    }
+
+    let (old, new) = (at(i as isize - radius), at(i as isize + radius + 1));
+    for ((sum, new), old) in sum.iter_mut().zip(new).zip(old) {
+      *sum = *sum + new as u32 - old as u32;
+    }
+  }
+}
+
+impl Pixels {
+  /// Must be called on the main thread
+  pub fn into_texture(self) -> gdk::Texture {
+    gdk::MemoryTexture::new(
+      self.width,
+      self.height,
+      gdk::MemoryFormat::R8g8b8a8,
+      &glib::Bytes::from_owned(self.data),
+      self.stride,
+    )
+    .upcast()
+  }
+
+  fn size(&self) -> (usize, usize) {
+    (self.width as usize, self.height as usize)
+  }
+
+  /// Calls `f` with the position and RGB channels of every pixel
+  fn for_each_rgb(&mut self, mut f: impl FnMut(usize, usize, &mut [u8])) {
+    let (width, _) = self.size();
+    for (y, row) in self.data.chunks_mut(self.stride).enumerate() {
+      for (x, pixel) in row[..width * 4].chunks_exact_mut(4).enumerate() {
+        f(x, y, &mut pixel[..3]);
+      }
+    }
+  }
+
+  /// Approximates a gaussian blur with three box blur passes
+  pub fn blur(&mut self, radius: u32) {
+    if radius == 0 {
+      return;
+    }
+
+    let (width, height) = self.size();
+    let radius = radius as usize;
+    let mut scratch = Vec::with_capacity(width.max(height));
+    for _ in 0..3 {
+      for y in 0..height {
+        box_blur(
+          &mut self.data,
+          &mut scratch,
+          y * self.stride,
+          4,
+          width,
+          radius,
+        );
+      }
+
+      for x in 0..width {
+        box_blur(
+          &mut self.data,
+          &mut scratch,
+          x * 4,
+          self.stride,
+          height,
+          radius,
+        );
+      }
+    }
+  }
+
+  /// Replaces each `size` by `size` block with its top left pixel
+  pub fn pixelate(&mut self, size: u32) {
+    if size <= 1 {
+      return;
+    }
+
+    let size = size as usize;
+    let stride = self.stride;
+    let data = self.data.clone();
+    self.for_each_rgb(|x, y, rgb| {
+      let o = (y - y % size) * stride + (x - x % size) * 4;
+      rgb.copy_from_slice(&data[o..o + 3]);
+    });
+  }
+
+  /// Darkens towards the corners. `strength` ranges from 0, unchanged, to 1,
+  /// black corners.
+  pub fn vignette(&mut self, strength: f64) {
+    if strength <= 0.0 {
+      return;
+    }
+
+    let (width, height) = self.size();
+    let (cx, cy) = (width as f64 / 2.0, height as f64 / 2.0);
+    let max = (cx * cx + cy * cy).sqrt();
+    self.for_each_rgb(|x, y, rgb| {
+      let (dx, dy) = (x as f64 - cx, y as f64 - cy);
+      let distance = (dx * dx + dy * dy).sqrt() / max;
+      let factor = 1.0 - strength * distance * distance;
+      for c in rgb {
+        *c = (*c as f64 * factor).round() as u8;
+      }
+    });
+  }
+
+  /// Makes everything outside the largest centered circle transparent, with
+  /// an antialiased edge
+  pub fn mask_circle(&mut self) {
+    let (width, height) = self.size();
+    let radius = width.min(height) as f64 / 2.0;
+    let (cx, cy) = (width as f64 / 2.0, height as f64 / 2.0);
+    for (y, row) in self.data.chunks_mut(self.stride).enumerate() {
+      for (x, pixel) in row[..width * 4].chunks_exact_mut(4).enumerate() {
+        let (dx, dy) = (x as f64 + 0.5 - cx, y as f64 + 0.5 - cy);
+        let coverage = (radius - (dx * dx + dy * dy).sqrt() + 0.5).clamp(0.0, 1.0);
+        pixel[3] = (pixel[3] as f64 * coverage).round() as u8;
+      }
+    }
+  }
+
+  /// Scales the colour towards black. `amount` ranges from 0, unchanged, to 1,
+  /// black.
+  pub fn darken(&mut self, amount: f64) {
+    if amount <= 0.0 {
+      return;
+    }
+
+    let factor = 1.0 - amount;
+    self.for_each_rgb(|_, _, rgb| {
+      for c in rgb {
+        *c = (*c as f64 * factor).round() as u8;
+      }
+    });
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// A `width` by `height` image with a padded stride, filled by `f(x, y)`
+  fn pixels(width: usize, height: usize, f: impl Fn(usize, usize) -> [u8; 4]) -> Pixels {
+    let stride = width * 4 + 4;
+    let mut data = vec![0xaa; stride * height];
+    for y in 0..height {
+      for x in 0..width {
+        let o = y * stride + x * 4;
+        data[o..o + 4].copy_from_slice(&f(x, y));
+      }
+    }
+
+    Pixels {
+      width: width as i32,
+      height: height as i32,
+      stride,
+      data,
+    }
+  }
+
+  fn at(pixels: &Pixels, x: usize, y: usize) -> [u8; 4] {
+    let o = y * pixels.stride + x * 4;
+    pixels.data[o..o + 4].try_into().unwrap()
+  }
+
+  /// The padding at the end of each row
+  fn padding(pixels: &Pixels) -> Vec<u8> {
+    let width = pixels.width as usize * 4;
+    pixels
+      .data
+      .chunks(pixels.stride)
+      .flat_map(|row| row[width..].to_vec())
+      .collect()
+  }
+
+  #[test]
+  fn blur_spreads_evenly_and_keeps_flat_areas() {
+    let mut flat = pixels(8, 8, |_, _| [10, 20, 30, 255]);
+    flat.blur(2);
+    assert!((0..8).all(|y| (0..8).all(|x| at(&flat, x, y) == [10, 20, 30, 255])));
+    assert!(padding(&flat).iter().all(|&byte| byte == 0xaa));
+
+    let mut dot = pixels(9, 1, |x, _| if x == 4 { [255; 4] } else { [0; 4] });
+    dot.blur(1);
+    let center = at(&dot, 4, 0);
+    assert!(center[0] > 0 && center[0] < 255);
+    for offset in 1..=4 {
+      assert_eq!(at(&dot, 4 - offset, 0), at(&dot, 4 + offset, 0));
+    }
+    assert!(at(&dot, 3, 0)[0] > at(&dot, 1, 0)[0]);
+
+    let mut unchanged = pixels(3, 3, |x, y| [x as u8, y as u8, 0, 255]);
+    unchanged.blur(0);
+    assert_eq!(at(&unchanged, 2, 1), [2, 1, 0, 255]);
+  }
+
+  #[test]
+  fn pixelate_repeats_the_top_left_of_each_block() {
+    let mut image = pixels(4, 4, |x, y| [(y * 4 + x) as u8, 0, 0, 100 + x as u8]);
+    image.pixelate(2);
+
+    assert_eq!(at(&image, 1, 1), [0, 0, 0, 101]);
+    assert_eq!(at(&image, 3, 0), [2, 0, 0, 103]);
+    assert_eq!(at(&image, 3, 3), [10, 0, 0, 103]);
+    assert!(padding(&image).iter().all(|&byte| byte == 0xaa));
+  }
+
+  #[test]
+  fn vignette_blackens_the_corners() {
+    let mut image = pixels(3, 3, |_, _| [255, 255, 255, 200]);
+    image.vignette(1.0);
+
+    assert_eq!(at(&image, 0, 0), [0, 0, 0, 200]);
+    assert_eq!(at(&image, 1, 1), [227, 227, 227, 200]);
+  }
+
+  #[test]
+  fn darken_scales_towards_black() {
+    let mut image = pixels(2, 2, |_, _| [200, 100, 50, 255]);
+    image.darken(0.0);
+    assert_eq!(at(&image, 0, 0), [200, 100, 50, 255]);
+
+    image.darken(0.5);
+    assert_eq!(at(&image, 1, 1), [100, 50, 25, 255]);
+  }
+
+  #[test]
+  fn mask_circle_clears_the_corners() {
+    let mut image = pixels(8, 8, |_, _| [50, 60, 70, 255]);
+    image.mask_circle();
+
+    assert_eq!(at(&image, 0, 0), [50, 60, 70, 0]);
+    assert_eq!(at(&image, 7, 7), [50, 60, 70, 0]);
+    assert_eq!(at(&image, 3, 4), [50, 60, 70, 255]);
+    // Antialiased where the edge crosses the pixel
+    let edge = at(&image, 1, 1)[3];
+    assert!(edge > 0 && edge < 255, "{edge}");
+  }
+}
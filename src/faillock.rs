@@ -0,0 +1,843 @@
+use std::{
+  cell::RefCell,
+  path::{Path, PathBuf},
+  sync::Arc,
+  time::{Duration, Instant, SystemTime},
+};
+
+use gtk4::{glib, prelude::*};
+use tracing::{info, warn};
+
+use crate::{
+  config::Config,
+  locker::{LockEvent, Locker},
+};
+
+const CONF_PATH: &str = "/etc/security/faillock.conf";
+const PAM_DIR: &str = "/etc/pam.d";
+const DEFAULT_DIR: &str = "/var/run/faillock";
+/// How often countdowns are updated
+const COUNTDOWN_INTERVAL: Duration = Duration::from_millis(250);
+/// Services included from others are followed this deep
+const MAX_INCLUDE_DEPTH: u32 = 8;
+
+/// A record in the tally file, see `faillock.h`
+const RECORD_SIZE: usize = 64;
+const RECORD_STATUS: usize = 54;
+const RECORD_TIME: usize = 56;
+const TALLY_STATUS_VALID: u16 = 0x1;
+
+/// The pam_faillock options that decide when the account gets locked, from
+/// `faillock.conf` and the module arguments in the PAM stack
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Policy {
+  /// Consecutive failures until the account is locked, 0 to never lock
+  deny: u32,
+  /// Failures further apart than this are not consecutive
+  fail_interval: Duration,
+  /// None if only an administrator can unlock the account
+  unlock_time: Option<Duration>,
+  dir: PathBuf,
+  even_deny_root: bool,
+  root_unlock_time: Option<Duration>,
+}
+
+impl Default for Policy {
+  fn default() -> Self {
+    Policy {
+      deny: 3,
+      fail_interval: Duration::from_secs(900),
+      unlock_time: Some(Duration::from_secs(600)),
+      dir: PathBuf::from(DEFAULT_DIR),
+      even_deny_root: false,
+      root_unlock_time: None,
+    }
+  }
+}
+
+/// Parses `unlock_time`, where 0 and "never" mean never
+fn unlock_time(value: &str) -> Option<Option<Duration>> {
+  if value == "never" {
+    return Some(None);
+  }
+
+  let secs = value.parse::<u64>().ok()?;
+  Some((secs > 0).then(|| Duration::from_secs(secs)))
+}
+
+impl Policy {
+  /// Applies one option, either `key=value` or a bare flag
+  fn set(&mut self, option: &str) {
+    let (key, value) = match option.split_once('=') {
+      Some((key, value)) => (key.trim(), value.trim()),
+      None => (option.trim(), ""),
+    };
+
+    let parsed = match key {
+      "deny" => value.parse().map(|deny| self.deny = deny).is_ok(),
+      "fail_interval" => value
+        .parse()
+        .map(|secs| self.fail_interval = Duration::from_secs(secs))
+        .is_ok(),
+      "unlock_time" => unlock_time(value)
+        .map(|unlock_time| self.unlock_time = unlock_time)
+        .is_some(),
+      "root_unlock_time" => unlock_time(value)
+        .map(|unlock_time| self.root_unlock_time = unlock_time)
+        .is_some(),
+      "dir" => {
+        self.dir = PathBuf::from(value);
+        true
+      }
+      "even_deny_root" => {
+        self.even_deny_root = true;
+        true
+      }
+      // Options that don't affect the lockout
+      _ => true,
+    };
+
+    if !parsed {
+      warn!("invalid faillock option {option:?}");
+    }
+  }
+
+  fn read_conf(&mut self, path: &Path) {
+    let Ok(conf) = std::fs::read_to_string(path) else {
+      return;
+    };
+
+    for line in conf.lines() {
+      let line = line.split('#').next().unwrap_or_default().trim();
+      if !line.is_empty() {
+        self.set(line);
+      }
+    }
+  }
+
+  /// Finds the arguments of pam_faillock in the auth stack of `service`,
+  /// following includes. None if pam_faillock isn't used.
+  fn stack_args(pam_dir: &Path, service: &str, depth: u32) -> Option<Vec<String>> {
+    if depth > MAX_INCLUDE_DEPTH {
+      return None;
+    }
+
+    let stack = std::fs::read_to_string(pam_dir.join(service)).ok()?;
+    let mut found: Option<Vec<String>> = None;
+    for line in stack.lines() {
+      let line = line.split('#').next().unwrap_or_default();
+      let tokens: Vec<&str> = line.split_whitespace().collect();
+
+      match tokens.as_slice() {
+        ["@include", included, ..] => {
+          if let Some(args) = Self::stack_args(pam_dir, included, depth + 1) {
+            found.get_or_insert_with(Vec::new).extend(args);
+          }
+        }
+        [kind, "include" | "substack", included, ..] if kind.trim_start_matches('-') == "auth" => {
+          if let Some(args) = Self::stack_args(pam_dir, included, depth + 1) {
+            found.get_or_insert_with(Vec::new).extend(args);
+          }
+        }
+        [kind, ..] if kind.trim_start_matches('-') == "auth" => {
+          let Some(module) = tokens
+            .iter()
+            .position(|token| token.ends_with("pam_faillock.so"))
+          else {
+            continue;
+          };
+
+          found
+            .get_or_insert_with(Vec::new)
+            .extend(tokens[module + 1..].iter().map(|arg| arg.to_string()));
+        }
+        _ => {}
+      }
+    }
+
+    found
+  }
+
+  /// None if the PAM service doesn't use pam_faillock, or never locks `user`
+  fn load(pam_dir: &Path, service: &str, user: &str) -> Option<Policy> {
+    let args = Self::stack_args(pam_dir, service, 0)?;
+
+    let conf = args
+      .iter()
+      .find_map(|arg| arg.strip_prefix("conf="))
+      .unwrap_or(CONF_PATH);
+
+    let mut policy = Policy::default();
+    policy.read_conf(Path::new(conf));
+    for arg in &args {
+      policy.set(arg);
+    }
+
+    if user == "root" {
+      if !policy.even_deny_root {
+        return None;
+      }
+
+      policy.unlock_time = policy.root_unlock_time.or(policy.unlock_time);
+    }
+
+    (policy.deny > 0).then_some(policy)
+  }
+
+  /// Times of the failures recorded by pam_faillock. Usually only readable
+  /// by root.
+  fn tally(&self, user: &str) -> std::io::Result<Vec<SystemTime>> {
+    let tally = std::fs::read(self.dir.join(user))?;
+    Ok(decode_tally(&tally))
+  }
+
+  /// Drops failures the way pam_faillock does before recording a new one:
+  /// those older than `fail_interval`, or all once a lockout expired
+  fn prune(&self, failures: &mut Vec<SystemTime>) {
+    let now = SystemTime::now();
+    let expired = failures
+      .iter()
+      .max()
+      .zip(self.unlock_time)
+      .is_some_and(|(latest, unlock_time)| *latest + unlock_time <= now);
+    if expired && failures.len() as u32 >= self.deny {
+      failures.clear();
+    }
+
+    failures.retain(|time| now.duration_since(*time).unwrap_or_default() < self.fail_interval);
+  }
+
+  /// Mirrors pam_faillock's check of the tally
+  fn status(&self, failures: &[SystemTime], count: u32) -> Status {
+    let now = SystemTime::now();
+    let Some(latest) = failures.iter().max().copied() else {
+      return Status::from_count(count, Some(self.deny));
+    };
+
+    let within = |from: SystemTime, time: &SystemTime| {
+      from.duration_since(*time).unwrap_or_default() < self.fail_interval
+    };
+
+    let consecutive = failures.iter().filter(|time| within(latest, time)).count() as u32;
+    if consecutive >= self.deny {
+      let until = self.unlock_time.map(|unlock_time| latest + unlock_time);
+      if until.is_none_or(|until| until > now) {
+        return Status::LockedOut { until };
+      }
+
+      // The lock expired and the next failure starts counting from zero
+      return Status::from_count(count, Some(self.deny));
+    }
+
+    // Failures that are too old are dropped on the next failure
+    let recent = failures.iter().filter(|time| within(now, time)).count() as u32;
+    Status::from_count(count, Some(self.deny.saturating_sub(recent)))
+  }
+}
+
+/// The times of the valid records in a tally file
+fn decode_tally(tally: &[u8]) -> Vec<SystemTime> {
+  tally
+    .chunks_exact(RECORD_SIZE)
+    .filter(|record| {
+      let status = [record[RECORD_STATUS], record[RECORD_STATUS + 1]];
+      u16::from_ne_bytes(status) & TALLY_STATUS_VALID != 0
+    })
+    .map(|record| {
+      let mut time = [0; 8];
+      time.copy_from_slice(&record[RECORD_TIME..RECORD_TIME + 8]);
+      SystemTime::UNIX_EPOCH + Duration::from_secs(u64::from_ne_bytes(time))
+    })
+    .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Status {
+  Clear,
+  /// `count` consecutive failures during this lock
+  Failed {
+    count: u32,
+    /// Attempts left until pam_faillock locks the account
+    remaining: Option<u32>,
+  },
+  /// None if only an administrator can unlock the account
+  LockedOut {
+    until: Option<SystemTime>,
+  },
+}
+
+impl Status {
+  fn from_count(count: u32, remaining: Option<u32>) -> Status {
+    match count {
+      0 => Status::Clear,
+      count => Status::Failed { count, remaining },
+    }
+  }
+
+  fn is_locked_out(&self) -> bool {
+    matches!(self, Status::LockedOut { .. })
+  }
+}
+
+/// Like "4:05"
+fn format_countdown(remaining: Duration) -> String {
+  let secs = remaining.as_secs();
+  match (secs / 3600, secs / 60 % 60, secs % 60) {
+    (0, minutes, secs) => format!("{minutes}:{secs:02}"),
+    (hours, minutes, secs) => format!("{hours}:{minutes:02}:{secs:02}"),
+  }
+}
+
+struct Tracker {
+  service: String,
+  user: String,
+  policy: Option<Policy>,
+  /// Our own failures, for when the tally can't be read
+  failures: Vec<SystemTime>,
+  status: Status,
+  /// Input is refused until then after a failed attempt
+  retry_at: Option<Instant>,
+  countdown: Option<glib::SourceId>,
+}
+
+thread_local! {
+  static TRACKER: RefCell<Option<Tracker>> = const { RefCell::new(None) };
+  static WIDGETS: RefCell<Vec<Widget>> = const { RefCell::new(Vec::new()) };
+}
+
+impl Tracker {
+  fn check(&self, count: u32) -> Status {
+    let Some(policy) = &self.policy else {
+      return Status::from_count(count, None);
+    };
+
+    match policy.tally(&self.user) {
+      Ok(tally) => policy.status(&tally, count),
+      Err(_) => policy.status(&self.failures, count),
+    }
+  }
+
+  /// Lifts an expired lockout or fail delay
+  fn expire(&mut self) {
+    if let Status::LockedOut { until: Some(until) } = self.status {
+      if until <= SystemTime::now() {
+        self.status = Status::Clear;
+      }
+    }
+
+    self.retry_at = self.retry_at.filter(|retry_at| *retry_at > Instant::now());
+  }
+
+  fn is_counting_down(&self) -> bool {
+    matches!(self.status, Status::LockedOut { until: Some(_) }) || self.retry_at.is_some()
+  }
+
+  fn is_refusing_input(&self) -> bool {
+    self.status.is_locked_out() || self.retry_at.is_some()
+  }
+}
+
+fn update_all() {
+  WIDGETS.with_borrow_mut(|widgets| {
+    widgets.retain(|widget| widget.label.upgrade().is_some());
+    widgets.iter().for_each(Widget::update);
+  });
+}
+
+fn set_status(status: Status, retry_at: Option<Instant>) {
+  TRACKER.with_borrow_mut(|tracker| {
+    let Some(tracker) = tracker else {
+      return;
+    };
+
+    if tracker.status != status {
+      info!("authentication status {status:?}");
+    }
+
+    tracker.status = status;
+    tracker.retry_at = retry_at;
+    tracker.expire();
+
+    if tracker.is_counting_down() && tracker.countdown.is_none() {
+      tracker.countdown = Some(glib::timeout_add_local(COUNTDOWN_INTERVAL, tick));
+    }
+  });
+
+  update_all();
+}
+
+/// Updates the countdowns and lifts the lockout or fail delay once it expired
+fn tick() -> glib::ControlFlow {
+  let counting_down = TRACKER.with_borrow_mut(|tracker| {
+    let Some(tracker) = tracker else {
+      return false;
+    };
+
+    tracker.expire();
+    if !tracker.is_counting_down() {
+      tracker.countdown = None;
+    }
+
+    tracker.is_counting_down()
+  });
+
+  update_all();
+
+  if counting_down {
+    glib::ControlFlow::Continue
+  } else {
+    glib::ControlFlow::Break
+  }
+}
+
+fn check(count: u32, failed: bool, retry_at: Option<Instant>) {
+  let status = TRACKER.with_borrow_mut(|tracker| {
+    let tracker = tracker.as_mut()?;
+    if failed {
+      if let Some(policy) = &tracker.policy {
+        policy.prune(&mut tracker.failures);
+      }
+
+      tracker.failures.push(SystemTime::now());
+    }
+
+    Some(tracker.check(count))
+  });
+
+  if let Some(status) = status {
+    set_status(status, retry_at);
+  }
+}
+
+/// Counts failed attempts and follows the pam_faillock tally, so users learn
+/// how many attempts they have left and when a lockout or the fail delay
+/// ends. A `preview` only counts its own failures, the tally and the policy
+/// are left alone.
+pub fn follow(locker: &Locker, config: Arc<Config>, preview: bool) {
+  let service = config.auth.service.clone();
+  let user = config.auth.user();
+  let policy = if preview {
+    None
+  } else {
+    Policy::load(Path::new(PAM_DIR), &service, &user)
+  };
+  info!("faillock policy: {policy:?}");
+
+  TRACKER.with_borrow_mut(|tracker| {
+    *tracker = Some(Tracker {
+      service,
+      user,
+      policy,
+      failures: Vec::new(),
+      status: Status::Clear,
+      retry_at: None,
+      countdown: None,
+    })
+  });
+
+  let locker = locker.clone();
+  let events = locker.subscribe();
+  glib::spawn_future_local(async move {
+    while let Ok(event) = events.recv_async().await {
+      match event {
+        LockEvent::Locking => {
+          // The policy may have changed, and an earlier lockout may still
+          // be running
+          if !preview {
+            TRACKER.with_borrow_mut(|tracker| {
+              if let Some(tracker) = tracker {
+                tracker.policy = Policy::load(Path::new(PAM_DIR), &tracker.service, &tracker.user);
+              }
+            });
+          }
+          check(0, false, None);
+        }
+        LockEvent::AuthFailed { count, .. } => check(count, true, locker.retry_at()),
+        LockEvent::Unlocked => set_status(Status::Clear, None),
+        LockEvent::Locked | LockEvent::Finished => {}
+      }
+    }
+  });
+}
+
+/// Shows `count` failures, and a lockout or fail delay ending after `remaining`,
+/// without following the locker or the tally. The countdown is frozen, so
+/// renders are reproducible.
+pub fn simulate(count: u32, locked_out: bool, remaining: Option<Duration>) {
+  TRACKER.with_borrow_mut(|tracker| {
+    *tracker = Some(Tracker {
+      service: String::new(),
+      user: String::new(),
+      policy: None,
+      failures: Vec::new(),
+      status: Status::Clear,
+      retry_at: None,
+      countdown: None,
+    })
+  });
+
+  let status = match (locked_out, remaining) {
+    (true, remaining) => Status::LockedOut {
+      until: remaining.map(|remaining| SystemTime::now() + remaining),
+    },
+    (false, _) => Status::from_count(count, None),
+  };
+  let retry_at = remaining
+    .filter(|_| !locked_out)
+    .map(|remaining| Instant::now() + remaining);
+  set_status(status, retry_at);
+
+  TRACKER.with_borrow_mut(|tracker| {
+    if let Some(countdown) = tracker
+      .as_mut()
+      .and_then(|tracker| tracker.countdown.take())
+    {
+      countdown.remove();
+    }
+  });
+}
+
+/// The failure message of one window, and the input it disables during a
+/// lockout or fail delay
+struct Widget {
+  label: glib::WeakRef<gtk4::Label>,
+  input_container: glib::WeakRef<gtk4::Box>,
+  input: glib::WeakRef<gtk4::PasswordEntry>,
+}
+
+impl Widget {
+  fn update(&self) {
+    let Some(label) = self.label.upgrade() else {
+      return;
+    };
+
+    let Some((status, retry_at, refusing_input)) = TRACKER.with_borrow(|tracker| {
+      let tracker = tracker.as_ref()?;
+      Some((
+        tracker.status,
+        tracker.retry_at,
+        tracker.is_refusing_input(),
+      ))
+    }) else {
+      return;
+    };
+
+    let mut text = match status {
+      Status::Clear => String::new(),
+      Status::Failed {
+        remaining: Some(1), ..
+      } => "Authentication failed, 1 attempt left before lockout".to_string(),
+      Status::Failed {
+        remaining: Some(remaining),
+        ..
+      } => format!("Authentication failed, {remaining} attempts left before lockout"),
+      Status::Failed { count: 1, .. } => "Authentication failed".to_string(),
+      Status::Failed { count, .. } => format!("Authentication failed ({count} attempts)"),
+      Status::LockedOut { until: Some(until) } => {
+        let remaining = until.duration_since(SystemTime::now()).unwrap_or_default();
+        format!(
+          "Too many failed attempts, try again in {}",
+          format_countdown(remaining + Duration::from_secs(1))
+        )
+      }
+      Status::LockedOut { until: None } => {
+        "Too many failed attempts, the account is locked until an administrator unlocks it"
+          .to_string()
+      }
+    };
+
+    if let (Some(retry_at), false) = (retry_at, status.is_locked_out()) {
+      let remaining = retry_at.saturating_duration_since(Instant::now());
+      text.push_str(&format!("\nTry again in {}s", remaining.as_secs() + 1));
+    }
+
+    label.set_label(&text);
+    label.set_visible(!text.is_empty());
+    if status.is_locked_out() {
+      label.add_css_class("locked-out");
+    } else {
+      label.remove_css_class("locked-out");
+    }
+
+    if let Some(input_container) = self.input_container.upgrade() {
+      let was_sensitive = input_container.is_sensitive();
+      input_container.set_sensitive(!refusing_input);
+
+      if !was_sensitive && !refusing_input {
+        if let Some(input) = self.input.upgrade() {
+          input.grab_focus();
+        }
+      }
+    }
+  }
+}
+
+/// The authentication status shown below the password entry. The input is
+/// disabled while the account is locked out or the fail delay runs.
+pub fn widget(input_container: &gtk4::Box, input: &gtk4::PasswordEntry) -> gtk4::Label {
+  let label = gtk4::Label::builder()
+    .css_classes(["auth-status"])
+    .wrap(true)
+    .justify(gtk4::Justification::Center)
+    .build();
+
+  let widget = Widget {
+    label: label.downgrade(),
+    input_container: input_container.downgrade(),
+    input: input.downgrade(),
+  };
+
+  widget.update();
+  WIDGETS.with_borrow_mut(|widgets| widgets.push(widget));
+
+  label
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::atomic::{AtomicU32, Ordering};
+
+  use super::*;
+
+  /// A directory of PAM services, removed on drop
+  struct PamDir(PathBuf);
+
+  impl PamDir {
+    fn new(services: &[(&str, &str)]) -> Self {
+      static NEXT: AtomicU32 = AtomicU32::new(0);
+      let dir = std::env::temp_dir().join(format!(
+        "dash3-faillock-{}-{}",
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+      ));
+      std::fs::create_dir_all(&dir).unwrap();
+      for (name, contents) in services {
+        std::fs::write(dir.join(name), contents).unwrap();
+      }
+
+      PamDir(dir)
+    }
+
+    /// A `conf=` argument, so the host's faillock.conf isn't read
+    fn conf(&self) -> String {
+      format!("conf={}", self.0.join("faillock.conf").display())
+    }
+  }
+
+  impl Drop for PamDir {
+    fn drop(&mut self) {
+      let _ = std::fs::remove_dir_all(&self.0);
+    }
+  }
+
+  fn ago(secs: u64) -> SystemTime {
+    SystemTime::now() - Duration::from_secs(secs)
+  }
+
+  #[test]
+  fn options_and_conf_lines() {
+    let mut policy = Policy::default();
+    for option in [
+      "deny = 5",
+      "fail_interval=60",
+      "unlock_time=never",
+      "root_unlock_time=30",
+      "dir=/run/faillock-test",
+      "even_deny_root",
+      "audit",
+    ] {
+      policy.set(option);
+    }
+
+    assert_eq!(
+      policy,
+      Policy {
+        deny: 5,
+        fail_interval: Duration::from_secs(60),
+        unlock_time: None,
+        dir: PathBuf::from("/run/faillock-test"),
+        even_deny_root: true,
+        root_unlock_time: Some(Duration::from_secs(30)),
+      }
+    );
+
+    // Invalid values keep the previous one, 0 never unlocks
+    policy.set("deny=many");
+    policy.set("unlock_time=0");
+    assert_eq!(policy.deny, 5);
+    assert_eq!(policy.unlock_time, None);
+
+    let pam = PamDir::new(&[(
+      "faillock.conf",
+      "# deny = 9\n\ndeny = 4 # the default is 3\nunlock_time = 120\n",
+    )]);
+    let mut policy = Policy::default();
+    policy.read_conf(&pam.0.join("faillock.conf"));
+    assert_eq!(policy.deny, 4);
+    assert_eq!(policy.unlock_time, Some(Duration::from_secs(120)));
+  }
+
+  #[test]
+  fn stack_args_follow_includes_and_substacks() {
+    let pam = PamDir::new(&[
+      (
+        "login",
+        "auth include system-auth\naccount required pam_faillock.so deny=9\n",
+      ),
+      (
+        "system-auth",
+        "auth required pam_faillock.so preauth deny=4\n\
+         # auth required pam_faillock.so deny=8\n\
+         auth substack faillock-fail\n",
+      ),
+      (
+        "faillock-fail",
+        "-auth [default=die] /usr/lib/security/pam_faillock.so authfail\n",
+      ),
+      ("debian", "@include common-auth\n"),
+      (
+        "common-auth",
+        "auth required pam_faillock.so unlock_time=60\n",
+      ),
+      ("plain", "auth required pam_unix.so\n"),
+    ]);
+
+    assert_eq!(
+      Policy::stack_args(&pam.0, "login", 0),
+      Some(vec![
+        "preauth".to_string(),
+        "deny=4".to_string(),
+        "authfail".to_string()
+      ])
+    );
+    assert_eq!(
+      Policy::stack_args(&pam.0, "debian", 0),
+      Some(vec!["unlock_time=60".to_string()])
+    );
+    assert_eq!(Policy::stack_args(&pam.0, "plain", 0), None);
+    assert_eq!(Policy::stack_args(&pam.0, "missing", 0), None);
+
+    let looping = PamDir::new(&[("loop", "auth include loop\n")]);
+    assert_eq!(Policy::stack_args(&looping.0, "loop", 0), None);
+  }
+
+  #[test]
+  fn load_applies_conf_then_args() {
+    let pam = PamDir::new(&[("faillock.conf", "deny = 5\nfail_interval = 60\n")]);
+    let stack = format!("auth required pam_faillock.so {} deny=2\n", pam.conf());
+    std::fs::write(pam.0.join("login"), stack).unwrap();
+
+    let policy = Policy::load(&pam.0, "login", "alice").unwrap();
+    assert_eq!(policy.deny, 2);
+    assert_eq!(policy.fail_interval, Duration::from_secs(60));
+
+    std::fs::write(pam.0.join("faillock.conf"), "deny = 0\n").unwrap();
+    let stack = format!("auth required pam_faillock.so {}\n", pam.conf());
+    std::fs::write(pam.0.join("login"), stack).unwrap();
+    assert_eq!(Policy::load(&pam.0, "login", "alice"), None);
+  }
+
+  #[test]
+  fn root_is_only_locked_with_even_deny_root() {
+    let pam = PamDir::new(&[]);
+    let stack = format!("auth required pam_faillock.so {}\n", pam.conf());
+    std::fs::write(pam.0.join("login"), stack).unwrap();
+    assert_eq!(Policy::load(&pam.0, "login", "root"), None);
+
+    let stack = format!(
+      "auth required pam_faillock.so {} even_deny_root\n",
+      pam.conf()
+    );
+    std::fs::write(pam.0.join("login"), stack).unwrap();
+    let policy = Policy::load(&pam.0, "login", "root").unwrap();
+    assert_eq!(policy.unlock_time, Some(Duration::from_secs(600)));
+
+    let stack = format!(
+      "auth required pam_faillock.so {} even_deny_root root_unlock_time=30\n",
+      pam.conf()
+    );
+    std::fs::write(pam.0.join("login"), stack).unwrap();
+    let policy = Policy::load(&pam.0, "login", "root").unwrap();
+    assert_eq!(policy.unlock_time, Some(Duration::from_secs(30)));
+
+    // Other users keep unlock_time
+    let policy = Policy::load(&pam.0, "login", "alice").unwrap();
+    assert_eq!(policy.unlock_time, Some(Duration::from_secs(600)));
+  }
+
+  #[test]
+  fn lockout_expires_after_unlock_time() {
+    let policy = Policy::default();
+
+    let failures = [ago(30), ago(20), ago(10)];
+    assert!(matches!(
+      policy.status(&failures, 3),
+      Status::LockedOut { until: Some(until) } if until == failures[2] + Duration::from_secs(600)
+    ));
+
+    let mut failures = vec![ago(720), ago(710), ago(700)];
+    assert_eq!(
+      policy.status(&failures, 3),
+      Status::Failed {
+        count: 3,
+        remaining: Some(3)
+      }
+    );
+    policy.prune(&mut failures);
+    assert!(failures.is_empty());
+
+    // Only an administrator lifts it
+    let never = Policy {
+      unlock_time: None,
+      ..Policy::default()
+    };
+    assert_eq!(
+      never.status(&[ago(720), ago(710), ago(700)], 3),
+      Status::LockedOut { until: None }
+    );
+  }
+
+  #[test]
+  fn failures_outside_fail_interval_are_not_counted() {
+    let policy = Policy::default();
+
+    let mut failures = vec![ago(1000), ago(10)];
+    assert_eq!(
+      policy.status(&failures, 2),
+      Status::Failed {
+        count: 2,
+        remaining: Some(2)
+      }
+    );
+
+    policy.prune(&mut failures);
+    assert_eq!(failures.len(), 1);
+    assert_eq!(policy.status(&[], 0), Status::Clear);
+  }
+
+  #[test]
+  fn tally_keeps_valid_records() {
+    let record = |status: u16, time: u64| {
+      let mut record = [0u8; RECORD_SIZE];
+      record[..9].copy_from_slice(b"/dev/tty1");
+      record[RECORD_STATUS..RECORD_STATUS + 2].copy_from_slice(&status.to_ne_bytes());
+      record[RECORD_TIME..].copy_from_slice(&time.to_ne_bytes());
+      record
+    };
+
+    let mut tally = Vec::new();
+    tally.extend(record(TALLY_STATUS_VALID, 1_700_000_000));
+    // Reset by `faillock --reset`
+    tally.extend(record(0, 1_700_000_100));
+    // TALLY_STATUS_RHOST
+    tally.extend(record(TALLY_STATUS_VALID | 0x2, 1_700_000_200));
+    // A record cut short by a crash
+    tally.extend(&record(TALLY_STATUS_VALID, 1_700_000_300)[..RECORD_SIZE / 2]);
+
+    let epoch = |secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+    assert_eq!(
+      decode_tally(&tally),
+      [epoch(1_700_000_000), epoch(1_700_000_200)]
+    );
+  }
+}
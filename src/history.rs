@@ -0,0 +1,483 @@
+use std::{
+  collections::BTreeMap,
+  fmt,
+  fs::{File, OpenOptions},
+  io::{BufRead, BufReader, Write},
+  os::unix::fs::OpenOptionsExt,
+  path::{Path, PathBuf},
+  sync::Arc,
+  time::SystemTime,
+};
+
+use anyhow::{anyhow, Context, Result};
+use gtk4::glib;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{
+  cli::HistoryArgs,
+  clock,
+  config::{self, Config},
+  locker::{LockEvent, LockReason, Locker},
+};
+
+const HISTORY_FILE: &str = "history.jsonl";
+/// Local time with the UTC offset, without fractional seconds
+const TIME_FORMAT: &str = "%FT%T%:z";
+
+/// One line of the history. Only what happened and when is recorded: never
+/// passwords or the prompts and messages of PAM modules.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum Record {
+  Locked {
+    reason: Option<LockReason>,
+  },
+  Unlocked {
+    duration_secs: u64,
+  },
+  /// The compositor ended the lock without an unlock
+  Finished {
+    duration_secs: u64,
+  },
+  AuthFailed {
+    count: u32,
+    /// The PAM return code, like "pam_authenticate: AUTH_ERR"
+    error: String,
+  },
+  /// The lock ended before the compositor confirmed it
+  LockRefused {
+    reason: Option<LockReason>,
+  },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+  time: String,
+  #[serde(flatten)]
+  record: Record,
+}
+
+/// A calendar day in local time
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Day {
+  year: i32,
+  month: i32,
+  day: i32,
+}
+
+impl Day {
+  fn of(time: &glib::DateTime) -> Day {
+    let (year, month, day) = time.ymd();
+    Day { year, month, day }
+  }
+
+  fn start(&self) -> Result<glib::DateTime> {
+    Ok(glib::DateTime::from_local(
+      self.year, self.month, self.day, 0, 0, 0.0,
+    )?)
+  }
+}
+
+impl fmt::Display for Day {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{:04}-{:02}-{:02}", self.year, self.month, self.day)
+  }
+}
+
+/// Parses a YYYY-MM-DD date for the command line
+pub fn parse_day(s: &str) -> Result<Day, String> {
+  let invalid = || format!("invalid date {s:?}, expected YYYY-MM-DD");
+  let mut parts = s.splitn(3, '-').map(|part| part.parse::<i32>());
+  let (Some(Ok(year)), Some(Ok(month)), Some(Ok(day))) = (parts.next(), parts.next(), parts.next())
+  else {
+    return Err(invalid());
+  };
+
+  let day = Day { year, month, day };
+  day.start().map_err(|_| invalid())?;
+  Ok(day)
+}
+
+/// `$XDG_STATE_HOME/dash3/history.jsonl`
+pub fn default_path() -> Option<PathBuf> {
+  Some(config::state_dir()?.join(HISTORY_FILE))
+}
+
+/// `history.jsonl.1` for `n` = 1, the newest rotated file
+fn rotated(path: &Path, n: u32) -> PathBuf {
+  let mut name = path.as_os_str().to_owned();
+  name.push(format!(".{n}"));
+  PathBuf::from(name)
+}
+
+/// Appends records, moving the file aside once it grew past `max_size`
+struct Log {
+  path: PathBuf,
+  max_size: u64,
+  keep: u32,
+}
+
+impl Log {
+  fn rotate(&self) -> Result<()> {
+    if self.keep == 0 {
+      std::fs::remove_file(&self.path)?;
+      return Ok(());
+    }
+
+    for n in (1..self.keep).rev() {
+      let from = rotated(&self.path, n);
+      if from.exists() {
+        std::fs::rename(&from, rotated(&self.path, n + 1))?;
+      }
+    }
+
+    std::fs::rename(&self.path, rotated(&self.path, 1))?;
+    Ok(())
+  }
+
+  fn append(&self, record: Record) -> Result<()> {
+    let now = glib::DateTime::now_local()?;
+    let entry = Entry {
+      time: now.format(TIME_FORMAT)?.into(),
+      record,
+    };
+
+    if let Some(dir) = self.path.parent() {
+      std::fs::create_dir_all(dir)?;
+    }
+
+    let size = std::fs::metadata(&self.path).map_or(0, |metadata| metadata.len());
+    if size >= self.max_size {
+      self
+        .rotate()
+        .with_context(|| format!("failed to rotate {}", self.path.display()))?;
+    }
+
+    let mut file = OpenOptions::new()
+      .create(true)
+      .append(true)
+      .mode(0o600)
+      .open(&self.path)?;
+    writeln!(file, "{}", serde_json::to_string(&entry)?)?;
+    Ok(())
+  }
+}
+
+/// Whole seconds since `at` on the wall clock, so time spent suspended counts
+fn secs_since(at: SystemTime) -> u64 {
+  at.elapsed().map_or(0, |elapsed| elapsed.as_secs())
+}
+
+/// Appends a record for each lock, unlock and failed attempt
+pub fn follow(locker: &Locker, config: Arc<Config>) {
+  let history = &config.history;
+  if !history.enabled {
+    return;
+  }
+
+  let Some(path) = default_path() else {
+    warn!("unable to find the state directory, not recording history");
+    return;
+  };
+
+  info!("recording history in {}", path.display());
+  let log = Log {
+    path,
+    max_size: *history.max_size_kib.get_ref() * 1024,
+    keep: *history.keep.get_ref(),
+  };
+
+  let locker = locker.clone();
+  let events = locker.subscribe();
+  glib::spawn_future_local(async move {
+    let mut locked_at: Option<SystemTime> = None;
+    while let Ok(event) = events.recv_async().await {
+      let record = match event {
+        LockEvent::Locking => continue,
+        LockEvent::Locked => {
+          locked_at = Some(SystemTime::now());
+          Record::Locked {
+            reason: locker.lock_reason(),
+          }
+        }
+        LockEvent::AuthFailed { count, error } => Record::AuthFailed { count, error },
+        LockEvent::Unlocked => Record::Unlocked {
+          duration_secs: locked_at.take().map_or(0, secs_since),
+        },
+        LockEvent::Finished => match locked_at.take() {
+          Some(at) => Record::Finished {
+            duration_secs: secs_since(at),
+          },
+          None => Record::LockRefused {
+            reason: locker.lock_reason(),
+          },
+        },
+      };
+
+      if let Err(err) = log.append(record) {
+        warn!("failed to record history in {}: {err}", log.path.display());
+      }
+    }
+  });
+}
+
+/// The log and its rotated files, oldest first
+fn files(path: &Path) -> Vec<PathBuf> {
+  let mut files: Vec<PathBuf> = (1..)
+    .map(|n| rotated(path, n))
+    .take_while(|file| file.exists())
+    .collect();
+  files.reverse();
+  files.push(path.to_path_buf());
+  files
+}
+
+fn read(path: &Path) -> Result<Vec<(glib::DateTime, Record)>> {
+  let mut entries = Vec::new();
+  for file in files(path) {
+    let reader = match File::open(&file) {
+      Ok(reader) => BufReader::new(reader),
+      Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+      Err(err) => return Err(err).with_context(|| format!("failed to read {}", file.display())),
+    };
+
+    for (number, line) in reader.lines().enumerate() {
+      let line = line?;
+      let parsed = serde_json::from_str::<Entry>(&line)
+        .map_err(|err| anyhow!("{err}"))
+        .and_then(|entry| {
+          let time = glib::DateTime::from_iso8601(&entry.time, None)?.to_local()?;
+          Ok((time, entry.record))
+        });
+
+      match parsed {
+        Ok(entry) => entries.push(entry),
+        Err(err) => eprintln!("dash3: skipping {}:{}: {err}", file.display(), number + 1),
+      }
+    }
+  }
+
+  Ok(entries)
+}
+
+fn format_duration(secs: u64) -> String {
+  clock::format_elapsed(glib::TimeSpan::from_seconds(secs as i64))
+}
+
+fn reason_suffix(reason: Option<LockReason>) -> String {
+  reason.map_or_else(String::new, |reason| format!(" ({})", reason.name()))
+}
+
+fn describe(record: &Record) -> String {
+  match record {
+    Record::Locked { reason } => format!("locked{}", reason_suffix(*reason)),
+    Record::Unlocked { duration_secs } => {
+      format!("unlocked after {}", format_duration(*duration_secs))
+    }
+    Record::Finished { duration_secs } => format!(
+      "lock ended by the compositor after {}",
+      format_duration(*duration_secs)
+    ),
+    Record::AuthFailed { count, error } => {
+      format!("authentication failed, attempt {count}: {error}")
+    }
+    Record::LockRefused { reason } => format!("lock refused{}", reason_suffix(*reason)),
+  }
+}
+
+#[derive(Debug, Default)]
+struct DayTotals {
+  locked_secs: u64,
+  locks: u32,
+  failures: u32,
+}
+
+/// Splits the lock that ended at `end` across the days it spans
+fn add_locked_time(
+  totals: &mut BTreeMap<Day, DayTotals>,
+  end: &glib::DateTime,
+  duration_secs: u64,
+) -> Result<()> {
+  let mut cursor = end.add_seconds(-(duration_secs as f64))?;
+  while cursor < *end {
+    let day = Day::of(&cursor);
+    let next_day = day.start()?.add_days(1)?;
+    let until = next_day.min(end.clone());
+    totals.entry(day).or_default().locked_secs += until.difference(&cursor).as_seconds() as u64;
+    cursor = until;
+  }
+
+  Ok(())
+}
+
+fn summarize(entries: &[(glib::DateTime, Record)]) -> Result<BTreeMap<Day, DayTotals>> {
+  let mut totals = BTreeMap::<Day, DayTotals>::new();
+  for (time, record) in entries {
+    match record {
+      Record::Locked { .. } => totals.entry(Day::of(time)).or_default().locks += 1,
+      Record::AuthFailed { .. } => totals.entry(Day::of(time)).or_default().failures += 1,
+      Record::Unlocked { duration_secs } | Record::Finished { duration_secs } => {
+        add_locked_time(&mut totals, time, *duration_secs)?
+      }
+      Record::LockRefused { .. } => {}
+    }
+  }
+
+  Ok(totals)
+}
+
+/// Prints the records between `--since` and `--until`, or the locked time
+/// per day with `--summary`
+pub fn query(args: &HistoryArgs) -> glib::ExitCode {
+  match run_query(args) {
+    Ok(()) => glib::ExitCode::SUCCESS,
+    Err(err) => {
+      eprintln!("dash3: {err}");
+      glib::ExitCode::FAILURE
+    }
+  }
+}
+
+fn run_query(args: &HistoryArgs) -> Result<()> {
+  let path = match &args.file {
+    Some(path) => path.clone(),
+    None => default_path().ok_or_else(|| anyhow!("unable to find the state directory"))?,
+  };
+
+  let in_range = |day: &Day| {
+    args.since.is_none_or(|since| *day >= since) && args.until.is_none_or(|until| *day <= until)
+  };
+
+  let entries = read(&path)?;
+  if args.summary {
+    for (day, totals) in summarize(&entries)?.iter().filter(|(day, _)| in_range(day)) {
+      println!(
+        "{day}  {:>8} locked  {} locks  {} failed attempts",
+        format_duration(totals.locked_secs),
+        totals.locks,
+        totals.failures
+      );
+    }
+
+    return Ok(());
+  }
+
+  for (time, record) in entries.iter().filter(|(time, _)| in_range(&Day::of(time))) {
+    println!("{}  {}", time.format("%F %T")?, describe(record));
+  }
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::atomic::{AtomicU32, Ordering};
+
+  use super::*;
+
+  /// A directory for a history file, removed on drop
+  struct StateDir(PathBuf);
+
+  impl StateDir {
+    fn new() -> Self {
+      static NEXT: AtomicU32 = AtomicU32::new(0);
+      let dir = std::env::temp_dir().join(format!(
+        "dash3-history-{}-{}",
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+      ));
+      std::fs::create_dir_all(&dir).unwrap();
+      StateDir(dir)
+    }
+
+    fn log(&self, keep: u32) -> Log {
+      Log {
+        path: self.0.join(HISTORY_FILE),
+        max_size: 0,
+        keep,
+      }
+    }
+  }
+
+  impl Drop for StateDir {
+    fn drop(&mut self) {
+      let _ = std::fs::remove_dir_all(&self.0);
+    }
+  }
+
+  fn contents(path: &Path) -> Option<String> {
+    std::fs::read_to_string(path).ok()
+  }
+
+  #[test]
+  fn parse_day_checks_the_date() {
+    assert_eq!(
+      parse_day("2024-03-01"),
+      Ok(Day {
+        year: 2024,
+        month: 3,
+        day: 1
+      })
+    );
+    assert_eq!(parse_day("2024-03-01").unwrap().to_string(), "2024-03-01");
+    assert!(parse_day("2024-02-30").is_err());
+    assert!(parse_day("2024-03").is_err());
+    assert!(parse_day("yesterday").is_err());
+  }
+
+  #[test]
+  fn rotate_shifts_the_files_and_drops_the_oldest() {
+    let dir = StateDir::new();
+    let log = dir.log(2);
+    std::fs::write(&log.path, "current").unwrap();
+    std::fs::write(rotated(&log.path, 1), "older").unwrap();
+    std::fs::write(rotated(&log.path, 2), "oldest").unwrap();
+
+    log.rotate().unwrap();
+    assert_eq!(contents(&log.path), None);
+    assert_eq!(contents(&rotated(&log.path, 1)).as_deref(), Some("current"));
+    assert_eq!(contents(&rotated(&log.path, 2)).as_deref(), Some("older"));
+    assert_eq!(contents(&rotated(&log.path, 3)), None);
+  }
+
+  #[test]
+  fn rotate_without_keeping_removes_the_log() {
+    let dir = StateDir::new();
+    let log = dir.log(0);
+    std::fs::write(&log.path, "current").unwrap();
+
+    log.rotate().unwrap();
+    assert_eq!(contents(&log.path), None);
+    assert_eq!(contents(&rotated(&log.path, 1)), None);
+  }
+
+  #[test]
+  fn files_are_oldest_first() {
+    let dir = StateDir::new();
+    let path = dir.0.join(HISTORY_FILE);
+    std::fs::write(rotated(&path, 1), "").unwrap();
+    std::fs::write(rotated(&path, 2), "").unwrap();
+
+    assert_eq!(
+      files(&path),
+      vec![rotated(&path, 2), rotated(&path, 1), path.clone()]
+    );
+  }
+
+  #[test]
+  fn locked_time_is_split_at_midnight() {
+    let day = |day| Day {
+      year: 2024,
+      month: 3,
+      day,
+    };
+    let end = day(2).start().unwrap().add_minutes(30).unwrap();
+
+    let mut totals = BTreeMap::new();
+    add_locked_time(&mut totals, &end, 3600).unwrap();
+    assert_eq!(totals.len(), 2);
+    assert_eq!(totals[&day(1)].locked_secs, 1800);
+    assert_eq!(totals[&day(2)].locked_secs, 1800);
+  }
+}
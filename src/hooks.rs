@@ -0,0 +1,256 @@
+use std::{
+  io::{BufRead, BufReader, Read},
+  os::unix::process::CommandExt,
+  pin::pin,
+  process::{Command, Stdio},
+  str::FromStr,
+  time::{Duration, Instant},
+};
+
+use futures::{
+  future::{join_all, select, Either},
+  FutureExt,
+};
+use gtk4::{glib, prelude::*, Application};
+use tracing::{error, info, warn};
+
+use crate::locker::{LockEvent, Locker};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookEvent {
+  PreLock,
+  Locked,
+  AuthFailed,
+  Unlocked,
+  Finished,
+}
+
+impl HookEvent {
+  pub fn name(&self) -> &'static str {
+    match self {
+      HookEvent::PreLock => "pre-lock",
+      HookEvent::Locked => "locked",
+      HookEvent::AuthFailed => "auth-failed",
+      HookEvent::Unlocked => "unlocked",
+      HookEvent::Finished => "finished",
+    }
+  }
+}
+
+impl FromStr for HookEvent {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "pre-lock" => Ok(HookEvent::PreLock),
+      "locked" => Ok(HookEvent::Locked),
+      "auth-failed" => Ok(HookEvent::AuthFailed),
+      "unlocked" => Ok(HookEvent::Unlocked),
+      "finished" => Ok(HookEvent::Finished),
+      _ => Err(format!(
+        "unknown hook event {s}, expected one of pre-lock, locked, auth-failed, unlocked, finished"
+      )),
+    }
+  }
+}
+
+/// A shell command run when a lock event happens
+#[derive(Debug, Clone)]
+pub struct Hook {
+  pub event: HookEvent,
+  pub command: String,
+}
+
+impl FromStr for Hook {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let Some((event, command)) = s.split_once('=') else {
+      return Err("expected EVENT=COMMAND".to_string());
+    };
+
+    Ok(Hook {
+      event: event.trim().parse()?,
+      command: command.to_string(),
+    })
+  }
+}
+
+fn log_output(name: String, stream: impl Read + Send + 'static, is_stderr: bool) {
+  std::thread::spawn(move || {
+    for line in BufReader::new(stream).lines().map_while(Result::ok) {
+      if is_stderr {
+        warn!(target: "dash3::hooks", "{name}: {line}");
+      } else {
+        info!(target: "dash3::hooks", "{name}: {line}");
+      }
+    }
+  });
+}
+
+/// Blocks until the process exited, leaving it to be reaped, so its pid and
+/// process group can't be reused in the meantime
+fn wait_for_exit(pid: libc::pid_t) {
+  let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+  loop {
+    let rc = unsafe {
+      libc::waitid(
+        libc::P_PID,
+        pid as libc::id_t,
+        &mut info,
+        libc::WEXITED | libc::WNOWAIT,
+      )
+    };
+    if rc == 0 || std::io::Error::last_os_error().kind() != std::io::ErrorKind::Interrupted {
+      return;
+    }
+  }
+}
+
+/// Runs the hook until it exits, killing it and everything it started once
+/// `timeout` has passed. A thread waits for the process, so nothing is
+/// polled.
+async fn run(hook: Hook, env: Vec<(&'static str, String)>, timeout: Duration) {
+  let name = format!("{} hook", hook.event.name());
+  let mut child = match Command::new("sh")
+    .arg("-c")
+    .arg(&hook.command)
+    .envs(env)
+    .stdin(Stdio::null())
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped())
+    // Its own group, so commands it started are killed along with it
+    .process_group(0)
+    .spawn()
+  {
+    Ok(child) => child,
+    Err(err) => {
+      error!("failed to run {name}: {err}");
+      return;
+    }
+  };
+
+  if let Some(stdout) = child.stdout.take() {
+    log_output(name.clone(), stdout, false);
+  }
+
+  if let Some(stderr) = child.stderr.take() {
+    log_output(name.clone(), stderr, true);
+  }
+
+  // The waiter only reaps the process once `reap_tx` is dropped, so the pid
+  // stays ours until then
+  let pid = child.id() as libc::pid_t;
+  let (exited_tx, exited_rx) = flume::bounded(1);
+  let (reap_tx, reap_rx) = flume::bounded::<()>(0);
+  let (status_tx, status_rx) = flume::bounded(1);
+  std::thread::spawn(move || {
+    wait_for_exit(pid);
+    let _ = exited_tx.send(());
+    let _ = reap_rx.recv();
+    let _ = status_tx.send(child.wait());
+  });
+
+  let exited = pin!(exited_rx.recv_async());
+  let timed_out = match select(exited, glib::timeout_future(timeout)).await {
+    Either::Left(_) => false,
+    Either::Right(_) => {
+      warn!("{name} timed out after {}s, killing it", timeout.as_secs());
+      unsafe { libc::kill(-pid, libc::SIGKILL) };
+      true
+    }
+  };
+
+  drop(reap_tx);
+  let status = status_rx.recv_async().await;
+  if timed_out {
+    return;
+  }
+
+  match status {
+    Ok(Ok(status)) if status.success() => {}
+    Ok(Ok(status)) => warn!("{name} exited with {status}"),
+    Ok(Err(err)) => error!("failed to wait for {name}: {err}"),
+    Err(_) => error!("lost the waiter of {name}"),
+  }
+}
+
+/// Runs the configured hooks for lock events. Details are passed to the
+/// commands as `DASH3_*` environment variables. Pre-lock hooks run to
+/// completion before the session is locked, all others run in the
+/// background, so they never hold up authentication or the wayland thread.
+///
+/// The app is held from a lock request until the hooks for its end have been
+/// spawned, so a one-shot lock doesn't exit before them.
+pub fn spawn(app: &Application, locker: &Locker, hooks: Vec<Hook>, timeout: Duration) {
+  if hooks.is_empty() {
+    return;
+  }
+
+  let pre_lock = hooks
+    .iter()
+    .filter(|hook| hook.event == HookEvent::PreLock)
+    .cloned()
+    .collect::<Vec<_>>();
+  if !pre_lock.is_empty() {
+    locker.set_pre_lock(move || {
+      let env = vec![("DASH3_EVENT", HookEvent::PreLock.name().to_string())];
+      let hooks = pre_lock
+        .iter()
+        .map(|hook| run(hook.clone(), env.clone(), timeout))
+        .collect::<Vec<_>>();
+      join_all(hooks).map(|_| ()).boxed_local()
+    });
+  }
+
+  let app = app.downgrade();
+  let mut hold = app.upgrade().map(|app| app.hold());
+  let events = locker.subscribe();
+  glib::spawn_future_local(async move {
+    let mut locked_at = None;
+
+    while let Ok(event) = events.recv_async().await {
+      let mut env = Vec::new();
+      let hook_event = match &event {
+        LockEvent::Locking => {
+          if hold.is_none() {
+            hold = app.upgrade().map(|app| app.hold());
+          }
+
+          // Already run by the locker
+          continue;
+        }
+        LockEvent::Locked => {
+          locked_at = Some(Instant::now());
+          HookEvent::Locked
+        }
+        LockEvent::AuthFailed { count, error } => {
+          env.push(("DASH3_FAILED_ATTEMPTS", count.to_string()));
+          env.push(("DASH3_PAM_ERROR", error.clone()));
+          HookEvent::AuthFailed
+        }
+        LockEvent::Unlocked => {
+          if let Some(locked_at) = locked_at.take() {
+            let duration = locked_at.elapsed().as_secs();
+            env.push(("DASH3_LOCK_DURATION", duration.to_string()));
+          }
+
+          HookEvent::Unlocked
+        }
+        LockEvent::Finished => {
+          locked_at = None;
+          HookEvent::Finished
+        }
+      };
+
+      env.push(("DASH3_EVENT", hook_event.name().to_string()));
+      for hook in hooks.iter().filter(|hook| hook.event == hook_event) {
+        glib::spawn_future_local(run(hook.clone(), env.clone(), timeout));
+      }
+
+      if matches!(hook_event, HookEvent::Unlocked | HookEvent::Finished) {
+        hold = None;
+      }
+    }
+  });
+}
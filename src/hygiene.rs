@@ -0,0 +1,246 @@
+use std::{
+  io::{BufRead, BufReader, Read, Write},
+  os::unix::net::UnixStream,
+  path::PathBuf,
+  process::Command,
+  time::Duration,
+};
+
+use anyhow::{anyhow, bail, Result};
+use gtk4::glib;
+use serde::Deserialize;
+use tracing::{info, warn};
+use wayland_client::{
+  event_created_child,
+  globals::{registry_queue_init, GlobalListContents},
+  protocol::{wl_registry::WlRegistry, wl_seat::WlSeat},
+  Connection, Dispatch, Proxy, QueueHandle,
+};
+use wayland_protocols::ext::data_control::v1::client::{
+  ext_data_control_device_v1::{self, ExtDataControlDeviceV1},
+  ext_data_control_manager_v1::ExtDataControlManagerV1,
+  ext_data_control_offer_v1::ExtDataControlOfferV1,
+};
+use wayland_protocols_wlr::data_control::v1::client::{
+  zwlr_data_control_device_v1::{self, ZwlrDataControlDeviceV1},
+  zwlr_data_control_manager_v1::ZwlrDataControlManagerV1,
+  zwlr_data_control_offer_v1::ZwlrDataControlOfferV1,
+};
+
+use crate::locker::{wayland, LockEvent, Locker};
+
+const SSH_AGENTC_REMOVE_ALL_IDENTITIES: u8 = 19;
+const SSH_AGENT_SUCCESS: u8 = 6;
+
+/// How long to wait for an agent to answer
+const AGENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Built-in actions run once the compositor confirmed the lock
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum LockAction {
+  /// Clear the clipboard and primary selection
+  ClearClipboard,
+  /// Remove all identities from ssh-agent
+  SshAgent,
+  /// Make gpg-agent forget cached passphrases
+  GpgAgent,
+}
+
+struct ClipboardState;
+
+fn clear_clipboard(conn: Connection) -> Result<()> {
+  let (globals, mut event_queue) = registry_queue_init::<ClipboardState>(&conn)?;
+  let qh = event_queue.handle();
+  let seat: WlSeat = globals.bind(&qh, 1..=1, ())?;
+
+  if let Ok(manager) = globals.bind::<ExtDataControlManagerV1, _, _>(&qh, 1..=1, ()) {
+    let device = manager.get_data_device(&seat, &qh, ());
+    device.set_selection(None);
+    device.set_primary_selection(None);
+    device.destroy();
+    manager.destroy();
+  } else if let Ok(manager) = globals.bind::<ZwlrDataControlManagerV1, _, _>(&qh, 1..=2, ()) {
+    let device = manager.get_data_device(&seat, &qh, ());
+    device.set_selection(None);
+    if manager.version() >= 2 {
+      device.set_primary_selection(None);
+    }
+
+    device.destroy();
+    manager.destroy();
+  } else {
+    bail!("compositor supports neither ext_data_control_v1 nor zwlr_data_control_v1");
+  }
+
+  event_queue.roundtrip(&mut ClipboardState)?;
+  Ok(())
+}
+
+fn flush_ssh_agent() -> Result<()> {
+  let path =
+    std::env::var_os("SSH_AUTH_SOCK").ok_or_else(|| anyhow!("SSH_AUTH_SOCK is not set"))?;
+  let mut stream = UnixStream::connect(path)?;
+  stream.set_read_timeout(Some(AGENT_TIMEOUT))?;
+
+  // A message is a u32 length followed by the message type
+  stream.write_all(&[0, 0, 0, 1, SSH_AGENTC_REMOVE_ALL_IDENTITIES])?;
+
+  let mut len = [0; 4];
+  stream.read_exact(&mut len)?;
+  if u32::from_be_bytes(len) == 0 {
+    bail!("empty reply from ssh-agent");
+  }
+
+  let mut kind = [0; 1];
+  stream.read_exact(&mut kind)?;
+  if kind[0] != SSH_AGENT_SUCCESS {
+    bail!("ssh-agent refused to remove identities");
+  }
+
+  Ok(())
+}
+
+fn gpg_agent_socket() -> Result<PathBuf> {
+  if let Ok(output) = Command::new("gpgconf")
+    .args(["--list-dirs", "agent-socket"])
+    .output()
+  {
+    let path = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if output.status.success() && !path.is_empty() {
+      return Ok(PathBuf::from(path));
+    }
+  }
+
+  let home = match std::env::var_os("GNUPGHOME") {
+    Some(home) => PathBuf::from(home),
+    None => {
+      let home = std::env::var_os("HOME").ok_or_else(|| anyhow!("HOME is not set"))?;
+      PathBuf::from(home).join(".gnupg")
+    }
+  };
+
+  Ok(home.join("S.gpg-agent"))
+}
+
+fn reload_gpg_agent() -> Result<()> {
+  let stream = UnixStream::connect(gpg_agent_socket()?)?;
+  stream.set_read_timeout(Some(AGENT_TIMEOUT))?;
+
+  let mut reader = BufReader::new(stream.try_clone()?);
+  let mut writer = stream;
+
+  let mut line = String::new();
+  reader.read_line(&mut line)?;
+  if !line.starts_with("OK") {
+    bail!("unexpected greeting from gpg-agent: {}", line.trim());
+  }
+
+  writer.write_all(b"RELOADAGENT\n")?;
+
+  line.clear();
+  reader.read_line(&mut line)?;
+  if !line.starts_with("OK") {
+    bail!("gpg-agent refused to reload: {}", line.trim());
+  }
+
+  Ok(())
+}
+
+/// Runs the given actions every time a lock is confirmed. Actions run on
+/// their own thread and failures are only logged, so they never interfere
+/// with locking.
+pub fn spawn(locker: &Locker, actions: Vec<LockAction>) {
+  if actions.is_empty() {
+    return;
+  }
+
+  let events = locker.subscribe();
+  glib::spawn_future_local(async move {
+    while let Ok(event) = events.recv_async().await {
+      if !matches!(event, LockEvent::Locked) {
+        continue;
+      }
+
+      // The connection has to be created on the main thread
+      let conn = actions
+        .contains(&LockAction::ClearClipboard)
+        .then(wayland::connection);
+
+      let actions = actions.clone();
+      std::thread::spawn(move || {
+        for action in actions {
+          let res = match action {
+            LockAction::ClearClipboard => match conn.clone() {
+              Some(conn) => clear_clipboard(conn),
+              None => continue,
+            },
+            LockAction::SshAgent => flush_ssh_agent(),
+            LockAction::GpgAgent => reload_gpg_agent(),
+          };
+
+          match res {
+            Ok(()) => info!("lock action {action:?} done"),
+            Err(err) => warn!("lock action {action:?} failed: {err}"),
+          }
+        }
+      });
+    }
+  });
+}
+
+impl Dispatch<WlRegistry, GlobalListContents> for ClipboardState {
+  fn event(
+    _state: &mut Self,
+    _proxy: &WlRegistry,
+    _event: <WlRegistry as Proxy>::Event,
+    _data: &GlobalListContents,
+    _conn: &Connection,
+    _qh: &QueueHandle<Self>,
+  ) {
+  }
+}
+
+impl Dispatch<ExtDataControlDeviceV1, ()> for ClipboardState {
+  fn event(
+    _state: &mut Self,
+    _proxy: &ExtDataControlDeviceV1,
+    event: ext_data_control_device_v1::Event,
+    _data: &(),
+    _conn: &Connection,
+    _qh: &QueueHandle<Self>,
+  ) {
+    if let ext_data_control_device_v1::Event::DataOffer { id } = event {
+      id.destroy();
+    }
+  }
+
+  event_created_child!(ClipboardState, ExtDataControlDeviceV1, [
+    ext_data_control_device_v1::EVT_DATA_OFFER_OPCODE => (ExtDataControlOfferV1, ()),
+  ]);
+}
+
+impl Dispatch<ZwlrDataControlDeviceV1, ()> for ClipboardState {
+  fn event(
+    _state: &mut Self,
+    _proxy: &ZwlrDataControlDeviceV1,
+    event: zwlr_data_control_device_v1::Event,
+    _data: &(),
+    _conn: &Connection,
+    _qh: &QueueHandle<Self>,
+  ) {
+    if let zwlr_data_control_device_v1::Event::DataOffer { id } = event {
+      id.destroy();
+    }
+  }
+
+  event_created_child!(ClipboardState, ZwlrDataControlDeviceV1, [
+    zwlr_data_control_device_v1::EVT_DATA_OFFER_OPCODE => (ZwlrDataControlOfferV1, ()),
+  ]);
+}
+
+wayland_client::delegate_noop!(ClipboardState: ignore WlSeat);
+wayland_client::delegate_noop!(ClipboardState: ExtDataControlManagerV1);
+wayland_client::delegate_noop!(ClipboardState: ZwlrDataControlManagerV1);
+wayland_client::delegate_noop!(ClipboardState: ignore ExtDataControlOfferV1);
+wayland_client::delegate_noop!(ClipboardState: ignore ZwlrDataControlOfferV1);
@@ -0,0 +1,215 @@
+use std::{cell::RefCell, ffi::OsStr};
+
+use gtk4::{gio, glib, prelude::*};
+use tracing::{info, warn};
+use xkbcommon::xkb;
+
+use crate::config::KeyboardConfig;
+
+/// Commands that switch to the next layout on compositors with an IPC for it,
+/// keyed by an environment variable those compositors set
+const SWITCH_COMMANDS: [(&str, &str); 3] = [
+  (
+    "SWAYSOCK",
+    "swaymsg input type:keyboard xkb_switch_layout next",
+  ),
+  (
+    "HYPRLAND_INSTANCE_SIGNATURE",
+    "hyprctl switchxkblayout all next",
+  ),
+  ("NIRI_SOCKET", "niri msg action switch-layout next"),
+];
+
+/// The keymap and lock modifiers reported to the lock surfaces' keyboard
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct State {
+  layouts: Vec<String>,
+  active: usize,
+  caps_lock: bool,
+  num_lock: bool,
+}
+
+thread_local! {
+  static STATE: RefCell<State> = RefCell::new(State::default());
+  static WIDGETS: RefCell<Vec<Widget>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Names of the layouts in a keymap in the xkb text format, like
+/// "English (US)". Safe to call from any thread.
+pub fn layout_names(keymap: String) -> Vec<String> {
+  let context = xkb::Context::new(xkb::CONTEXT_NO_FLAGS);
+  let Some(keymap) = xkb::Keymap::new_from_string(
+    &context,
+    keymap,
+    xkb::KEYMAP_FORMAT_TEXT_V1,
+    xkb::KEYMAP_COMPILE_NO_FLAGS,
+  ) else {
+    warn!("failed to parse the keymap from the compositor");
+    return Vec::new();
+  };
+
+  (0..keymap.num_layouts())
+    .map(|index| keymap.layout_get_name(index).to_string())
+    .collect()
+}
+
+fn update(f: impl FnOnce(&mut State)) {
+  let changed = STATE.with_borrow_mut(|state| {
+    let before = state.clone();
+    f(state);
+    *state != before
+  });
+
+  if changed {
+    WIDGETS.with_borrow_mut(|widgets| {
+      widgets.retain(|widget| widget.layout.upgrade().is_some());
+      widgets.iter().for_each(Widget::update);
+    });
+  }
+}
+
+/// Must be called on the main thread
+pub fn set_layouts(layouts: Vec<String>) {
+  update(|state| state.layouts = layouts);
+}
+
+/// Must be called on the main thread
+pub fn set_modifiers(layout: u32, caps_lock: bool, num_lock: bool) {
+  update(|state| {
+    state.active = layout as usize;
+    state.caps_lock = caps_lock;
+    state.num_lock = num_lock;
+  });
+}
+
+/// The compositor owns the keymap, so switching goes through its IPC
+fn switch_command(config: &KeyboardConfig) -> Option<String> {
+  if let Some(command) = &config.switch_command {
+    return Some(command.get_ref().clone());
+  }
+
+  SWITCH_COMMANDS
+    .iter()
+    .find(|(var, _)| std::env::var_os(var).is_some())
+    .map(|(_, command)| command.to_string())
+}
+
+/// Asks the compositor to switch to the next layout. The indicator updates
+/// once the compositor reports the new layout.
+fn next_layout(config: &KeyboardConfig) {
+  if STATE.with_borrow(|state| state.layouts.len()) < 2 {
+    return;
+  }
+
+  let Some(command) = switch_command(config) else {
+    warn!("unable to switch layouts on this compositor, set keyboard.switch_command");
+    return;
+  };
+
+  info!("switching keyboard layout");
+  let argv = [OsStr::new("sh"), OsStr::new("-c"), OsStr::new(&command)];
+  let child = match gio::Subprocess::newv(&argv, gio::SubprocessFlags::NONE) {
+    Ok(child) => child,
+    Err(err) => {
+      warn!("failed to run {command:?}: {err}");
+      return;
+    }
+  };
+
+  glib::spawn_future_local(async move {
+    match child.wait_future().await {
+      Ok(()) if child.is_successful() => {}
+      Ok(()) if child.has_exited() => {
+        warn!("{command:?} exited with status {}", child.exit_status())
+      }
+      Ok(()) => warn!("{command:?} was killed by signal {}", child.term_sig()),
+      Err(err) => warn!("failed to wait for {command:?}: {err}"),
+    }
+  });
+}
+
+/// The layout indicator and lock key warning of one window
+struct Widget {
+  layout: glib::WeakRef<gtk4::Button>,
+  warning: glib::WeakRef<gtk4::Label>,
+  warn_num_lock: bool,
+}
+
+impl Widget {
+  fn update(&self) {
+    let (Some(layout), Some(warning)) = (self.layout.upgrade(), self.warning.upgrade()) else {
+      return;
+    };
+
+    let state = STATE.with_borrow(Clone::clone);
+
+    let name = state.layouts.get(state.active);
+    layout.set_visible(name.is_some());
+    layout.set_label(name.map_or("", String::as_str));
+    layout.set_sensitive(state.layouts.len() > 1);
+
+    let mut warnings = Vec::new();
+    if state.caps_lock {
+      warnings.push("Caps Lock is on");
+    }
+
+    if state.num_lock && self.warn_num_lock {
+      warnings.push("Num Lock is on");
+    }
+
+    warning.set_label(&warnings.join(", "));
+    warning.set_visible(!warnings.is_empty());
+  }
+}
+
+/// Returns the layout indicator, which switches layouts when clicked, and
+/// the Caps Lock warning, to be placed next to the password entry
+pub fn widgets(config: &KeyboardConfig) -> (gtk4::Button, gtk4::Label) {
+  let layout = gtk4::Button::builder()
+    .css_classes(["keyboard-layout"])
+    .valign(gtk4::Align::Center)
+    .build();
+  let warning = gtk4::Label::builder()
+    .css_classes(["lock-key-warning"])
+    .build();
+
+  {
+    let config = config.clone();
+    layout.connect_clicked(move |_| next_layout(&config));
+  }
+
+  let widget = Widget {
+    layout: layout.downgrade(),
+    warning: warning.downgrade(),
+    warn_num_lock: config.warn_num_lock,
+  };
+
+  widget.update();
+  WIDGETS.with_borrow_mut(|widgets| widgets.push(widget));
+
+  (layout, warning)
+}
+
+/// Switches layouts with the configured hotkey while `window` has focus
+pub fn connect_hotkey(window: &gtk4::ApplicationWindow, config: &KeyboardConfig) {
+  let hotkey = config.switch_hotkey.get_ref();
+  if hotkey.is_empty() {
+    return;
+  }
+
+  let Some(trigger) = gtk4::ShortcutTrigger::parse_string(hotkey) else {
+    warn!("invalid keyboard.switch_hotkey {hotkey:?}");
+    return;
+  };
+
+  let config = config.clone();
+  let action = gtk4::CallbackAction::new(move |_, _| {
+    next_layout(&config);
+    glib::Propagation::Stop
+  });
+
+  let controller = gtk4::ShortcutController::new();
+  controller.set_scope(gtk4::ShortcutScope::Global);
+  controller.add_shortcut(gtk4::Shortcut::new(Some(trigger), Some(action)));
+  window.add_controller(controller);
+}
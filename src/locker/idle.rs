@@ -0,0 +1,87 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use smithay_client_toolkit::reexports::protocols::ext::idle_notify::v1::client::{
+  ext_idle_notification_v1::{self, ExtIdleNotificationV1},
+  ext_idle_notifier_v1::ExtIdleNotifierV1,
+};
+use tracing::{error, info};
+use wayland_client::{
+  globals::{registry_queue_init, GlobalListContents},
+  protocol::{wl_registry::WlRegistry, wl_seat::WlSeat},
+  Connection, Dispatch, QueueHandle,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdleEvent {
+  Idle,
+  Resumed,
+}
+
+struct IdleState {
+  idle_tx: flume::Sender<IdleEvent>,
+}
+
+/// Watches for user inactivity through ext_idle_notify_v1 on the GTK
+/// connection, and reports transitions on `idle_tx`. Must be called from the
+/// main thread after the display has been opened.
+pub fn watch(timeout: Duration, idle_tx: flume::Sender<IdleEvent>) -> Result<()> {
+  let conn = super::wayland::connection();
+  let (globals, mut event_queue) = registry_queue_init::<IdleState>(&conn)?;
+  let qh = event_queue.handle();
+
+  let seat: WlSeat = globals.bind(&qh, 1..=1, ())?;
+  let notifier: ExtIdleNotifierV1 = globals.bind(&qh, 1..=1, ())?;
+  let notification = notifier.get_idle_notification(timeout.as_millis() as u32, &seat, &qh, ());
+
+  info!("watching for {}s of inactivity", timeout.as_secs());
+
+  std::thread::spawn(move || {
+    // Keep the notification alive for as long as the thread runs
+    let _notification = notification;
+    let mut state = IdleState { idle_tx };
+
+    loop {
+      if let Err(err) = event_queue.blocking_dispatch(&mut state) {
+        error!("failed to dispatch idle events: {err}");
+        return;
+      }
+    }
+  });
+
+  Ok(())
+}
+
+impl Dispatch<ExtIdleNotificationV1, ()> for IdleState {
+  fn event(
+    state: &mut Self,
+    _proxy: &ExtIdleNotificationV1,
+    event: ext_idle_notification_v1::Event,
+    _data: &(),
+    _conn: &Connection,
+    _qh: &QueueHandle<Self>,
+  ) {
+    let event = match event {
+      ext_idle_notification_v1::Event::Idled => IdleEvent::Idle,
+      ext_idle_notification_v1::Event::Resumed => IdleEvent::Resumed,
+      _ => return,
+    };
+
+    let _ = state.idle_tx.send(event);
+  }
+}
+
+impl Dispatch<WlRegistry, GlobalListContents> for IdleState {
+  fn event(
+    _state: &mut Self,
+    _proxy: &WlRegistry,
+    _event: <WlRegistry as wayland_client::Proxy>::Event,
+    _data: &GlobalListContents,
+    _conn: &Connection,
+    _qh: &QueueHandle<Self>,
+  ) {
+  }
+}
+
+wayland_client::delegate_noop!(IdleState: ExtIdleNotifierV1);
+wayland_client::delegate_noop!(IdleState: ignore WlSeat);
@@ -1 +1,444 @@
+pub mod idle;
+pub mod preview;
 pub mod wayland;
+
+use std::{
+  cell::{Cell, RefCell},
+  rc::Rc,
+  sync::Arc,
+  time::{Duration, Instant, SystemTime},
+};
+
+use futures::future::LocalBoxFuture;
+use futures_signals::signal::{Mutable, Signal};
+use gtk4::{glib, prelude::*, Application};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+
+use self::preview::{PreviewHandle, PreviewMode};
+use crate::{
+  config::Config,
+  logging,
+  pam::{Backoff, PamMessage, PamThread},
+  summary, SendApp,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LockState {
+  Unlocked,
+  Locking,
+  Locked,
+}
+
+/// What asked for a lock
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LockReason {
+  /// Running dash3, which activates the resident instance
+  Command,
+  /// The idle timeout of the daemon
+  Idle,
+  /// The control socket
+  Control,
+  /// The org.freedesktop.ScreenSaver interface
+  ScreenSaver,
+}
+
+impl LockReason {
+  pub fn name(&self) -> &'static str {
+    match self {
+      LockReason::Command => "command",
+      LockReason::Idle => "idle",
+      LockReason::Control => "control",
+      LockReason::ScreenSaver => "screen-saver",
+    }
+  }
+}
+
+/// Lock lifecycle events. These are reported by the wayland and PAM threads
+/// and forwarded to all subscribers on the main thread.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum LockEvent {
+  /// A lock was requested and is about to be created
+  Locking,
+  Locked,
+  /// `count` is the number of consecutive failures during this lock
+  AuthFailed {
+    count: u32,
+    error: String,
+  },
+  Unlocked,
+  /// The compositor ended the lock without us unlocking it
+  Finished,
+}
+
+#[derive(Debug, Clone)]
+pub struct Failure {
+  pub at: SystemTime,
+  pub error: String,
+}
+
+/// What happened during a lock, kept until the next lock starts
+#[derive(Debug, Clone, Default)]
+pub struct Activity {
+  pub locked_at: Option<SystemTime>,
+  pub unlocked_at: Option<SystemTime>,
+  pub failures: Vec<Failure>,
+  /// Error messages PAM modules showed during the conversation
+  pub pam_errors: Vec<String>,
+}
+
+impl Activity {
+  pub fn has_failures(&self) -> bool {
+    !self.failures.is_empty() || !self.pam_errors.is_empty()
+  }
+}
+
+/// Ends the current lock once authentication succeeded
+enum Handle {
+  Session(wayland::LockHandle),
+  Preview(PreviewHandle),
+}
+
+impl Handle {
+  fn unlock(&self) {
+    match self {
+      Handle::Session(handle) => handle.unlock(),
+      Handle::Preview(handle) => handle.unlock(),
+    }
+  }
+}
+
+/// An idle PAM thread along with the channels to talk to it
+struct PreparedPam {
+  pam: PamThread,
+  pw_tx: flume::Sender<String>,
+  pam_rx: flume::Receiver<PamMessage>,
+}
+
+struct Inner {
+  app: Application,
+  config: Arc<Config>,
+  state: Mutable<LockState>,
+  requested_at: Cell<Option<Instant>>,
+  locked_at: Cell<Option<Instant>>,
+  lock_latency: Cell<Option<Duration>>,
+  failed_attempts: Cell<u32>,
+  retry_at: Cell<Option<Instant>>,
+  reason: Cell<Option<LockReason>>,
+  activity: RefCell<Activity>,
+  subscribers: RefCell<Vec<flume::Sender<LockEvent>>>,
+  pam: RefCell<Option<PamThread>>,
+  prewarm: Cell<bool>,
+  prepared: RefCell<Option<PreparedPam>>,
+  preview: Cell<Option<PreviewMode>>,
+  pre_lock: RefCell<Option<PreLock>>,
+}
+
+/// Awaited after a lock was requested and before the session is locked
+type PreLock = Rc<dyn Fn() -> LocalBoxFuture<'static, ()>>;
+
+/// Owns the lifecycle of a lock: the PAM thread, the session lock and the
+/// lock windows. Lives on the main thread.
+#[derive(Clone)]
+pub struct Locker {
+  inner: Rc<Inner>,
+}
+
+impl Locker {
+  pub fn new(app: &Application, config: Arc<Config>) -> Self {
+    Locker {
+      inner: Rc::new(Inner {
+        app: app.clone(),
+        config,
+        state: Mutable::new(LockState::Unlocked),
+        requested_at: Cell::new(None),
+        locked_at: Cell::new(None),
+        lock_latency: Cell::new(None),
+        failed_attempts: Cell::new(0),
+        retry_at: Cell::new(None),
+        reason: Cell::new(None),
+        activity: RefCell::new(Activity::default()),
+        subscribers: RefCell::new(Vec::new()),
+        pam: RefCell::new(None),
+        prewarm: Cell::new(false),
+        prepared: RefCell::new(None),
+        preview: Cell::new(None),
+        pre_lock: RefCell::new(None),
+      }),
+    }
+  }
+
+  pub fn state(&self) -> LockState {
+    self.inner.state.get()
+  }
+
+  pub fn state_signal(&self) -> impl Signal<Item = LockState> {
+    self.inner.state.signal()
+  }
+
+  /// When the compositor confirmed the current lock, if any
+  pub fn locked_at(&self) -> Option<Instant> {
+    self.inner.locked_at.get()
+  }
+
+  /// Time from the last lock request until the compositor confirmed the lock
+  pub fn lock_latency(&self) -> Option<Duration> {
+    self.inner.lock_latency.get()
+  }
+
+  /// Consecutive failed authentication attempts during the current lock
+  pub fn failed_attempts(&self) -> u32 {
+    self.inner.failed_attempts.get()
+  }
+
+  /// Until when input is refused after the last failed attempt
+  pub fn retry_at(&self) -> Option<Instant> {
+    self
+      .inner
+      .retry_at
+      .get()
+      .filter(|retry_at| *retry_at > Instant::now())
+  }
+
+  /// What asked for the current or last lock
+  pub fn lock_reason(&self) -> Option<LockReason> {
+    self.inner.reason.get()
+  }
+
+  /// What happened during the current lock, or the last one once unlocked
+  pub fn activity(&self) -> Activity {
+    self.inner.activity.borrow().clone()
+  }
+
+  pub fn subscribe(&self) -> flume::Receiver<LockEvent> {
+    let (tx, rx) = flume::unbounded();
+    let mut subscribers = self.inner.subscribers.borrow_mut();
+    subscribers.retain(|tx| !tx.is_disconnected());
+    subscribers.push(tx);
+    rx
+  }
+
+  fn broadcast(&self, event: LockEvent) {
+    self
+      .inner
+      .subscribers
+      .borrow_mut()
+      .retain(|tx| tx.send(event.clone()).is_ok());
+  }
+
+  fn backoff(&self) -> Option<Backoff> {
+    let timeouts = &self.inner.config.timeouts;
+    timeouts.fail_delay.as_ref().map(|initial| Backoff {
+      initial: Duration::from_secs(*initial.get_ref()),
+      max: Duration::from_secs(*timeouts.max_fail_delay.get_ref()),
+    })
+  }
+
+  fn prepare_pam(&self) -> PreparedPam {
+    let (pam_tx, pam_rx) = flume::unbounded::<PamMessage>();
+    let (pw_tx, pw_rx) = flume::unbounded::<String>();
+    let auth = &self.inner.config.auth;
+    let pam = PamThread::spawn(&auth.service, &auth.user(), self.backoff(), pw_rx, pam_tx);
+
+    PreparedPam { pam, pw_tx, pam_rx }
+  }
+
+  /// Keeps an idle PAM thread ready between locks, so a lock request only
+  /// needs to create the session lock and its surfaces
+  pub fn prewarm(&self) {
+    self.inner.prewarm.set(true);
+    if self.inner.prepared.borrow().is_none() {
+      self.inner.prepared.replace(Some(self.prepare_pam()));
+    }
+  }
+
+  /// Shows locks in a regular window with a mock authenticator instead of
+  /// locking the session
+  pub fn set_preview(&self, mode: PreviewMode) {
+    self.inner.preview.set(Some(mode));
+  }
+
+  /// Runs `f` to completion on each lock request before the session is
+  /// locked. The lock stays in `Locking` meanwhile.
+  pub fn set_pre_lock(&self, f: impl Fn() -> LocalBoxFuture<'static, ()> + 'static) {
+    self.inner.pre_lock.replace(Some(Rc::new(f)));
+  }
+
+  pub fn lock(&self, reason: LockReason) {
+    if self.state() != LockState::Unlocked {
+      info!("lock requested while {:?}, ignoring", self.state());
+      return;
+    }
+
+    info!("locking session, requested by {}", reason.name());
+    self.inner.reason.set(Some(reason));
+    self.inner.requested_at.set(Some(Instant::now()));
+    self.inner.state.set(LockState::Locking);
+    self.inner.failed_attempts.set(0);
+    self.inner.retry_at.set(None);
+    self.inner.activity.replace(Activity::default());
+    self.broadcast(LockEvent::Locking);
+
+    let pre_lock = self.inner.pre_lock.borrow().clone();
+    match pre_lock {
+      Some(pre_lock) => {
+        let locker = self.clone();
+        glib::spawn_future_local(async move {
+          pre_lock().await;
+          locker.start_lock();
+        });
+      }
+      None => self.start_lock(),
+    }
+  }
+
+  /// Opens the lock windows and starts authenticating
+  fn start_lock(&self) {
+    let is_loading = Mutable::new(false);
+    let (event_tx, event_rx) = flume::unbounded::<LockEvent>();
+    let (handle, pam_rx) = if let Some(mode) = self.inner.preview.get() {
+      let (pam_tx, pam_rx) = flume::unbounded::<PamMessage>();
+      let (pw_tx, pw_rx) = flume::unbounded::<String>();
+      preview::authenticate(self.backoff(), pw_rx, pam_tx);
+      let handle = preview::open(
+        &self.inner.app,
+        &self.inner.config,
+        mode,
+        pw_tx,
+        is_loading.clone(),
+        event_tx,
+      );
+      (Handle::Preview(handle), pam_rx)
+    } else {
+      let prepared = self.inner.prepared.take();
+      let PreparedPam { pam, pw_tx, pam_rx } = prepared.unwrap_or_else(|| self.prepare_pam());
+      pam.begin();
+
+      let handle = match wayland::lock_session(
+        SendApp(self.inner.app.clone()),
+        self.inner.config.clone(),
+        pw_tx,
+        is_loading.clone(),
+        event_tx,
+      ) {
+        Ok(handle) => handle,
+        Err(err) => {
+          error!("failed to lock session: {err}");
+          pam.cancel();
+          self.inner.state.set(LockState::Unlocked);
+          self.broadcast(LockEvent::Finished);
+          self.rearm();
+          return;
+        }
+      };
+
+      self.inner.pam.replace(Some(pam));
+      (Handle::Session(handle), pam_rx)
+    };
+
+    let locker = self.clone();
+    let transcript = self.inner.config.logging.pam_transcript;
+    glib::spawn_future_local(async move {
+      while let Ok(msg) = pam_rx.recv_async().await {
+        match msg {
+          PamMessage::Echo(s) => {
+            logging::pam_message("echo", &s, transcript);
+            is_loading.set(false);
+          }
+          PamMessage::Blind(s) => {
+            logging::pam_message("blind", &s, transcript);
+            is_loading.set(false);
+          }
+          PamMessage::Info(s) => logging::pam_message("info", &s, transcript),
+          PamMessage::Error(s) => {
+            logging::pam_message("error", &s, transcript);
+            locker.inner.activity.borrow_mut().pam_errors.push(s);
+          }
+          PamMessage::Failed { error, delay } => {
+            // The countdown replaces the spinner
+            is_loading.set(false);
+            locker.auth_failed(error, delay);
+          }
+          PamMessage::Success => {
+            let config = &locker.inner.config;
+            if let Some(shown) = summary::show_overlay(config, &locker.activity()) {
+              glib::timeout_future(shown).await;
+            }
+
+            handle.unlock();
+            break;
+          }
+        }
+      }
+    });
+
+    {
+      let locker = self.clone();
+      glib::spawn_future_local(async move {
+        while let Ok(event) = event_rx.recv_async().await {
+          locker.handle_event(event);
+        }
+      });
+    }
+  }
+
+  fn auth_failed(&self, error: String, delay: Duration) {
+    let count = self.inner.failed_attempts.get() + 1;
+    self.inner.failed_attempts.set(count);
+    self.inner.retry_at.set(Some(Instant::now() + delay));
+    self.inner.activity.borrow_mut().failures.push(Failure {
+      at: SystemTime::now(),
+      error: error.clone(),
+    });
+    info!("authentication failed ({count} attempts): {error}");
+    self.broadcast(LockEvent::AuthFailed { count, error });
+  }
+
+  fn handle_event(&self, event: LockEvent) {
+    match event {
+      LockEvent::Locked => {
+        let now = Instant::now();
+        let latency = self.inner.requested_at.get().map(|at| now - at);
+        if let Some(latency) = latency {
+          info!("session locked in {}ms", latency.as_millis());
+        }
+
+        self.inner.locked_at.set(Some(now));
+        self.inner.activity.borrow_mut().locked_at = Some(SystemTime::now());
+        self.inner.lock_latency.set(latency);
+        self.inner.state.set(LockState::Locked);
+      }
+      LockEvent::Locking | LockEvent::AuthFailed { .. } => {}
+      LockEvent::Unlocked | LockEvent::Finished => {
+        info!("session lock ended: {event:?}");
+        if let Some(pam) = self.inner.pam.take() {
+          match event {
+            LockEvent::Unlocked => pam.end(),
+            _ => pam.cancel(),
+          }
+        }
+
+        for window in self.inner.app.windows() {
+          window.destroy();
+        }
+
+        self.inner.locked_at.set(None);
+        self.inner.activity.borrow_mut().unlocked_at = Some(SystemTime::now());
+        self.inner.state.set(LockState::Unlocked);
+        self.rearm();
+      }
+    }
+
+    self.broadcast(event);
+  }
+
+  /// Prepares the PAM thread for the next lock when prewarming
+  fn rearm(&self) {
+    if self.inner.prewarm.get() {
+      self.prewarm();
+    }
+  }
+}
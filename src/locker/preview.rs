@@ -0,0 +1,143 @@
+use std::{sync::Arc, time::Duration};
+
+use futures_signals::signal::Mutable;
+use gtk4::{glib, prelude::*};
+use gtk4_layer_shell::{Edge, KeyboardMode, Layer, LayerShell};
+use tracing::info;
+
+use super::LockEvent;
+use crate::{
+  config::Config,
+  create_window,
+  pam::{Backoff, PamMessage},
+  scrambler::Scrambler,
+};
+
+/// The only password the mock authenticator accepts
+pub const PASSWORD: &str = "preview";
+const PROMPT: &str = "Password: ";
+const AUTH_ERROR: &str = "pam_authenticate: AUTH_ERR";
+/// How long the mock authenticator pretends to check a password, so the
+/// loading state can be styled too
+const CHECK_DURATION: Duration = Duration::from_millis(800);
+const WINDOW_SIZE: (i32, i32) = (1280, 800);
+
+/// Where the lock window of a preview is shown
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum PreviewMode {
+  /// A regular toplevel window
+  Window,
+  /// A fullscreen layer-shell surface above other windows. Unlike a session
+  /// lock, it can still be closed with Escape.
+  Overlay,
+}
+
+/// Ends a preview once the mock authentication succeeded
+pub struct PreviewHandle {
+  events: flume::Sender<LockEvent>,
+}
+
+impl PreviewHandle {
+  pub fn unlock(&self) {
+    let _ = self.events.send(LockEvent::Unlocked);
+  }
+}
+
+/// Shows the lock window without locking the session, with the GTK inspector
+/// open. Lock events are reported as if a session lock was confirmed.
+pub fn open(
+  app: &gtk4::Application,
+  config: &Config,
+  mode: PreviewMode,
+  pw_tx: flume::Sender<String>,
+  is_loading: Mutable<bool>,
+  events: flume::Sender<LockEvent>,
+) -> PreviewHandle {
+  let window = create_window(app, config, None, None, is_loading, pw_tx);
+  window.set_title(Some("dash3 preview"));
+
+  match mode {
+    PreviewMode::Window => window.set_default_size(WINDOW_SIZE.0, WINDOW_SIZE.1),
+    PreviewMode::Overlay => {
+      window.init_layer_shell();
+      window.set_layer(Layer::Overlay);
+      for edge in [Edge::Top, Edge::Bottom, Edge::Left, Edge::Right] {
+        window.set_anchor(edge, true);
+      }
+      window.set_keyboard_mode(KeyboardMode::OnDemand);
+    }
+  }
+
+  let close = gtk4::EventControllerKey::new();
+  {
+    let window = window.downgrade();
+    close.connect_key_pressed(move |_, key, _, _| {
+      if key != gtk4::gdk::Key::Escape {
+        return glib::Propagation::Proceed;
+      }
+
+      if let Some(window) = window.upgrade() {
+        window.close();
+      }
+      glib::Propagation::Stop
+    });
+  }
+  window.add_controller(close);
+
+  {
+    let events = events.clone();
+    window.connect_close_request(move |_| {
+      let _ = events.send(LockEvent::Finished);
+      glib::Propagation::Stop
+    });
+  }
+
+  info!("previewing in a {mode:?}, the password is {PASSWORD:?}");
+  window.present();
+  gtk4::Window::set_interactive_debugging(true);
+  let _ = events.send(LockEvent::Locked);
+
+  PreviewHandle { events }
+}
+
+/// Prompts like a PAM module would, accepting only `PASSWORD`. Failures wait
+/// for the configured fail delay, so the retry countdown can be previewed.
+pub fn authenticate(
+  backoff: Option<Backoff>,
+  pw_rx: flume::Receiver<String>,
+  pam_tx: flume::Sender<PamMessage>,
+) {
+  glib::spawn_future_local(async move {
+    let mut failures = 0;
+    while pam_tx.send(PamMessage::Blind(PROMPT.to_string())).is_ok() {
+      let Ok(mut pw) = pw_rx.recv_async().await else {
+        break;
+      };
+
+      glib::timeout_future(CHECK_DURATION).await;
+      let accepted = pw == PASSWORD;
+      pw.scramble();
+      if accepted {
+        let _ = pam_tx.send(PamMessage::Success);
+        break;
+      }
+
+      failures += 1;
+      let delay = backoff
+        .map(|backoff| backoff.delay(failures))
+        .unwrap_or_default();
+      let failed = PamMessage::Failed {
+        error: AUTH_ERROR.to_string(),
+        delay,
+      };
+      if pam_tx.send(failed).is_err() {
+        break;
+      }
+
+      glib::timeout_future(delay).await;
+      for mut pw in pw_rx.drain() {
+        pw.scramble();
+      }
+    }
+  });
+}
@@ -1,50 +1,89 @@
-use std::{sync::{Arc, Mutex}, time::Duration};
+use std::{
+  collections::HashMap,
+  sync::{Arc, Mutex},
+  time::Duration,
+};
 
 use anyhow::Result;
+use futures_signals::signal::Mutable;
 use gdk4_wayland::prelude::WaylandSurfaceExtManual;
 use gtk4::{glib::translate::ToGlibPtr, prelude::*};
 use smithay_client_toolkit::{
   output::{OutputHandler, OutputState},
   reexports::{
-    calloop::{channel::channel, EventLoop, LoopHandle},
+    calloop::{
+      channel::{self, channel},
+      EventLoop, LoopHandle,
+    },
     calloop_wayland_source::WaylandSource,
   },
   registry::{ProvidesRegistryState, RegistryState},
   registry_handlers,
-  session_lock::{
-    SessionLock, SessionLockHandler, SessionLockState, SessionLockSurface,
-    SessionLockSurfaceConfigure,
+  seat::{
+    keyboard::{KeyEvent, KeyboardHandler, Keymap, Keysym, Modifiers},
+    Capability, SeatHandler, SeatState,
   },
 };
-use tracing::{error, info};
+use tracing::{error, info, warn};
 use wayland_backend::client::Backend;
 use wayland_client::{
   globals::registry_queue_init,
   protocol::{
     wl_buffer,
+    wl_keyboard::WlKeyboard,
     wl_output::{self, WlOutput},
+    wl_seat::WlSeat,
     wl_surface::WlSurface,
   },
-  Connection, QueueHandle,
+  Connection, Dispatch, Proxy, QueueHandle, WEnum,
+};
+use wayland_protocols::ext::session_lock::v1::client::{
+  ext_session_lock_manager_v1::ExtSessionLockManagerV1,
+  ext_session_lock_surface_v1::{self, ExtSessionLockSurfaceV1},
+  ext_session_lock_v1::{self, ExtSessionLockV1},
+};
+
+use wayland_protocols_wlr::output_power_management::v1::client::{
+  zwlr_output_power_manager_v1::ZwlrOutputPowerManagerV1,
+  zwlr_output_power_v1::{self, ZwlrOutputPowerV1},
 };
 
-use crate::{create_window, pam::PamMessage, SendApp};
+use super::LockEvent;
+use crate::{
+  animation, config::Config, create_window, effects::Pixels, keyboard, screenshot, wallpaper,
+  SendApp,
+};
 
 struct WaylandState {
   app: SendApp,
   running: bool,
   loop_handle: LoopHandle<'static, Self>,
   conn: Connection,
-  session_lock_state: SessionLockState,
-  session_lock: Option<SessionLock>,
+  session_lock: Option<ExtSessionLockV1>,
+  /// Whether the compositor confirmed the lock, which then has to be
+  /// unlocked rather than just destroyed. Screenshot effects wait for it.
+  locked: Mutable<bool>,
   registry_state: RegistryState,
   output_state: OutputState,
-  surfaces: Arc<Mutex<Vec<SessionLockSurface>>>,
+  seat_state: SeatState,
+  /// Follows the keymap and lock keys for the layout indicator
+  keyboard: Option<WlKeyboard>,
+  /// The lock surface roles of the windows. The wl_surfaces themselves
+  /// belong to GTK.
+  surfaces: Arc<Mutex<Vec<ExtSessionLockSurfaceV1>>>,
+  /// Raw captures taken before locking, used as the backgrounds of the lock
+  /// surfaces once their effects are applied
+  screenshots: HashMap<WlOutput, Pixels>,
+  /// Reports outputs being powered off, so animations can pause
+  output_power_manager: Option<ZwlrOutputPowerManagerV1>,
+  output_powers: Vec<ZwlrOutputPowerV1>,
+
+  events: flume::Sender<LockEvent>,
+  config: Arc<Config>,
 
   // app state
   is_loading: futures_signals::signal::Mutable<bool>,
   pw_tx: flume::Sender<String>,
-  pam_rx: flume::Receiver<PamMessage>,
 }
 
 impl WaylandState {
@@ -54,13 +93,36 @@ impl WaylandState {
       return;
     };
 
-    session_lock.unlock();
+    if self.locked.get() {
+      session_lock.unlock_and_destroy();
+    } else {
+      session_lock.destroy();
+    }
+
+    if let Some(keyboard) = self.keyboard.take() {
+      release_keyboard(keyboard);
+    }
+
+    for output_power in self.output_powers.drain(..) {
+      output_power.destroy();
+    }
+
+    if let Some(manager) = self.output_power_manager.take() {
+      manager.destroy();
+    }
 
     // Sync connection to make sure compostor receives destroy
     if let Err(err) = self.conn.roundtrip() {
       error!("failed to roundtrip after unlocking session: {err}");
     };
 
+    // Only the roles are destroyed, the wl_surfaces go with the windows
+    for surface in self.surfaces.lock().unwrap().drain(..) {
+      surface.destroy();
+    }
+
+    let _ = self.events.send(LockEvent::Unlocked);
+
     // Then we can exit
     self.running = false;
   }
@@ -68,20 +130,39 @@ impl WaylandState {
   fn create_lock_surface(&mut self, qh: &QueueHandle<Self>, output: &WlOutput) -> Result<()> {
     let is_loading = self.is_loading.clone();
     let pw_tx = self.pw_tx.clone();
-    let pam_rx = self.pam_rx.clone();
+    let config = self.config.clone();
+    let output_info = self.output_state.info(output);
+    let mut screenshot = self.screenshots.remove(output);
+
+    let name = output_info.as_ref().and_then(|info| info.name.clone());
+    if let (Some(manager), Some(name)) = (&self.output_power_manager, name) {
+      let output_power = manager.get_output_power(output, qh, name);
+      self.output_powers.push(output_power);
+    }
 
     let session_lock = self.session_lock.as_ref().unwrap().clone();
     let app = self.app.clone();
     let qh = qh.clone();
     let output = output.clone();
     let surfaces = self.surfaces.clone();
+    let locked = self.locked.clone();
 
     gtk4::glib::idle_add(move || {
       info!("running on main thread");
 
       let mut surfaces = surfaces.lock().unwrap();
       let app = app.clone();
-      let win = create_window(&app.0, is_loading.clone(), pw_tx.clone(), pam_rx.clone());
+      let background = screenshot
+        .take()
+        .map(|screenshot| screenshot::picture(screenshot, &config.screenshot, &locked));
+      let win = create_window(
+        &app.0,
+        &config,
+        output_info.as_ref(),
+        background,
+        is_loading.clone(),
+        pw_tx.clone(),
+      );
       WidgetExt::realize(&win);
 
       let surface = win.surface().unwrap();
@@ -89,7 +170,7 @@ impl WaylandState {
       let wl_surface: WlSurface = surface.wl_surface().unwrap();
 
       info!("creating");
-      let surface = session_lock.create_lock_surface(wl_surface, &output, &qh);
+      let surface = session_lock.get_lock_surface(&wl_surface, &output, &qh, ());
       info!("pushing");
       surfaces.push(surface);
       info!("presenting window");
@@ -102,14 +183,23 @@ impl WaylandState {
   }
 }
 
-pub fn lock_session(
-  app: SendApp,
-  pw_tx: flume::Sender<String>,
-  pam_rx: flume::Receiver<PamMessage>,
-  is_loading: futures_signals::signal::Mutable<bool>,
-) -> Result<()> {
-  let (_unlock_tx, unlock_rx) = channel::<()>();
+/// Used to end a lock from the main thread once authentication succeeded
+#[derive(Clone)]
+pub struct LockHandle {
+  unlock_tx: channel::Sender<()>,
+}
 
+impl LockHandle {
+  pub fn unlock(&self) {
+    if self.unlock_tx.send(()).is_err() {
+      error!("wayland thread is gone, unable to unlock");
+    }
+  }
+}
+
+/// Creates a connection sharing the display GTK already opened. Must be
+/// called from the main thread.
+pub fn connection() -> Connection {
   let display = gtk4::gdk::Display::default().unwrap();
   let wl_display = display.downcast::<gdk4_wayland::WaylandDisplay>().unwrap();
 
@@ -117,9 +207,20 @@ pub fn lock_session(
     unsafe { gdk4_wayland::ffi::gdk_wayland_display_get_wl_display(wl_display.to_glib_none().0) };
 
   let wl_backend = unsafe { Backend::from_foreign_display(wl_display as *mut _) };
-  let wl_conn = Connection::from_backend(wl_backend);
+  Connection::from_backend(wl_backend)
+}
+
+pub fn lock_session(
+  app: SendApp,
+  config: Arc<Config>,
+  pw_tx: flume::Sender<String>,
+  is_loading: futures_signals::signal::Mutable<bool>,
+  events: flume::Sender<LockEvent>,
+) -> Result<LockHandle> {
+  let (unlock_tx, unlock_rx) = channel::<()>();
+  let wl_conn = connection();
 
-  let (globals, event_queue) = registry_queue_init(&wl_conn)?;
+  let (globals, mut event_queue) = registry_queue_init(&wl_conn)?;
 
   let qh: QueueHandle<WaylandState> = event_queue.handle();
 
@@ -128,46 +229,82 @@ pub fn lock_session(
       Ok(event_loop) => event_loop,
       Err(err) => {
         error!("Failed to create event loop: {err}");
-        // exit
+        let _ = events.send(LockEvent::Finished);
         return;
       }
     };
 
     let loop_handle = event_loop.handle();
 
-    // if let Err(err) = loop_handle.insert_source(unlock_rx, |_, _, app_data| app_data.unlock()) {
-    //   error!("failed to insert unlock source: {err}");
-    //   // exit
-    //   return;
-    // }
+    if let Err(err) = loop_handle.insert_source(unlock_rx, |event, _, app_data| {
+      if let channel::Event::Msg(()) = event {
+        app_data.unlock();
+      }
+    }) {
+      error!("failed to insert unlock source: {err}");
+      let _ = events.send(LockEvent::Finished);
+      return;
+    }
 
     let mut wl_state = WaylandState {
       app,
       running: true,
       output_state: OutputState::new(&globals, &qh),
+      seat_state: SeatState::new(&globals, &qh),
+      keyboard: None,
       registry_state: RegistryState::new(&globals),
       loop_handle,
       conn: wl_conn.clone(),
-      session_lock_state: SessionLockState::new(&globals, &qh),
       session_lock: None,
+      locked: Mutable::new(false),
+      events,
+      config,
       is_loading,
       pw_tx,
-      pam_rx,
       surfaces: Arc::new(Mutex::new(Vec::new())),
+      screenshots: HashMap::new(),
+      output_power_manager: globals.bind(&qh, 1..=1, ()).ok(),
+      output_powers: Vec::new(),
     };
 
-    let session_lock = match wl_state.session_lock_state.lock(&qh) {
-      Ok(session_lock) => session_lock,
+    // Output names and sizes are only known once the compositor sent them
+    if let Err(err) = event_queue.roundtrip(&mut wl_state) {
+      error!("failed to query outputs: {err}");
+    }
+
+    {
+      let outputs = wl_state
+        .output_state
+        .outputs()
+        .filter_map(|output| wl_state.output_state.info(&output))
+        .collect::<Vec<_>>();
+      gtk4::glib::idle_add_once(move || wallpaper::retain_cache(&outputs));
+    }
+
+    // Capturing has to finish before the lock is requested, since the
+    // compositor hides the desktop from then on. The effects are applied
+    // once locked.
+    if wl_state.config.screenshot.enabled {
+      let outputs: Vec<_> = wl_state.output_state.outputs().collect();
+      match screenshot::capture(&wl_conn, &outputs) {
+        Ok(screenshots) => wl_state.screenshots = screenshots,
+        Err(err) => warn!("failed to capture outputs, using wallpapers instead: {err}"),
+      }
+    }
+
+    let manager = globals.bind::<ExtSessionLockManagerV1, _, _>(&qh, 1..=1, ());
+    let session_lock = match manager {
+      Ok(manager) => manager.lock(&qh, ()),
       Err(err) => {
         error!("Compositor does not support ext_session_lock_v1: {err}");
-        // exit
+        let _ = wl_state.events.send(LockEvent::Finished);
         return;
       }
     };
 
     if let Err(err) = WaylandSource::new(wl_conn.clone(), event_queue).insert(event_loop.handle()) {
       error!("failed to insert wayland source: {err}");
-      // exit
+      let _ = wl_state.events.send(LockEvent::Finished);
       return;
     }
 
@@ -192,14 +329,14 @@ pub fn lock_session(
     // exit 0
   });
 
-  Ok(())
+  Ok(LockHandle { unlock_tx })
 }
 
 impl ProvidesRegistryState for WaylandState {
   fn registry(&mut self) -> &mut RegistryState {
     &mut self.registry_state
   }
-  registry_handlers![OutputState,];
+  registry_handlers![OutputState, SeatState];
 }
 
 impl OutputHandler for WaylandState {
@@ -232,25 +369,192 @@ impl OutputHandler for WaylandState {
   }
 }
 
-impl SessionLockHandler for WaylandState {
-  fn locked(&mut self, _conn: &Connection, _qh: &QueueHandle<Self>, _session_lock: SessionLock) {}
+/// `release` only exists since version 3, older keyboards are destroyed with
+/// the connection
+fn release_keyboard(keyboard: WlKeyboard) {
+  if keyboard.version() >= 3 {
+    keyboard.release();
+  }
+}
+
+impl SeatHandler for WaylandState {
+  fn seat_state(&mut self) -> &mut SeatState {
+    &mut self.seat_state
+  }
+
+  fn new_seat(&mut self, _conn: &Connection, _qh: &QueueHandle<Self>, _seat: WlSeat) {}
 
-  fn finished(&mut self, _conn: &Connection, _qh: &QueueHandle<Self>, _session_lock: SessionLock) {
-    self.running = false;
+  fn new_capability(
+    &mut self,
+    _conn: &Connection,
+    qh: &QueueHandle<Self>,
+    seat: WlSeat,
+    capability: Capability,
+  ) {
+    if capability != Capability::Keyboard || self.keyboard.is_some() {
+      return;
+    }
+
+    match self.seat_state.get_keyboard(qh, &seat, None) {
+      Ok(keyboard) => self.keyboard = Some(keyboard),
+      Err(err) => warn!("failed to get keyboard: {err}"),
+    }
+  }
+
+  fn remove_capability(
+    &mut self,
+    _conn: &Connection,
+    _qh: &QueueHandle<Self>,
+    _seat: WlSeat,
+    capability: Capability,
+  ) {
+    if capability == Capability::Keyboard {
+      if let Some(keyboard) = self.keyboard.take() {
+        release_keyboard(keyboard);
+      }
+    }
   }
 
-  fn configure(
+  fn remove_seat(&mut self, _conn: &Connection, _qh: &QueueHandle<Self>, _seat: WlSeat) {}
+}
+
+/// Key presses are handled by GTK, this keyboard only reports the keymap and
+/// lock keys to the layout indicator
+impl KeyboardHandler for WaylandState {
+  fn enter(
+    &mut self,
+    _conn: &Connection,
+    _qh: &QueueHandle<Self>,
+    _keyboard: &WlKeyboard,
+    _surface: &WlSurface,
+    _serial: u32,
+    _raw: &[u32],
+    _keysyms: &[Keysym],
+  ) {
+  }
+
+  fn leave(
+    &mut self,
+    _conn: &Connection,
+    _qh: &QueueHandle<Self>,
+    _keyboard: &WlKeyboard,
+    _surface: &WlSurface,
+    _serial: u32,
+  ) {
+  }
+
+  fn press_key(
     &mut self,
     _conn: &Connection,
     _qh: &QueueHandle<Self>,
-    _session_lock_surface: SessionLockSurface,
-    _configure: SessionLockSurfaceConfigure,
+    _keyboard: &WlKeyboard,
     _serial: u32,
+    _event: KeyEvent,
+  ) {
+  }
+
+  fn release_key(
+    &mut self,
+    _conn: &Connection,
+    _qh: &QueueHandle<Self>,
+    _keyboard: &WlKeyboard,
+    _serial: u32,
+    _event: KeyEvent,
+  ) {
+  }
+
+  fn update_modifiers(
+    &mut self,
+    _conn: &Connection,
+    _qh: &QueueHandle<Self>,
+    _keyboard: &WlKeyboard,
+    _serial: u32,
+    modifiers: Modifiers,
+    layout: u32,
+  ) {
+    gtk4::glib::MainContext::default()
+      .invoke(move || keyboard::set_modifiers(layout, modifiers.caps_lock, modifiers.num_lock));
+  }
+
+  fn update_keymap(
+    &mut self,
+    _conn: &Connection,
+    _qh: &QueueHandle<Self>,
+    _keyboard: &WlKeyboard,
+    keymap: Keymap<'_>,
+  ) {
+    let layouts = keyboard::layout_names(keymap.as_string());
+    gtk4::glib::MainContext::default().invoke(move || keyboard::set_layouts(layouts));
+  }
+}
+
+impl Dispatch<ExtSessionLockV1, ()> for WaylandState {
+  fn event(
+    state: &mut Self,
+    proxy: &ExtSessionLockV1,
+    event: ext_session_lock_v1::Event,
+    _data: &(),
+    _conn: &Connection,
+    _qh: &QueueHandle<Self>,
   ) {
+    match event {
+      ext_session_lock_v1::Event::Locked => {
+        state.locked.set(true);
+        let _ = state.events.send(LockEvent::Locked);
+      }
+      ext_session_lock_v1::Event::Finished => {
+        for surface in state.surfaces.lock().unwrap().drain(..) {
+          surface.destroy();
+        }
+        proxy.destroy();
+        state.session_lock = None;
+        let _ = state.events.send(LockEvent::Finished);
+        state.running = false;
+      }
+      _ => {}
+    }
+  }
+}
+
+impl Dispatch<ExtSessionLockSurfaceV1, ()> for WaylandState {
+  fn event(
+    _state: &mut Self,
+    proxy: &ExtSessionLockSurfaceV1,
+    event: ext_session_lock_surface_v1::Event,
+    _data: &(),
+    _conn: &Connection,
+    _qh: &QueueHandle<Self>,
+  ) {
+    // GTK sizes the window itself, the configure only has to be acked
+    if let ext_session_lock_surface_v1::Event::Configure { serial, .. } = event {
+      proxy.ack_configure(serial);
+    }
+  }
+}
+
+impl Dispatch<ZwlrOutputPowerV1, String> for WaylandState {
+  fn event(
+    _state: &mut Self,
+    _proxy: &ZwlrOutputPowerV1,
+    event: zwlr_output_power_v1::Event,
+    output: &String,
+    _conn: &Connection,
+    _qh: &QueueHandle<Self>,
+  ) {
+    let zwlr_output_power_v1::Event::Mode { mode } = event else {
+      return;
+    };
+
+    let on = mode != WEnum::Value(zwlr_output_power_v1::Mode::Off);
+    let output = output.clone();
+    gtk4::glib::MainContext::default().invoke(move || animation::set_output_power(&output, on));
   }
 }
 
 smithay_client_toolkit::delegate_output!(WaylandState);
-smithay_client_toolkit::delegate_session_lock!(WaylandState);
 smithay_client_toolkit::delegate_registry!(WaylandState);
+smithay_client_toolkit::delegate_seat!(WaylandState);
+smithay_client_toolkit::delegate_keyboard!(WaylandState);
 wayland_client::delegate_noop!(WaylandState: ignore wl_buffer::WlBuffer);
+wayland_client::delegate_noop!(WaylandState: ZwlrOutputPowerManagerV1);
+wayland_client::delegate_noop!(WaylandState: ExtSessionLockManagerV1);
@@ -0,0 +1,175 @@
+use std::{
+  cell::RefCell,
+  fs::OpenOptions,
+  os::unix::fs::OpenOptionsExt,
+  path::{Path, PathBuf},
+  sync::Mutex,
+};
+
+use anyhow::{anyhow, Result};
+use tracing::{debug, warn};
+use tracing_subscriber::{
+  fmt::MakeWriter, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer, Registry,
+};
+
+use crate::config::{self, LogFormat, LogTarget, LoggingConfig};
+
+const LOG_FILE: &str = "dash3.log";
+const SYSLOG_IDENTIFIER: &str = "dash3";
+/// Longer PAM messages are cut in the transcript
+const MAX_TRANSCRIPT_CHARS: usize = 200;
+
+type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+thread_local! {
+  /// Lowercase words of the user's names, masked by `sanitize`
+  static NAMES: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+}
+
+fn fmt_layer<W>(writer: W, format: LogFormat, ansi: bool) -> BoxedLayer
+where
+  W: for<'writer> MakeWriter<'writer> + Send + Sync + 'static,
+{
+  let layer = tracing_subscriber::fmt::layer()
+    .with_writer(writer)
+    .with_ansi(ansi);
+  match format {
+    LogFormat::Text => layer.boxed(),
+    LogFormat::Json => layer.json().boxed(),
+  }
+}
+
+/// `$XDG_STATE_HOME/dash3/dash3.log`
+fn default_path() -> Option<PathBuf> {
+  Some(config::state_dir()?.join(LOG_FILE))
+}
+
+fn file_layer(path: &Path, format: LogFormat) -> Result<BoxedLayer> {
+  if let Some(dir) = path.parent() {
+    std::fs::create_dir_all(dir)?;
+  }
+
+  let file = OpenOptions::new()
+    .create(true)
+    .append(true)
+    .mode(0o600)
+    .open(path)?;
+  Ok(fmt_layer(Mutex::new(file), format, false))
+}
+
+fn target_layer(config: &LoggingConfig) -> Result<BoxedLayer> {
+  match config.target {
+    LogTarget::Stderr => Ok(fmt_layer(std::io::stderr, config.format, true)),
+    LogTarget::Journald => {
+      let layer = tracing_journald::layer()?.with_syslog_identifier(SYSLOG_IDENTIFIER.to_string());
+      Ok(layer.boxed())
+    }
+    LogTarget::File => {
+      let path = match &config.file {
+        Some(file) => file.get_ref().clone(),
+        None => default_path().ok_or_else(|| anyhow!("unable to find the state directory"))?,
+      };
+      file_layer(&path, config.format)
+        .map_err(|err| anyhow!("failed to open {}: {err}", path.display()))
+    }
+  }
+}
+
+/// Installs the global subscriber. Falls back to stderr if the configured
+/// target is unavailable.
+pub fn init(config: &LoggingConfig) {
+  // The level was validated along with the config
+  let filter = EnvFilter::try_from_default_env()
+    .or_else(|_| EnvFilter::try_new(config.level.get_ref()))
+    .unwrap_or_else(|_| EnvFilter::new("info"));
+
+  let (layer, fallback) = match target_layer(config) {
+    Ok(layer) => (layer, None),
+    Err(err) => (fmt_layer(std::io::stderr, config.format, true), Some(err)),
+  };
+
+  tracing_subscriber::registry()
+    .with(layer)
+    .with(filter)
+    .init();
+
+  if let Some(err) = fallback {
+    warn!(
+      "logging to stderr, {:?} is unavailable: {err}",
+      config.target
+    );
+  }
+}
+
+/// Masks each word of `name`, like the login or the real name from GECOS,
+/// in sanitized messages. Single letters are kept, they are too common.
+pub fn redact(name: &str) {
+  NAMES.with_borrow_mut(|names| {
+    for word in name.split_whitespace().map(str::to_lowercase) {
+      if word.chars().count() > 1 && !names.contains(&word) {
+        names.push(word);
+      }
+    }
+  });
+}
+
+fn is_name(word: &str) -> bool {
+  let word = word
+    .trim_matches(|c: char| !c.is_alphanumeric())
+    .to_lowercase();
+  NAMES.with_borrow(|names| names.contains(&word))
+}
+
+/// Masks what could identify the user or be a one-time code: digits, the
+/// user's names, and words that look like email addresses. Control
+/// characters are dropped.
+pub fn sanitize(message: &str) -> String {
+  let mut sanitized = message
+    .split(' ')
+    .map(|word| {
+      if word.contains('@') || is_name(word) {
+        return "<redacted>".to_string();
+      }
+
+      word
+        .chars()
+        .filter(|c| !c.is_control())
+        .map(|c| if c.is_numeric() { '#' } else { c })
+        .collect()
+    })
+    .collect::<Vec<String>>()
+    .join(" ");
+
+  if let Some((cut, _)) = sanitized.char_indices().nth(MAX_TRANSCRIPT_CHARS) {
+    sanitized.truncate(cut);
+    sanitized.push('…');
+  }
+
+  sanitized
+}
+
+/// Logs a message of the PAM conversation at debug level. Only its kind is
+/// logged unless the transcript was enabled.
+pub fn pam_message(kind: &str, message: &str, transcript: bool) {
+  if transcript {
+    debug!(target: "dash3::pam", "{kind}: {}", sanitize(message));
+  } else {
+    debug!(target: "dash3::pam", "{kind} message ({} chars)", message.chars().count());
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn sanitize_masks_names_codes_and_addresses() {
+    redact("Alice B Smith");
+    redact("asmith");
+
+    assert_eq!(
+      sanitize("Welcome ALICE (asmith), code 1234 sent to a@b.c, see B\u{7}"),
+      "Welcome <redacted> <redacted> code #### sent to <redacted> see B"
+    );
+  }
+}
@@ -1,24 +1,62 @@
-use std::path::PathBuf;
+use std::{
+  cell::{Cell, RefCell},
+  rc::Rc,
+  sync::Arc,
+  time::Duration,
+};
 
+use clap::Parser;
+use config::Config;
 use futures_signals::signal::SignalExt;
 use gtk4::{
   gdk::Display,
+  gio,
   glib::{self},
   prelude::*,
-  style_context_add_provider_for_display, Application, ApplicationWindow, CssProvider,
-  STYLE_PROVIDER_PRIORITY_APPLICATION,
+  Application, ApplicationWindow,
 };
-use notify::Watcher;
-use pam::PamMessage;
-use tracing::info;
-
+use locker::{
+  idle::{self, IdleEvent},
+  LockEvent, LockReason, Locker,
+};
+use smithay_client_toolkit::output::OutputInfo;
+use tracing::{error, info};
+
+mod account;
+mod animation;
+mod assets;
+mod battery;
+mod cli;
+mod clock;
+mod config;
+mod control;
+mod effects;
+mod faillock;
+mod history;
+mod hooks;
+mod hygiene;
+mod keyboard;
 mod locker;
+mod logging;
+mod media;
+mod network;
 mod pam;
+mod power;
+mod render;
 mod scrambler;
-
-fn load_css() -> String {
-  grass::from_path("./src/styles.scss", &grass::Options::default()).unwrap()
-}
+mod screensaver;
+mod screenshot;
+mod socket;
+mod summary;
+#[cfg(test)]
+mod testbus;
+mod theme;
+mod wallpaper;
+
+const APPLICATION_ID: &str = "lol.happens.dash3";
+/// Separate from the locker's, so a preview never activates a resident
+/// instance
+const PREVIEW_APPLICATION_ID: &str = "lol.happens.dash3.preview";
 
 #[derive(Clone)]
 struct SendApp(pub gtk4::Application);
@@ -26,91 +64,241 @@ struct SendApp(pub gtk4::Application);
 unsafe impl Send for SendApp {}
 
 fn main() -> glib::ExitCode {
-  tracing_subscriber::fmt::init();
+  let args = cli::Args::parse();
+
+  if let Some(cli::Command::History(history)) = &args.command {
+    return history::query(history);
+  }
+
+  let loaded = match config::load(args.config.as_deref()) {
+    Ok(loaded) => loaded,
+    Err(err) => {
+      eprintln!("dash3: {err}");
+      return glib::ExitCode::FAILURE;
+    }
+  };
+
+  if args.check_config {
+    match &loaded {
+      Some((path, _)) => println!("{}: ok", path.display()),
+      None => println!("no config file found, using defaults"),
+    }
+
+    return glib::ExitCode::SUCCESS;
+  }
+
+  let config = Arc::new(loaded.map(|(_, config)| config).unwrap_or_default());
+
+  logging::init(&config.logging);
+  logging::redact(&config.auth.user());
   gtk4::gio::resources_register_include!("dash3.gresource").unwrap();
 
+  if let Some(cli::Command::Render(render)) = &args.command {
+    return render::run(render, &config);
+  }
+
+  // As a service, the app is not activated on startup. Running dash3 again
+  // activates the resident instance instead, which locks the session. A
+  // preview has its own ID and never activates the resident instance.
+  let (application_id, flags) = if args.preview.is_some() {
+    (PREVIEW_APPLICATION_ID, gio::ApplicationFlags::NON_UNIQUE)
+  } else if args.daemon {
+    (APPLICATION_ID, gio::ApplicationFlags::IS_SERVICE)
+  } else {
+    (APPLICATION_ID, gio::ApplicationFlags::empty())
+  };
+
   let app = Application::builder()
-    .application_id("lol.happens.dash3")
+    .application_id(application_id)
+    .flags(flags)
     .build();
 
-  let (style_reload_tx, style_reload_rx) = flume::unbounded::<()>();
-  app.connect_startup(move |_| {
-    let display = Display::default().unwrap();
+  {
+    let config = config.clone();
+    let preview = args.preview.is_some();
+    app.connect_startup(move |_| theme::load(&Display::default().unwrap(), &config, preview));
+  }
+
+  let locker = Locker::new(&app, config.clone());
+  if let Some(mode) = args.preview {
+    locker.set_preview(mode);
+  }
+
+  clock::follow(&locker);
+  account::follow(&locker, config.clone());
+  if config.widgets.battery {
+    battery::watch();
+  }
+
+  if config.widgets.network {
+    network::watch();
+  }
+
+  // A preview is not a lock, so it is neither recorded nor acted upon, and
+  // its failures don't count towards a lockout
+  let preview = args.preview.is_some();
+  faillock::follow(&locker, config.clone(), preview);
+  let pause_on_lock = config.media.pause_on_lock && !preview;
+  if config.widgets.media || pause_on_lock {
+    media::spawn(&locker, pause_on_lock);
+  }
+
+  if !preview {
+    summary::follow(&locker, config.clone());
+    history::follow(&locker, config.clone());
 
-    let style_provider = CssProvider::new();
-    style_provider.load_from_string(&load_css());
+    let mut hooks = config.hooks.hooks();
+    hooks.extend(args.hooks);
+    let hook_timeout = args.hook_timeout.unwrap_or(*config.timeouts.hook.get_ref());
+    hooks::spawn(&app, &locker, hooks, Duration::from_secs(hook_timeout));
 
-    style_context_add_provider_for_display(
-      &display,
-      &style_provider,
-      STYLE_PROVIDER_PRIORITY_APPLICATION,
-    );
+    let mut lock_actions = config.lock.actions.clone();
+    lock_actions.extend(args.lock_actions);
+    hygiene::spawn(&locker, lock_actions);
+  }
 
-    let move_style_provider = style_provider.clone();
-    let move_style_reload_rx = style_reload_rx.clone();
+  // Keep the app open even if there are no windows
+  let hold = app.hold();
+
+  // Kept for as long as the app runs, released after it quit
+  let daemon = Rc::new(RefCell::new(None));
+  let _hold = if args.daemon {
+    let locker = locker.clone();
+    let daemon = daemon.clone();
+    let idle_timeout = args
+      .idle_timeout
+      .or(config.timeouts.idle.as_ref().map(|idle| *idle.get_ref()))
+      .map(Duration::from_secs);
+
+    app.connect_startup(move |app| {
+      daemon.replace(Some(start_daemon(app, &locker, idle_timeout)));
+    });
+    Some(hold)
+  } else {
+    // Exit once the lock has ended and nothing else, like hooks, holds the
+    // app anymore
+    let events = locker.subscribe();
     glib::spawn_future_local(async move {
-      while let Ok(()) = move_style_reload_rx.recv_async().await {
-        move_style_provider.load_from_string(&load_css());
+      let _hold = hold;
+      while let Ok(event) = events.recv_async().await {
+        if matches!(event, LockEvent::Unlocked | LockEvent::Finished) {
+          break;
+        }
       }
     });
-  });
+    None
+  };
 
-  let mut watcher = notify::INotifyWatcher::new(
-    move |ev: Result<notify::Event, notify::Error>| match ev {
-      Ok(ev) => {
-        if !ev.kind.is_modify() {
-          return;
-        }
+  app.connect_activate(move |_| locker.lock(LockReason::Command));
+
+  // Arguments are handled by clap, GTK should not try to parse them
+  let code = app.run_with_args::<&str>(&[]);
+  drop(daemon.take());
+
+  code
+}
+
+/// What the daemon holds while it runs, given up on drop
+struct Daemon {
+  bus_name: Option<gio::OwnerId>,
+  _control: Option<control::ControlSocket>,
+}
+
+impl Drop for Daemon {
+  fn drop(&mut self) {
+    if let Some(bus_name) = self.bus_name.take() {
+      gio::bus_unown_name(bus_name);
+    }
+  }
+}
 
-        style_reload_tx.send(()).unwrap();
+fn start_daemon(app: &Application, locker: &Locker, idle_timeout: Option<Duration>) -> Daemon {
+  info!("running as daemon");
+  locker.prewarm();
+
+  // Quit instead of dying, so the control socket is cleaned up
+  for signal in [libc::SIGTERM, libc::SIGINT] {
+    let app = app.downgrade();
+    glib::unix_signal_add_local(signal, move || {
+      info!("received signal {signal}, exiting");
+      if let Some(app) = app.upgrade() {
+        app.quit();
       }
-      Err(e) => println!("Error: {:?}", e),
-    },
-    notify::Config::default(),
-  )
-  .unwrap();
-
-  watcher
-    .watch(
-      &PathBuf::from("./src/styles.scss"),
-      notify::RecursiveMode::NonRecursive,
-    )
-    .unwrap();
-
-  let (pam_tx, pam_rx) = flume::unbounded::<PamMessage>();
-  let (pw_tx, pw_rx) = flume::unbounded::<String>();
-  let handle = pam::PamThread::start("dash3", "happens", pw_rx, pam_tx);
+      glib::ControlFlow::Continue
+    });
+  }
 
-  // Keep the app open even if there are no windows
-  let _hold = app.hold();
+  let inhibitors = screensaver::Inhibitors::default();
+  let bus_name = screensaver::serve(locker.clone(), inhibitors.clone());
+
+  let control = control::serve(locker.clone())
+    .inspect_err(|err| error!("failed to start control socket: {err}"))
+    .ok();
+
+  if let Some(idle_timeout) = idle_timeout {
+    lock_when_idle(locker, inhibitors, idle_timeout);
+  }
 
-  app.connect_activate(move |app| activate(app, pam_rx.clone(), pw_tx.clone()));
-  let exit_code = app.run();
-  handle.end();
-  exit_code
+  Daemon {
+    bus_name: Some(bus_name),
+    _control: control,
+  }
 }
 
-fn activate(app: &Application, pam_rx: flume::Receiver<PamMessage>, pw_tx: flume::Sender<String>) {
-  let is_loading = futures_signals::signal::Mutable::new(false);
-  let _ = locker::wayland::lock_session(SendApp(app.clone()), pw_tx, pam_rx, is_loading);
+/// Locks after `idle_timeout` of inactivity. If locking is inhibited at that
+/// point, the session locks once the last inhibitor is released, unless the
+/// user came back in the meantime.
+fn lock_when_idle(locker: &Locker, inhibitors: screensaver::Inhibitors, idle_timeout: Duration) {
+  let (idle_tx, idle_rx) = flume::unbounded::<IdleEvent>();
+  if let Err(err) = idle::watch(idle_timeout, idle_tx) {
+    error!("failed to watch for inactivity: {err}");
+    return;
+  }
+
+  let idle = Rc::new(Cell::new(false));
+  {
+    let locker = locker.clone();
+    let idle = idle.clone();
+    inhibitors.connect_released(move || {
+      if idle.get() {
+        info!("last inhibitor released while idle, locking");
+        locker.lock(LockReason::Idle);
+      }
+    });
+  }
+
+  let locker = locker.clone();
+  glib::spawn_future_local(async move {
+    while let Ok(event) = idle_rx.recv_async().await {
+      idle.set(event == IdleEvent::Idle);
+      if event != IdleEvent::Idle {
+        continue;
+      }
+
+      if inhibitors.is_inhibited() {
+        info!("idle, but locking is inhibited");
+        continue;
+      }
+
+      locker.lock(LockReason::Idle);
+    }
+  });
 }
 
 fn ctl_button(icon: &str) -> gtk4::Button {
   gtk4::Button::builder()
     .css_classes(["ctl-button"])
-    .child(
-      &gtk4::Image::builder()
-        .resource(&format!("/lol/happens/dash3/icons/{icon}.svg"))
-        .build(),
-    )
+    .child(&assets::icon(icon))
     .build()
 }
 
 fn create_window(
   app: &gtk4::Application,
+  config: &Config,
+  output: Option<&OutputInfo>,
+  background: Option<gtk4::Picture>,
   is_loading: futures_signals::signal::Mutable<bool>,
   pw_tx: flume::Sender<String>,
-  pam_rx: flume::Receiver<PamMessage>,
 ) -> gtk4::ApplicationWindow {
   let login = gtk4::Box::builder()
     .orientation(gtk4::Orientation::Vertical)
@@ -119,16 +307,25 @@ fn create_window(
     .spacing(24)
     .build();
 
-  login.append(
-    &gtk4::Image::builder()
-      .resource("/lol/happens/dash3/profile.png")
-      .overflow(gtk4::Overflow::Hidden)
-      .halign(gtk4::Align::Center)
-      .width_request(108)
-      .height_request(108)
-      .css_classes(["avatar"])
-      .build(),
-  );
+  let widgets = &config.widgets;
+  if let Some(clock) = clock::widget(
+    &config.clock,
+    widgets.clock,
+    widgets.date,
+    widgets.locked_since,
+  ) {
+    login.append(&clock);
+  }
+
+  if widgets.avatar {
+    let avatar_size = *config.appearance.avatar_size.get_ref();
+    let scale = output.map_or(1, |output| output.scale_factor);
+    login.append(&account::avatar(avatar_size, scale));
+  }
+
+  if widgets.name {
+    login.append(&account::name());
+  }
 
   let input_container = gtk4::Box::builder()
     .orientation(gtk4::Orientation::Horizontal)
@@ -148,11 +345,7 @@ fn create_window(
   }
 
   let overlay = gtk4::Overlay::builder()
-    .child(
-      &gtk4::Image::builder()
-        .resource("/lol/happens/dash3/icons/fingerprint-simple.svg")
-        .build(),
-    )
+    .child(&assets::icon("icons/fingerprint-simple.svg"))
     .build();
 
   let spinner = gtk4::Spinner::new();
@@ -178,6 +371,13 @@ fn create_window(
   input_container.append(&input);
   input_container.append(&input_button);
   login.append(&input_container);
+  login.append(&faillock::widget(&input_container, &input));
+
+  if widgets.keyboard {
+    let (layout, lock_warning) = keyboard::widgets(&config.keyboard);
+    input_container.append(&layout);
+    login.append(&lock_warning);
+  }
 
   let msg = gtk4::Label::builder().label("message").build();
   login.append(&msg);
@@ -189,88 +389,109 @@ fn create_window(
     .spacing(10)
     .build();
 
-  ctl.append(&ctl_button("arrow-clockwise"));
-  ctl.append(&ctl_button("moon-stars"));
-  ctl.append(&ctl_button("power"));
+  let confirm_timeout = Duration::from_secs(*config.timeouts.power_confirm.get_ref());
+  for &action in &config.power.buttons {
+    let button = ctl_button(action.icon());
+    power::connect_button(&button, action, &msg, confirm_timeout);
+    ctl.append(&button);
+  }
+
+  ctl.set_visible(widgets.power);
+
+  let bottom = gtk4::Box::builder()
+    .orientation(gtk4::Orientation::Horizontal)
+    .halign(gtk4::Align::Center)
+    .spacing(24)
+    .build();
+
+  if widgets.media {
+    bottom.append(&media::widget(&config.media));
+  }
+
+  bottom.append(&ctl);
+
+  let footer = gtk4::Box::builder()
+    .orientation(gtk4::Orientation::Vertical)
+    .halign(gtk4::Align::Center)
+    .build();
+
+  if widgets.network {
+    footer.append(&network::widget());
+  }
+
+  footer.append(&bottom);
 
   let root = gtk4::CenterBox::builder()
     .orientation(gtk4::Orientation::Vertical)
     .halign(gtk4::Align::Center)
     .center_widget(&login)
-    .end_widget(&ctl)
+    .end_widget(&footer)
     .build();
 
+  if widgets.battery {
+    root.set_start_widget(Some(&battery::widget(&config.battery)));
+  }
+
   // root.set_sensitive(false);
   // root.set_visible(false);
 
+  let background = background.or_else(|| wallpaper::background(config, output));
+
+  let content: gtk4::Widget = match background {
+    Some(picture) => {
+      let overlay = gtk4::Overlay::builder().child(&picture).build();
+      overlay.add_overlay(&root);
+      overlay.upcast()
+    }
+    None => root.upcast(),
+  };
+  let content = summary::overlay(&content);
+
   let window = ApplicationWindow::builder()
     .application(app)
     .css_classes(["window"])
-    .child(&root)
+    .child(&content)
     .build();
 
-  {
-    let is_loading = is_loading.clone();
-    let app = app.downgrade();
-    glib::spawn_future_local(async move {
-      while let Ok(pam_rx) = pam_rx.recv_async().await {
-        match pam_rx {
-          PamMessage::Echo(s) => {
-            info!("echo: {s}");
-            is_loading.set(false);
-          }
-          PamMessage::Blind(s) => {
-            info!("blind: {s}");
-            is_loading.set(false);
-          }
-          PamMessage::Info(s) => info!("info: {s}"),
-          PamMessage::Error(s) => info!("error: {s}"),
-          PamMessage::Success => {
-            if let Some(app) = app.upgrade() {
-              app.quit();
-            }
-          }
-        }
-      }
-    });
-  }
+  keyboard::connect_hotkey(&window, &config.keyboard);
 
+  // The initial state is shown right away, so the window can be drawn before
+  // the main loop runs again
+  show_loading(&input, &input_button, &spinner, is_loading.get());
   glib::spawn_future_local(is_loading.signal().for_each(move |is_loading| {
     let input = input.downgrade();
     let input_button = input_button.downgrade();
     let spinner = spinner.downgrade();
 
     async move {
-      if is_loading {
-        if let Some(input) = input.upgrade() {
-          input.set_sensitive(false);
-        }
-
-        if let Some(input_button) = input_button.upgrade() {
-          input_button.set_sensitive(false);
-          input_button.add_css_class("loading");
-        }
-
-        if let Some(spinner) = spinner.upgrade() {
-          spinner.start();
-        }
-      } else {
-        if let Some(input) = input.upgrade() {
-          input.set_sensitive(true);
-          input.grab_focus();
-        }
-
-        if let Some(input_button) = input_button.upgrade() {
-          input_button.set_sensitive(true);
-          input_button.remove_css_class("loading");
-        }
-
-        if let Some(spinner) = spinner.upgrade() {
-          spinner.stop();
-        }
+      if let (Some(input), Some(input_button), Some(spinner)) =
+        (input.upgrade(), input_button.upgrade(), spinner.upgrade())
+      {
+        show_loading(&input, &input_button, &spinner, is_loading);
       }
     }
   }));
 
   window
 }
+
+/// Disables the input and spins while a password is checked
+fn show_loading(
+  input: &gtk4::PasswordEntry,
+  input_button: &gtk4::Button,
+  spinner: &gtk4::Spinner,
+  is_loading: bool,
+) {
+  if is_loading {
+    input.set_sensitive(false);
+    input_button.set_sensitive(false);
+    input_button.add_css_class("loading");
+    spinner.start();
+  } else {
+    input.set_sensitive(true);
+    input.grab_focus();
+    input_button.set_sensitive(true);
+    input_button.remove_css_class("loading");
+    spinner.stop();
+  }
+}
@@ -0,0 +1,694 @@
+use std::{cell::RefCell, collections::HashMap};
+
+use anyhow::{anyhow, Result};
+use gtk4::{gdk, gio, glib, prelude::*};
+use serde::Deserialize;
+use tracing::{info, warn};
+
+use crate::{
+  config::MediaConfig,
+  locker::{LockEvent, Locker},
+};
+
+const DBUS_NAME: &str = "org.freedesktop.DBus";
+const DBUS_PATH: &str = "/org/freedesktop/DBus";
+const PROPERTIES: &str = "org.freedesktop.DBus.Properties";
+
+/// Players own a bus name with this prefix
+const MPRIS_PREFIX: &str = "org.mpris.MediaPlayer2.";
+const MPRIS_PATH: &str = "/org/mpris/MediaPlayer2";
+const MPRIS_PLAYER: &str = "org.mpris.MediaPlayer2.Player";
+
+/// Size of the cover art in logical pixels
+const ART_SIZE: i32 = 48;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MediaControl {
+  Previous,
+  PlayPause,
+  Next,
+}
+
+impl MediaControl {
+  fn method(&self) -> &'static str {
+    match self {
+      MediaControl::Previous => "Previous",
+      MediaControl::PlayPause => "PlayPause",
+      MediaControl::Next => "Next",
+    }
+  }
+
+  fn icon(&self, playing: bool) -> &'static str {
+    match self {
+      MediaControl::Previous => "media-skip-backward-symbolic",
+      MediaControl::PlayPause if playing => "media-playback-pause-symbolic",
+      MediaControl::PlayPause => "media-playback-start-symbolic",
+      MediaControl::Next => "media-skip-forward-symbolic",
+    }
+  }
+
+  fn is_available(&self, player: &Player) -> bool {
+    match self {
+      MediaControl::Previous => player.can_go_previous,
+      MediaControl::PlayPause => player.can_pause || player.can_play,
+      MediaControl::Next => player.can_go_next,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PlaybackStatus {
+  Playing,
+  Paused,
+  Stopped,
+}
+
+/// The state of one MPRIS player
+#[derive(Debug, Clone, PartialEq)]
+pub struct Player {
+  /// Bus name of the player, like `org.mpris.MediaPlayer2.spotify`
+  name: String,
+  status: PlaybackStatus,
+  title: Option<String>,
+  artists: Vec<String>,
+  art_url: Option<String>,
+  can_play: bool,
+  can_pause: bool,
+  can_go_next: bool,
+  can_go_previous: bool,
+}
+
+thread_local! {
+  static MPRIS: RefCell<Option<Mpris>> = const { RefCell::new(None) };
+  /// The player shown on the lock screen
+  static CURRENT: RefCell<Option<Player>> = const { RefCell::new(None) };
+  static WIDGETS: RefCell<Vec<Widget>> = const { RefCell::new(Vec::new()) };
+  /// Cover art by URL, None while loading or when it failed to load
+  static ART: RefCell<HashMap<String, Option<gdk::Texture>>> = RefCell::new(HashMap::new());
+}
+
+/// Minimal client for MPRIS2 players. The connection is passed in so a fake
+/// player on a private bus can be used instead of the session bus.
+#[derive(Clone)]
+pub struct Mpris {
+  conn: gio::DBusConnection,
+}
+
+impl Mpris {
+  pub fn new(conn: gio::DBusConnection) -> Self {
+    Mpris { conn }
+  }
+
+  pub async fn session() -> Result<Self> {
+    let conn = gio::bus_get_future(gio::BusType::Session).await?;
+    Ok(Mpris::new(conn))
+  }
+
+  async fn call(
+    &self,
+    name: &str,
+    path: &str,
+    interface: &str,
+    method: &str,
+    args: Option<&glib::Variant>,
+    reply_type: &str,
+  ) -> Result<glib::Variant> {
+    let reply_type = glib::VariantTy::new(reply_type)?;
+    let reply = self
+      .conn
+      .call_future(
+        Some(name),
+        path,
+        interface,
+        method,
+        args,
+        Some(reply_type),
+        gio::DBusCallFlags::NONE,
+        -1,
+      )
+      .await?;
+
+    Ok(reply)
+  }
+
+  /// Bus names of all running players
+  pub async fn players(&self) -> Result<Vec<String>> {
+    let reply = self
+      .call(DBUS_NAME, DBUS_PATH, DBUS_NAME, "ListNames", None, "(as)")
+      .await?;
+    let (names,) = reply
+      .get::<(Vec<String>,)>()
+      .ok_or_else(|| anyhow!("unexpected reply {reply}"))?;
+
+    Ok(
+      names
+        .into_iter()
+        .filter(|name| name.starts_with(MPRIS_PREFIX))
+        .collect(),
+    )
+  }
+
+  pub async fn player(&self, name: &str) -> Result<Player> {
+    let reply = self
+      .call(
+        name,
+        MPRIS_PATH,
+        PROPERTIES,
+        "GetAll",
+        Some(&(MPRIS_PLAYER,).to_variant()),
+        "(a{sv})",
+      )
+      .await?;
+
+    let properties = glib::VariantDict::new(Some(&reply.child_value(0)));
+    let flag = |key: &str| {
+      properties
+        .lookup::<bool>(key)
+        .ok()
+        .flatten()
+        .unwrap_or(false)
+    };
+
+    let status = match properties
+      .lookup::<String>("PlaybackStatus")
+      .ok()
+      .flatten()
+      .as_deref()
+    {
+      Some("Playing") => PlaybackStatus::Playing,
+      Some("Paused") => PlaybackStatus::Paused,
+      _ => PlaybackStatus::Stopped,
+    };
+
+    let metadata = properties
+      .lookup_value("Metadata", Some(glib::VariantTy::VARDICT))
+      .map(|metadata| glib::VariantDict::new(Some(&metadata)))
+      .unwrap_or_default();
+
+    Ok(Player {
+      name: name.to_string(),
+      status,
+      title: metadata.lookup::<String>("xesam:title").ok().flatten(),
+      artists: metadata
+        .lookup::<Vec<String>>("xesam:artist")
+        .ok()
+        .flatten()
+        .unwrap_or_default(),
+      art_url: metadata.lookup::<String>("mpris:artUrl").ok().flatten(),
+      can_play: flag("CanPlay"),
+      can_pause: flag("CanPause"),
+      can_go_next: flag("CanGoNext"),
+      can_go_previous: flag("CanGoPrevious"),
+    })
+  }
+
+  /// Calls a method without arguments on a player, like `PlayPause`
+  pub async fn control(&self, name: &str, method: &str) -> Result<()> {
+    self
+      .call(name, MPRIS_PATH, MPRIS_PLAYER, method, None, "()")
+      .await?;
+
+    Ok(())
+  }
+
+  /// All players, skipping those that fail to answer
+  async fn all_players(&self) -> Vec<Player> {
+    let names = match self.players().await {
+      Ok(names) => names,
+      Err(err) => {
+        warn!("failed to list media players: {err}");
+        return Vec::new();
+      }
+    };
+
+    let mut players = Vec::new();
+    for name in names {
+      match self.player(&name).await {
+        Ok(player) => players.push(player),
+        Err(err) => warn!("failed to query media player {name}: {err}"),
+      }
+    }
+
+    players
+  }
+}
+
+/// Prefers a playing player, then a paused one that has a track
+fn pick(players: Vec<Player>) -> Option<Player> {
+  let playing = players
+    .iter()
+    .position(|player| player.status == PlaybackStatus::Playing);
+  let paused = players
+    .iter()
+    .position(|player| player.status == PlaybackStatus::Paused && player.title.is_some());
+
+  let index = playing.or(paused)?;
+  players.into_iter().nth(index)
+}
+
+/// The track, cover art and controls of one window
+struct Widget {
+  container: glib::WeakRef<gtk4::Box>,
+  art: gtk4::Picture,
+  title: gtk4::Label,
+  artist: gtk4::Label,
+  buttons: Vec<(MediaControl, gtk4::Button)>,
+  /// The cover art URL being shown
+  art_url: RefCell<Option<String>>,
+}
+
+impl Widget {
+  fn update(&self) {
+    let Some(container) = self.container.upgrade() else {
+      return;
+    };
+
+    let Some(player) = CURRENT.with_borrow(Clone::clone) else {
+      container.set_visible(false);
+      return;
+    };
+
+    container.set_visible(true);
+    self
+      .title
+      .set_label(player.title.as_deref().unwrap_or("Unknown track"));
+    self.artist.set_label(&player.artists.join(", "));
+    self.artist.set_visible(!player.artists.is_empty());
+
+    let playing = player.status == PlaybackStatus::Playing;
+    for (control, button) in &self.buttons {
+      button.set_icon_name(control.icon(playing));
+      button.set_sensitive(control.is_available(&player));
+    }
+
+    // Only local files, anything else would be fetched from the lock screen
+    let url = player.art_url.filter(|url| url.starts_with("file://"));
+    if *self.art_url.borrow() != url {
+      if let Some(url) = &url {
+        load_art(url);
+      }
+      self.art_url.replace(url);
+      self.show_art();
+    }
+  }
+
+  fn show_art(&self) {
+    let texture = self
+      .art_url
+      .borrow()
+      .as_ref()
+      .and_then(|url| ART.with_borrow(|art| art.get(url).cloned().flatten()));
+    self.art.set_visible(texture.is_some());
+    self.art.set_paintable(texture.as_ref());
+  }
+}
+
+/// Loads the cover art at `url` once, the widgets showing it are updated when
+/// it's done
+fn load_art(url: &str) {
+  if ART.with_borrow(|art| art.contains_key(url)) {
+    return;
+  }
+
+  ART.with_borrow_mut(|art| art.insert(url.to_string(), None));
+  let url = url.to_string();
+  glib::spawn_future_local(async move {
+    let texture = match gio::File::for_uri(&url).load_bytes_future().await {
+      Ok((bytes, _)) => gdk::Texture::from_bytes(&bytes),
+      Err(err) => Err(err),
+    };
+
+    let texture = match texture {
+      Ok(texture) => texture,
+      Err(err) => {
+        warn!("failed to load cover art {url}: {err}");
+        return;
+      }
+    };
+
+    ART.with_borrow_mut(|art| art.insert(url, Some(texture)));
+    WIDGETS.with_borrow(|widgets| widgets.iter().for_each(Widget::show_art));
+  });
+}
+
+fn update_all() {
+  WIDGETS.with_borrow_mut(|widgets| {
+    widgets.retain(|widget| widget.container.upgrade().is_some());
+    widgets.iter().for_each(Widget::update);
+  });
+}
+
+fn refresh() {
+  let Some(mpris) = MPRIS.with_borrow(Clone::clone) else {
+    return;
+  };
+
+  glib::spawn_future_local(async move {
+    let player = pick(mpris.all_players().await);
+    let changed =
+      CURRENT.with_borrow_mut(|current| std::mem::replace(current, player.clone()) != player);
+    if changed {
+      update_all();
+    }
+  });
+}
+
+/// Keeps track of the players on `mpris`'s bus, for the media widgets
+pub fn watch(mpris: Mpris) {
+  let conn = mpris.conn.clone();
+  MPRIS.set(Some(mpris));
+  refresh();
+
+  conn.signal_subscribe(
+    None,
+    Some(PROPERTIES),
+    Some("PropertiesChanged"),
+    Some(MPRIS_PATH),
+    Some(MPRIS_PLAYER),
+    gio::DBusSignalFlags::NONE,
+    |_, _, _, _, _, _| refresh(),
+  );
+
+  conn.signal_subscribe(
+    Some(DBUS_NAME),
+    Some(DBUS_NAME),
+    Some("NameOwnerChanged"),
+    Some(DBUS_PATH),
+    None,
+    gio::DBusSignalFlags::NONE,
+    |_, _, _, _, _, params| {
+      let Some((name, _, _)) = params.get::<(String, String, String)>() else {
+        return;
+      };
+
+      if name.starts_with(MPRIS_PREFIX) {
+        refresh();
+      }
+    },
+  );
+}
+
+/// Pauses all playing players once a lock is requested, and resumes them
+/// after unlocking
+pub fn pause_on_lock(locker: &Locker, mpris: Mpris) {
+  let events = locker.subscribe();
+  glib::spawn_future_local(async move {
+    let mut paused = Vec::new();
+    while let Ok(event) = events.recv_async().await {
+      match event {
+        LockEvent::Locking => {
+          for player in mpris.all_players().await {
+            if player.status != PlaybackStatus::Playing {
+              continue;
+            }
+
+            match mpris.control(&player.name, "Pause").await {
+              Ok(()) => paused.push(player.name),
+              Err(err) => warn!("failed to pause {}: {err}", player.name),
+            }
+          }
+
+          if !paused.is_empty() {
+            info!("paused {} media players", paused.len());
+          }
+        }
+        LockEvent::Unlocked | LockEvent::Finished => {
+          for name in paused.drain(..) {
+            if let Err(err) = mpris.control(&name, "Play").await {
+              warn!("failed to resume {name}: {err}");
+            }
+          }
+        }
+        LockEvent::Locked | LockEvent::AuthFailed { .. } => {}
+      }
+    }
+  });
+}
+
+/// Connects to the session bus, then watches players and, with `pause`,
+/// pauses them while locked
+pub fn spawn(locker: &Locker, pause: bool) {
+  let locker = locker.clone();
+  glib::spawn_future_local(async move {
+    let mpris = match Mpris::session().await {
+      Ok(mpris) => mpris,
+      Err(err) => {
+        warn!("failed to connect to session bus, media controls are disabled: {err}");
+        return;
+      }
+    };
+
+    if pause {
+      pause_on_lock(&locker, mpris.clone());
+    }
+
+    watch(mpris);
+  });
+}
+
+/// The current track with its cover art and the configured controls, hidden
+/// while no player is active
+pub fn widget(config: &MediaConfig) -> gtk4::Box {
+  let container = gtk4::Box::builder()
+    .orientation(gtk4::Orientation::Horizontal)
+    .valign(gtk4::Align::Center)
+    .css_classes(["media"])
+    .spacing(12)
+    .build();
+
+  let art = gtk4::Picture::builder()
+    .can_shrink(true)
+    .content_fit(gtk4::ContentFit::Cover)
+    .width_request(ART_SIZE)
+    .height_request(ART_SIZE)
+    .overflow(gtk4::Overflow::Hidden)
+    .visible(false)
+    .css_classes(["media-art"])
+    .build();
+  container.append(&art);
+
+  let track = gtk4::Box::builder()
+    .orientation(gtk4::Orientation::Vertical)
+    .valign(gtk4::Align::Center)
+    .build();
+  let title = gtk4::Label::builder()
+    .xalign(0.0)
+    .max_width_chars(32)
+    .ellipsize(gtk4::pango::EllipsizeMode::End)
+    .css_classes(["media-title"])
+    .build();
+  let artist = gtk4::Label::builder()
+    .xalign(0.0)
+    .max_width_chars(32)
+    .ellipsize(gtk4::pango::EllipsizeMode::End)
+    .css_classes(["media-artist"])
+    .build();
+  track.append(&title);
+  track.append(&artist);
+  container.append(&track);
+
+  let mut buttons = Vec::new();
+  for &control in &config.controls {
+    let button = gtk4::Button::builder()
+      .icon_name(control.icon(false))
+      .valign(gtk4::Align::Center)
+      .css_classes(["media-button"])
+      .build();
+
+    button.connect_clicked(move |_| {
+      let Some(mpris) = MPRIS.with_borrow(Clone::clone) else {
+        return;
+      };
+
+      let Some(name) = CURRENT.with_borrow(|current| current.as_ref().map(|p| p.name.clone()))
+      else {
+        return;
+      };
+
+      glib::spawn_future_local(async move {
+        if let Err(err) = mpris.control(&name, control.method()).await {
+          warn!("{} on {name} failed: {err}", control.method());
+        }
+      });
+    });
+
+    container.append(&button);
+    buttons.push((control, button));
+  }
+
+  let widget = Widget {
+    container: container.downgrade(),
+    art,
+    title,
+    artist,
+    buttons,
+    art_url: RefCell::new(None),
+  };
+
+  widget.update();
+  WIDGETS.with_borrow_mut(|widgets| widgets.push(widget));
+
+  container
+}
+
+#[cfg(test)]
+mod tests {
+  use std::rc::Rc;
+
+  use super::*;
+  use crate::testbus::{self, TestBus};
+
+  const PLAYER_XML: &str = r#"
+<node>
+  <interface name="org.mpris.MediaPlayer2.Player">
+    <method name="PlayPause"/>
+    <method name="Play"/>
+    <method name="Pause"/>
+    <method name="Next"/>
+    <method name="Previous"/>
+    <property name="PlaybackStatus" type="s" access="read"/>
+    <property name="Metadata" type="a{sv}" access="read"/>
+    <property name="CanPlay" type="b" access="read"/>
+    <property name="CanPause" type="b" access="read"/>
+    <property name="CanGoNext" type="b" access="read"/>
+    <property name="CanGoPrevious" type="b" access="read"/>
+  </interface>
+</node>
+"#;
+
+  const PLAYER_NAME: &str = "org.mpris.MediaPlayer2.fake";
+
+  struct FakePlayer {
+    /// Owns the fake player
+    _service: gio::DBusConnection,
+    client: Mpris,
+    status: Rc<RefCell<&'static str>>,
+    /// The methods it was called with
+    calls: Rc<RefCell<Vec<String>>>,
+  }
+
+  fn metadata() -> glib::Variant {
+    let metadata = glib::VariantDict::new(None);
+    metadata.insert_value("xesam:title", &"Song".to_variant());
+    metadata.insert_value("xesam:artist", &vec!["Band", "Singer"].to_variant());
+    metadata.insert_value("mpris:artUrl", &"file:///tmp/art.png".to_variant());
+    metadata.end()
+  }
+
+  async fn fake_player(bus: &TestBus) -> FakePlayer {
+    let service = bus.connect().await;
+    testbus::own_name(&service, PLAYER_NAME).await;
+    // Not a player, so it is not listed
+    testbus::own_name(&service, "org.example.Other").await;
+
+    let status = Rc::new(RefCell::new("Playing"));
+    let calls = Rc::new(RefCell::new(Vec::new()));
+    {
+      let handled_status = status.clone();
+      let calls = calls.clone();
+      let status = status.clone();
+      testbus::export_with_properties(
+        &service,
+        MPRIS_PATH,
+        PLAYER_XML,
+        move |method, _| {
+          match method {
+            "Pause" => *handled_status.borrow_mut() = "Paused",
+            "Play" => *handled_status.borrow_mut() = "Playing",
+            _ => {}
+          }
+          calls.borrow_mut().push(method.to_string());
+          Some(().to_variant())
+        },
+        move |property| match property {
+          "PlaybackStatus" => status.borrow().to_variant(),
+          "Metadata" => metadata(),
+          "CanGoPrevious" => false.to_variant(),
+          _ => true.to_variant(),
+        },
+      );
+    }
+
+    FakePlayer {
+      _service: service,
+      client: Mpris::new(bus.connect().await),
+      status,
+      calls,
+    }
+  }
+
+  #[test]
+  fn players_are_the_mpris_names() {
+    let bus = TestBus::up();
+    testbus::run(|| async {
+      let fake = fake_player(&bus).await;
+      assert_eq!(fake.client.players().await.unwrap(), [PLAYER_NAME]);
+    });
+  }
+
+  #[test]
+  fn player_reads_status_metadata_and_capabilities() {
+    let bus = TestBus::up();
+    testbus::run(|| async {
+      let fake = fake_player(&bus).await;
+      let player = fake.client.player(PLAYER_NAME).await.unwrap();
+      assert_eq!(
+        player,
+        Player {
+          name: PLAYER_NAME.to_string(),
+          status: PlaybackStatus::Playing,
+          title: Some("Song".to_string()),
+          artists: vec!["Band".to_string(), "Singer".to_string()],
+          art_url: Some("file:///tmp/art.png".to_string()),
+          can_play: true,
+          can_pause: true,
+          can_go_next: true,
+          can_go_previous: false,
+        }
+      );
+      assert!(!MediaControl::Previous.is_available(&player));
+      assert!(MediaControl::PlayPause.is_available(&player));
+    });
+  }
+
+  #[test]
+  fn control_calls_the_player() {
+    let bus = TestBus::up();
+    testbus::run(|| async {
+      let fake = fake_player(&bus).await;
+      let method = MediaControl::PlayPause.method();
+      fake.client.control(PLAYER_NAME, method).await.unwrap();
+      fake.client.control(PLAYER_NAME, "Pause").await.unwrap();
+      assert_eq!(*fake.calls.borrow(), ["PlayPause", "Pause"]);
+      assert_eq!(*fake.status.borrow(), "Paused");
+
+      let player = fake.client.player(PLAYER_NAME).await.unwrap();
+      assert_eq!(player.status, PlaybackStatus::Paused);
+    });
+  }
+
+  #[test]
+  fn pick_prefers_playing_then_paused_with_a_track() {
+    let player = |name: &str, status, title: Option<&str>| Player {
+      name: name.to_string(),
+      status,
+      title: title.map(str::to_string),
+      artists: Vec::new(),
+      art_url: None,
+      can_play: true,
+      can_pause: true,
+      can_go_next: true,
+      can_go_previous: true,
+    };
+
+    let paused = player("paused", PlaybackStatus::Paused, Some("Song"));
+    let empty = player("empty", PlaybackStatus::Paused, None);
+    let playing = player("playing", PlaybackStatus::Playing, None);
+
+    let picked = pick(vec![paused.clone(), playing.clone()]);
+    assert_eq!(picked, Some(playing));
+    assert_eq!(pick(vec![empty.clone(), paused.clone()]), Some(paused));
+    assert_eq!(pick(vec![empty]), None);
+  }
+}
@@ -0,0 +1,454 @@
+use std::{
+  cell::{Cell, RefCell},
+  collections::BTreeSet,
+  ffi::CStr,
+  io,
+  os::fd::{AsRawFd, FromRawFd, OwnedFd},
+  path::Path,
+  time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use gtk4::{
+  gio,
+  glib::{self, variant::ObjectPath},
+  prelude::*,
+};
+use tracing::{info, warn};
+
+const NM_NAME: &str = "org.freedesktop.NetworkManager";
+const NM_PATH: &str = "/org/freedesktop/NetworkManager";
+const NM_ACTIVE_CONNECTION: &str = "org.freedesktop.NetworkManager.Connection.Active";
+const NM_WIRELESS: &str = "org.freedesktop.NetworkManager.Device.Wireless";
+const NM_ACCESS_POINT: &str = "org.freedesktop.NetworkManager.AccessPoint";
+const PROPERTIES: &str = "org.freedesktop.DBus.Properties";
+
+/// `NM_ACTIVE_CONNECTION_STATE_ACTIVATED`
+const NM_ACTIVATED: u32 = 2;
+
+/// NetworkManager sends bursts of changes, like signal strength updates for
+/// every access point, so refreshes are coalesced
+const REFRESH_DELAY: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum Connectivity {
+  /// Not checked, or no way to tell
+  #[default]
+  Unknown,
+  None,
+  /// Connected to a network, but not to the internet, or behind a portal
+  Limited,
+  Full,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Link {
+  Wifi {
+    /// Unknown without NetworkManager
+    ssid: Option<String>,
+    /// In percent
+    strength: Option<u8>,
+  },
+  Wired,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+struct Status {
+  links: BTreeSet<Link>,
+  vpns: BTreeSet<String>,
+  connectivity: Connectivity,
+}
+
+thread_local! {
+  static STATUS: RefCell<Option<Status>> = const { RefCell::new(None) };
+  static WIDGETS: RefCell<Vec<Widget>> = const { RefCell::new(Vec::new()) };
+  static REFRESH_PENDING: Cell<bool> = const { Cell::new(false) };
+}
+
+impl Connectivity {
+  /// From `NMConnectivityState`, falling back to `NMState` when connectivity
+  /// checking is disabled
+  fn from_nm(connectivity: u32, state: u32) -> Connectivity {
+    match (connectivity, state) {
+      (4, _) => Connectivity::Full,
+      (2 | 3, _) => Connectivity::Limited,
+      (1, _) => Connectivity::None,
+      (_, 70) => Connectivity::Full,
+      (_, 50 | 60) => Connectivity::Limited,
+      (_, 10 | 20) => Connectivity::None,
+      _ => Connectivity::Unknown,
+    }
+  }
+}
+
+impl Status {
+  fn is_offline(&self) -> bool {
+    self.links.is_empty() || self.connectivity == Connectivity::None
+  }
+
+  fn summary(&self) -> String {
+    if self.links.is_empty() {
+      return "No network connection".to_string();
+    }
+
+    let mut parts: Vec<String> = self
+      .links
+      .iter()
+      .map(|link| match link {
+        Link::Wifi {
+          ssid: Some(ssid),
+          strength: Some(strength),
+        } => format!("Wi-Fi {ssid} ({strength}%)"),
+        Link::Wifi {
+          ssid: Some(ssid), ..
+        } => format!("Wi-Fi {ssid}"),
+        Link::Wifi { ssid: None, .. } => "Wi-Fi".to_string(),
+        Link::Wired => "Wired".to_string(),
+      })
+      .collect();
+
+    parts.extend(self.vpns.iter().map(|vpn| format!("VPN {vpn}")));
+
+    match self.connectivity {
+      Connectivity::None => parts.push("no internet".to_string()),
+      Connectivity::Limited => parts.push("limited connectivity".to_string()),
+      Connectivity::Full | Connectivity::Unknown => {}
+    }
+
+    parts.join(" · ")
+  }
+}
+
+async fn get_all(
+  conn: &gio::DBusConnection,
+  path: &str,
+  interface: &str,
+) -> Result<glib::VariantDict> {
+  let reply = conn
+    .call_future(
+      Some(NM_NAME),
+      path,
+      PROPERTIES,
+      "GetAll",
+      Some(&(interface,).to_variant()),
+      Some(glib::VariantTy::new("(a{sv})")?),
+      gio::DBusCallFlags::NONE,
+      -1,
+    )
+    .await?;
+
+  Ok(glib::VariantDict::new(Some(&reply.child_value(0))))
+}
+
+fn lookup<T: glib::variant::FromVariant>(properties: &glib::VariantDict, key: &str) -> Result<T> {
+  properties
+    .lookup::<T>(key)?
+    .ok_or_else(|| anyhow!("missing property {key}"))
+}
+
+/// SSID and signal strength of the access point a wireless device uses
+async fn access_point(conn: &gio::DBusConnection, device: &str) -> Result<(String, u8)> {
+  let wireless = get_all(conn, device, NM_WIRELESS).await?;
+  let access_point = lookup::<ObjectPath>(&wireless, "ActiveAccessPoint")?;
+  let properties = get_all(conn, access_point.as_str(), NM_ACCESS_POINT).await?;
+  let ssid = lookup::<Vec<u8>>(&properties, "Ssid")?;
+  let strength = lookup::<u8>(&properties, "Strength")?;
+
+  Ok((String::from_utf8_lossy(&ssid).into_owned(), strength))
+}
+
+async fn nm_status(conn: &gio::DBusConnection) -> Result<Status> {
+  let manager = get_all(conn, NM_PATH, NM_NAME).await?;
+  let mut status = Status {
+    connectivity: Connectivity::from_nm(
+      lookup::<u32>(&manager, "Connectivity").unwrap_or(0),
+      lookup::<u32>(&manager, "State").unwrap_or(0),
+    ),
+    ..Status::default()
+  };
+
+  for path in lookup::<Vec<ObjectPath>>(&manager, "ActiveConnections")? {
+    let active = match get_all(conn, path.as_str(), NM_ACTIVE_CONNECTION).await {
+      Ok(active) => active,
+      // Connections disappear while they are being listed
+      Err(_) => continue,
+    };
+
+    if lookup::<u32>(&active, "State")? != NM_ACTIVATED {
+      continue;
+    }
+
+    let id = lookup::<String>(&active, "Id")?;
+    match lookup::<String>(&active, "Type")?.as_str() {
+      "802-11-wireless" => {
+        let devices = lookup::<Vec<ObjectPath>>(&active, "Devices")?;
+        let access_point = match devices.first() {
+          Some(device) => access_point(conn, device.as_str()).await.ok(),
+          None => None,
+        };
+
+        status.links.insert(match access_point {
+          Some((ssid, strength)) => Link::Wifi {
+            ssid: Some(ssid),
+            strength: Some(strength),
+          },
+          None => Link::Wifi {
+            ssid: Some(id),
+            strength: None,
+          },
+        });
+      }
+      "802-3-ethernet" => {
+        status.links.insert(Link::Wired);
+      }
+      "vpn" | "wireguard" => {
+        status.vpns.insert(id);
+      }
+      // Bridges, loopback and the like
+      _ => {}
+    }
+  }
+
+  Ok(status)
+}
+
+/// Interfaces that are up and have an address, from `getifaddrs`
+fn interface_status() -> Status {
+  let mut status = Status::default();
+
+  let mut addrs = std::ptr::null_mut();
+  if unsafe { libc::getifaddrs(&mut addrs) } != 0 {
+    warn!("getifaddrs failed: {}", io::Error::last_os_error());
+    return status;
+  }
+
+  let mut ifa = addrs;
+  while let Some(entry) = unsafe { ifa.as_ref() } {
+    ifa = entry.ifa_next;
+
+    let flags = entry.ifa_flags as i32;
+    let up = flags & libc::IFF_UP != 0 && flags & libc::IFF_RUNNING != 0;
+    let family = unsafe { entry.ifa_addr.as_ref() }.map(|addr| addr.sa_family as i32);
+    if !up
+      || flags & libc::IFF_LOOPBACK != 0
+      || !matches!(family, Some(libc::AF_INET | libc::AF_INET6))
+    {
+      continue;
+    }
+
+    let name = unsafe { CStr::from_ptr(entry.ifa_name) }
+      .to_string_lossy()
+      .into_owned();
+    let sysfs = Path::new("/sys/class/net").join(&name);
+    if sysfs.join("wireless").exists() {
+      status.links.insert(Link::Wifi {
+        ssid: None,
+        strength: None,
+      });
+    } else if sysfs.join("tun_flags").exists() || name.starts_with("wg") {
+      status.vpns.insert(name);
+    } else {
+      status.links.insert(Link::Wired);
+    }
+  }
+
+  unsafe { libc::freeifaddrs(addrs) };
+  status
+}
+
+/// Notifies `tx` whenever a link or address changes, using an rtnetlink
+/// socket subscribed to those groups
+fn watch_rtnetlink(tx: flume::Sender<()>) -> Result<()> {
+  let fd = unsafe {
+    libc::socket(
+      libc::AF_NETLINK,
+      libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+      libc::NETLINK_ROUTE,
+    )
+  };
+  if fd < 0 {
+    return Err(io::Error::last_os_error().into());
+  }
+
+  let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+  let mut addr: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+  addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+  addr.nl_groups = (libc::RTMGRP_LINK | libc::RTMGRP_IPV4_IFADDR | libc::RTMGRP_IPV6_IFADDR) as u32;
+
+  let res = unsafe {
+    libc::bind(
+      fd.as_raw_fd(),
+      &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+      std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+    )
+  };
+  if res < 0 {
+    return Err(io::Error::last_os_error().into());
+  }
+
+  std::thread::spawn(move || {
+    // Only the fact that something changed matters, the messages are dropped
+    let mut buf = [0u8; 8192];
+    loop {
+      let n = unsafe { libc::recv(fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len(), 0) };
+      if n < 0 {
+        let err = io::Error::last_os_error();
+        if err.kind() == io::ErrorKind::Interrupted {
+          continue;
+        }
+
+        warn!("rtnetlink socket failed, network status won't update: {err}");
+        return;
+      }
+
+      if tx.send(()).is_err() {
+        return;
+      }
+    }
+  });
+
+  Ok(())
+}
+
+/// The status row of one window
+struct Widget {
+  container: glib::WeakRef<gtk4::Box>,
+  label: gtk4::Label,
+}
+
+impl Widget {
+  fn update(&self) {
+    let Some(container) = self.container.upgrade() else {
+      return;
+    };
+
+    let Some(status) = STATUS.with_borrow(Clone::clone) else {
+      container.set_visible(false);
+      return;
+    };
+
+    container.set_visible(true);
+    self.label.set_label(&status.summary());
+
+    for (class, enabled) in [
+      ("offline", status.is_offline()),
+      ("limited", status.connectivity == Connectivity::Limited),
+    ] {
+      if enabled {
+        container.add_css_class(class);
+      } else {
+        container.remove_css_class(class);
+      }
+    }
+  }
+}
+
+fn set_status(status: Status) {
+  let status = Some(status);
+  let changed =
+    STATUS.with_borrow_mut(|current| std::mem::replace(current, status.clone()) != status);
+  if !changed {
+    return;
+  }
+
+  WIDGETS.with_borrow_mut(|widgets| {
+    widgets.retain(|widget| widget.container.upgrade().is_some());
+    widgets.iter().for_each(Widget::update);
+  });
+}
+
+fn refresh_nm(conn: gio::DBusConnection) {
+  if REFRESH_PENDING.replace(true) {
+    return;
+  }
+
+  glib::timeout_add_local_once(REFRESH_DELAY, move || {
+    REFRESH_PENDING.set(false);
+    glib::spawn_future_local(async move {
+      match nm_status(&conn).await {
+        Ok(status) => set_status(status),
+        Err(err) => warn!("failed to read network status: {err}"),
+      }
+    });
+  });
+}
+
+fn watch_interfaces() {
+  set_status(interface_status());
+
+  let (tx, rx) = flume::unbounded::<()>();
+  if let Err(err) = watch_rtnetlink(tx) {
+    warn!("failed to watch rtnetlink, network status won't update: {err}");
+    return;
+  }
+
+  glib::spawn_future_local(async move {
+    while rx.recv_async().await.is_ok() {
+      // Changes arrive in bursts, only the state after them matters
+      glib::timeout_future(REFRESH_DELAY).await;
+      rx.drain().for_each(drop);
+      set_status(interface_status());
+    }
+  });
+}
+
+/// Follows NetworkManager, or the kernel's interfaces through rtnetlink when
+/// NetworkManager isn't running
+pub fn watch() {
+  glib::spawn_future_local(async move {
+    let conn = match gio::bus_get_future(gio::BusType::System).await {
+      Ok(conn) => conn,
+      Err(err) => {
+        warn!("failed to connect to the system bus: {err}");
+        watch_interfaces();
+        return;
+      }
+    };
+
+    match nm_status(&conn).await {
+      Ok(status) => set_status(status),
+      Err(err) => {
+        info!("NetworkManager unavailable, watching interfaces instead: {err}");
+        watch_interfaces();
+        return;
+      }
+    }
+
+    let updates = conn.clone();
+    conn.signal_subscribe(
+      Some(NM_NAME),
+      Some(PROPERTIES),
+      Some("PropertiesChanged"),
+      None,
+      None,
+      gio::DBusSignalFlags::NONE,
+      move |_, _, _, _, _, _| refresh_nm(updates.clone()),
+    );
+  });
+}
+
+/// A row with the network status, with the `offline` and `limited` style
+/// classes for themes
+pub fn widget() -> gtk4::Box {
+  let container = gtk4::Box::builder()
+    .orientation(gtk4::Orientation::Horizontal)
+    .halign(gtk4::Align::Center)
+    .css_classes(["ctl-container", "network"])
+    .spacing(10)
+    .build();
+
+  let label = gtk4::Label::builder()
+    .css_classes(["network-status"])
+    .build();
+  container.append(&label);
+
+  let widget = Widget {
+    container: container.downgrade(),
+    label,
+  };
+
+  widget.update();
+  WIDGETS.with_borrow_mut(|widgets| widgets.push(widget));
+
+  container
+}
@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 /// A trait representing the PAM authentification conversation
 ///
 /// PAM authentification is done as a conversation mechanism, in which PAM
@@ -27,4 +29,6 @@ pub trait Converse {
   /// This is an error message from PAM
   #[allow(clippy::result_unit_err)]
   fn error(&self, msg: &str) -> Result<(), ()>;
+  /// PAM asks to wait this long before the next attempt, after a failure
+  fn fail_delay(&self, delay: Duration);
 }
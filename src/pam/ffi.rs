@@ -1,6 +1,6 @@
-use std::{ffi::CStr, mem, pin::Pin};
+use std::{ffi::CStr, mem, pin::Pin, time::Duration};
 
-use libc::{c_char, c_int, c_void, calloc, free, memcpy, size_t};
+use libc::{c_char, c_int, c_uint, c_void, calloc, free, memcpy, size_t};
 use pam_sys::{PamConversation, PamMessage, PamMessageStyle, PamResponse, PamReturnCode};
 
 use super::converse::Converse;
@@ -18,6 +18,19 @@ pub fn make_conversation(conv: &mut PamConvHandlerWrapper) -> PamConversation {
   }
 }
 
+/// Registered as `PAM_FAIL_DELAY`, so libpam reports the delay instead of
+/// sleeping in `pam_authenticate`. Gets the conversation's data pointer.
+pub extern "C" fn fail_delay(retval: c_int, usec_delay: c_uint, appdata_ptr: *mut c_void) {
+  if retval == PamReturnCode::SUCCESS as c_int || appdata_ptr.is_null() {
+    return;
+  }
+
+  let wrapper = unsafe { &*(appdata_ptr as *const PamConvHandlerWrapper) };
+  wrapper
+    .handler
+    .fail_delay(Duration::from_micros(usec_delay as u64));
+}
+
 unsafe fn to_cstr(mut s: String) -> *mut c_char {
   let a = calloc(1, s.len() + 1) as *mut c_char;
   if a.is_null() {
@@ -3,14 +3,16 @@ mod env;
 mod ffi;
 pub mod session;
 
-use std::{cell::Cell, thread::JoinHandle};
+use std::{cell::Cell, thread::JoinHandle, time::Duration};
 
 use anyhow::Result;
 use flume::{Receiver, Sender};
 use thiserror::Error as ThisError;
 
 use pam_sys::PamReturnCode;
-use tracing::{info, warn};
+use tracing::{error, info, warn};
+
+use crate::scrambler::Scrambler;
 
 #[derive(Debug, ThisError)]
 pub enum PamError {
@@ -47,23 +49,53 @@ pub enum PamMessage {
   Blind(String),
   Info(String),
   Error(String),
+  /// An authentication attempt failed, a new session will be started once
+  /// `delay` passed. Passwords sent until then are dropped.
+  Failed {
+    error: String,
+    delay: Duration,
+  },
   Success,
 }
 
+/// Waits longer after each consecutive failure, starting at `initial` and
+/// doubling up to `max`
+#[derive(Debug, Clone, Copy)]
+pub struct Backoff {
+  pub initial: Duration,
+  pub max: Duration,
+}
+
+impl Backoff {
+  pub fn delay(&self, failures: u32) -> Duration {
+    let factor = 1u32
+      .checked_shl(failures.saturating_sub(1))
+      .unwrap_or(u32::MAX);
+    self.initial.saturating_mul(factor).min(self.max)
+  }
+}
+
 struct ChannelConv {
   pw_rx: Receiver<String>,
   pam_tx: Sender<PamMessage>,
   cancel_rx: Receiver<()>,
   canceled: Cell<bool>,
+  fail_delay: Sender<Duration>,
 }
 
 impl ChannelConv {
-  pub fn new(pw_rx: Receiver<String>, pam_tx: Sender<PamMessage>, cancel_rx: Receiver<()>) -> Self {
+  pub fn new(
+    pw_rx: Receiver<String>,
+    pam_tx: Sender<PamMessage>,
+    cancel_rx: Receiver<()>,
+    fail_delay: Sender<Duration>,
+  ) -> Self {
     ChannelConv {
       pw_rx,
       pam_tx,
       cancel_rx,
       canceled: Cell::new(false),
+      fail_delay,
     }
   }
 }
@@ -124,26 +156,78 @@ impl converse::Converse for ChannelConv {
       .send(PamMessage::Error(msg.to_string()))
       .map_err(|err| warn!("send error: {err}"))
   }
+
+  fn fail_delay(&self, delay: Duration) {
+    let _ = self.fail_delay.send(delay);
+  }
 }
 
 pub struct PamThread {
   handle: JoinHandle<()>,
   cancel_tx: Sender<()>,
+  begin_tx: Sender<()>,
 }
 
 impl PamThread {
-  pub fn start(app: &str, user: &str, pw_rx: Receiver<String>, pam_tx: Sender<PamMessage>) -> Self {
+  /// Spawns the handler thread without starting a PAM session. The thread
+  /// stays idle until `begin` is called, so PAM modules don't start
+  /// authenticating (e.g. activating a fingerprint reader) ahead of time.
+  pub fn spawn(
+    app: &str,
+    user: &str,
+    backoff: Option<Backoff>,
+    pw_rx: Receiver<String>,
+    pam_tx: Sender<PamMessage>,
+  ) -> Self {
     info!("Starting PAM handler thread");
     let (cancel_tx, cancel_rx) = flume::unbounded::<()>();
+    let (begin_tx, begin_rx) = flume::bounded::<()>(1);
 
     let app = app.to_string();
     let user = user.to_string();
 
-    let handle = std::thread::spawn(move || 'session: loop {
+    let handle = std::thread::spawn(move || {
+      let begin = flume::Selector::new()
+        .recv(&begin_rx, |res| res.is_ok())
+        .recv(&cancel_rx, |_| false)
+        .wait();
+
+      if begin {
+        Self::run(&app, &user, backoff, pw_rx, pam_tx, cancel_rx);
+      }
+    });
+
+    PamThread {
+      handle,
+      cancel_tx,
+      begin_tx,
+    }
+  }
+
+  fn run(
+    app: &str,
+    user: &str,
+    backoff: Option<Backoff>,
+    pw_rx: Receiver<String>,
+    pam_tx: Sender<PamMessage>,
+    cancel_rx: Receiver<()>,
+  ) {
+    let (delay_tx, delay_rx) = flume::unbounded::<Duration>();
+    let mut failures = 0;
+
+    'session: loop {
       info!("Starting PAM session");
-      let conv = ChannelConv::new(pw_rx.clone(), pam_tx.clone(), cancel_rx.clone());
+      let conv = ChannelConv::new(
+        pw_rx.clone(),
+        pam_tx.clone(),
+        cancel_rx.clone(),
+        delay_tx.clone(),
+      );
       let conv = Box::pin(conv);
-      let mut pam_session = session::PamSession::start(&app, &user, conv).unwrap();
+      let mut pam_session = session::PamSession::start(app, user, conv).unwrap();
+      if let Err(err) = pam_session.set_fail_delay() {
+        warn!("failed to set the fail delay callback, PAM will sleep instead: {err}");
+      }
 
       let err = match pam_session.authenticate(pam_sys::PamFlag::NONE) {
         Ok(()) => {
@@ -155,26 +239,61 @@ impl PamThread {
       };
 
       match err {
-        PamError::Error(err) | PamError::AuthError(err) | PamError::AbortError(err) => {
-          pam_tx.send(PamMessage::Error(err)).unwrap();
+        PamError::Error(error) | PamError::AuthError(error) | PamError::AbortError(error) => {
           pam_session.end().unwrap();
+
+          failures += 1;
+          let requested = delay_rx.try_iter().max().unwrap_or_default();
+          let delay = backoff
+            .map(|backoff| backoff.delay(failures))
+            .unwrap_or_default()
+            .max(requested);
+
+          pam_tx.send(PamMessage::Failed { error, delay }).unwrap();
+
+          if !delay.is_zero() {
+            info!("waiting {}ms before the next attempt", delay.as_millis());
+            if cancel_rx.recv_timeout(delay).is_ok() {
+              break 'session;
+            }
+          }
+
+          for mut pw in pw_rx.drain() {
+            pw.scramble();
+          }
         }
         PamError::ConvError => {
           // This means the conversation was cancelled and the thread should exit
           break 'session;
         }
       }
-    });
+    }
+  }
 
-    PamThread { handle, cancel_tx }
+  /// Starts the first PAM session on an idle thread
+  pub fn begin(&self) {
+    let _ = self.begin_tx.try_send(());
   }
 
   pub fn cancel(self) {
-    self.cancel_tx.send(()).unwrap();
-    self.handle.join().unwrap();
+    // The thread may already have exited after a successful authentication
+    let _ = self.cancel_tx.send(());
+    self.detach();
   }
 
   pub fn end(self) {
-    self.handle.join().unwrap();
+    self.detach();
+  }
+
+  /// Lets the thread finish in the background. A PAM module can block outside
+  /// the conversation, like pam_fprintd waiting for a finger, so the thread
+  /// is never joined on the main thread.
+  fn detach(self) {
+    let handle = self.handle;
+    std::thread::spawn(move || {
+      if handle.join().is_err() {
+        error!("PAM handler thread panicked");
+      }
+    });
   }
 }
@@ -7,13 +7,13 @@ use std::{
   ptr,
 };
 
-use libc::c_void;
+use libc::{c_int, c_uint, c_void};
 use pam_sys::{PamFlag, PamHandle, PamItemType, PamReturnCode};
 
 use super::{
   converse::Converse,
   env::{get_pam_env, PamEnvList},
-  ffi::{make_conversation, PamConvHandlerWrapper},
+  ffi::{fail_delay, make_conversation, PamConvHandlerWrapper},
   PamError,
 };
 
@@ -105,6 +105,23 @@ impl<'a> PamSession<'a> {
     }
   }
 
+  /// Makes libpam report failure delays to the conversation's `fail_delay`
+  /// instead of sleeping
+  pub fn set_fail_delay(&mut self) -> Result<(), PamError> {
+    let delay_fn: extern "C" fn(c_int, c_uint, *mut c_void) = fail_delay;
+    self.last_code = PamReturnCode::from(unsafe {
+      pam_sys::raw::pam_set_item(
+        self.handle,
+        PamItemType::FAIL_DELAY as i32,
+        delay_fn as *const c_void,
+      )
+    });
+    match self.last_code {
+      PamReturnCode::SUCCESS => Ok(()),
+      rc => Err(PamError::from_rc("pam_set_item", rc)),
+    }
+  }
+
   pub fn get_user(&mut self) -> Result<String, PamError> {
     let mut p: *const c_char = ptr::null_mut();
     self.last_code = pam_sys::get_user(self.handle, &mut p, ptr::null());
@@ -0,0 +1,428 @@
+use std::{cell::RefCell, rc::Rc, time::Duration};
+
+use anyhow::Result;
+use gtk4::{gio, glib, prelude::*};
+use serde::Deserialize;
+use tracing::{error, info, warn};
+
+const LOGIND_NAME: &str = "org.freedesktop.login1";
+const LOGIND_PATH: &str = "/org/freedesktop/login1";
+const LOGIND_MANAGER: &str = "org.freedesktop.login1.Manager";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PowerAction {
+  Suspend,
+  Reboot,
+  PowerOff,
+}
+
+impl PowerAction {
+  pub fn icon(&self) -> &'static str {
+    match self {
+      PowerAction::Suspend => "icons/moon-stars.svg",
+      PowerAction::Reboot => "icons/arrow-clockwise.svg",
+      PowerAction::PowerOff => "icons/power.svg",
+    }
+  }
+
+  /// Destructive actions need a second click before they are performed
+  pub fn is_destructive(&self) -> bool {
+    matches!(self, PowerAction::Reboot | PowerAction::PowerOff)
+  }
+
+  fn method(&self) -> &'static str {
+    match self {
+      PowerAction::Suspend => "Suspend",
+      PowerAction::Reboot => "Reboot",
+      PowerAction::PowerOff => "PowerOff",
+    }
+  }
+
+  fn can_method(&self) -> &'static str {
+    match self {
+      PowerAction::Suspend => "CanSuspend",
+      PowerAction::Reboot => "CanReboot",
+      PowerAction::PowerOff => "CanPowerOff",
+    }
+  }
+
+  /// The inhibitor lock type that blocks this action
+  fn inhibit_what(&self) -> &'static str {
+    match self {
+      PowerAction::Suspend => "sleep",
+      PowerAction::Reboot | PowerAction::PowerOff => "shutdown",
+    }
+  }
+
+  fn verb(&self) -> &'static str {
+    match self {
+      PowerAction::Suspend => "suspend",
+      PowerAction::Reboot => "restart",
+      PowerAction::PowerOff => "shut down",
+    }
+  }
+
+  fn noun(&self) -> &'static str {
+    match self {
+      PowerAction::Suspend => "suspend",
+      PowerAction::Reboot => "restart",
+      PowerAction::PowerOff => "shutdown",
+    }
+  }
+}
+
+/// Minimal client for the logind manager interface. The connection is passed
+/// in so a mock login1 on a private bus can be used instead of the system bus.
+#[derive(Clone)]
+pub struct Logind {
+  conn: gio::DBusConnection,
+}
+
+impl Logind {
+  pub fn new(conn: gio::DBusConnection) -> Self {
+    Logind { conn }
+  }
+
+  pub async fn system() -> Result<Self> {
+    let conn = gio::bus_get_future(gio::BusType::System).await?;
+    Ok(Logind::new(conn))
+  }
+
+  async fn call(
+    &self,
+    method: &str,
+    args: Option<&glib::Variant>,
+    reply_type: &str,
+  ) -> Result<glib::Variant> {
+    let reply_type = glib::VariantTy::new(reply_type)?;
+    let reply = self
+      .conn
+      .call_future(
+        Some(LOGIND_NAME),
+        LOGIND_PATH,
+        LOGIND_MANAGER,
+        method,
+        args,
+        Some(reply_type),
+        gio::DBusCallFlags::NONE,
+        -1,
+      )
+      .await?;
+
+    Ok(reply)
+  }
+
+  /// Whether the action can be performed without interactive authorization.
+  /// `challenge` is treated as unavailable, since no polkit agent can prompt
+  /// while the session is locked.
+  pub async fn can(&self, action: PowerAction) -> bool {
+    match self.call(action.can_method(), None, "(s)").await {
+      Ok(reply) => reply.get::<(String,)>().is_some_and(|(res,)| res == "yes"),
+      Err(err) => {
+        warn!("{} failed: {err}", action.can_method());
+        false
+      }
+    }
+  }
+
+  /// Returns the names of all applications holding a blocking inhibitor for
+  /// the given action
+  pub async fn blocking_inhibitors(&self, action: PowerAction) -> Result<Vec<String>> {
+    let reply = self.call("ListInhibitors", None, "(a(ssssuu))").await?;
+    let Some((inhibitors,)) = reply.get::<(Vec<(String, String, String, String, u32, u32)>,)>()
+    else {
+      return Ok(Vec::new());
+    };
+
+    let mut who = Vec::new();
+    for (what, inhibitor, _why, mode, _uid, _pid) in inhibitors {
+      if mode == "block"
+        && what.split(':').any(|w| w == action.inhibit_what())
+        && !who.contains(&inhibitor)
+      {
+        who.push(inhibitor);
+      }
+    }
+
+    Ok(who)
+  }
+
+  pub async fn perform(&self, action: PowerAction) -> Result<()> {
+    info!("requesting {}", action.method());
+    self
+      .call(action.method(), Some(&(false,).to_variant()), "()")
+      .await?;
+
+    Ok(())
+  }
+}
+
+fn inhibited_message(who: &[String], action: PowerAction) -> String {
+  match who {
+    [one] => format!("{one} is preventing {}", action.noun()),
+    [rest @ .., last] => format!(
+      "{} and {last} are preventing {}",
+      rest.join(", "),
+      action.noun()
+    ),
+    [] => String::new(),
+  }
+}
+
+/// Wires a control button up to the given logind action. The button stays
+/// hidden until logind reports the action as available, and messages are
+/// shown in `msg`. Destructive actions stay armed for `confirm_timeout`
+/// after the first click.
+pub fn connect_button(
+  button: &gtk4::Button,
+  action: PowerAction,
+  msg: &gtk4::Label,
+  confirm_timeout: Duration,
+) {
+  button.set_visible(false);
+
+  {
+    let button = button.downgrade();
+    glib::spawn_future_local(async move {
+      let available = match Logind::system().await {
+        Ok(logind) => logind.can(action).await,
+        Err(err) => {
+          warn!("failed to connect to system bus: {err}");
+          false
+        }
+      };
+
+      if let Some(button) = button.upgrade() {
+        button.set_visible(available);
+      }
+    });
+  }
+
+  let pending = Rc::new(RefCell::new(None::<glib::SourceId>));
+  let msg = msg.downgrade();
+  button.connect_clicked(move |button| {
+    // Armed right away, so a second click made while logind is still being
+    // asked counts as the confirmation
+    let confirmed = !action.is_destructive() || disarm(&pending, button);
+    if !confirmed {
+      arm(&pending, button, &msg, action, confirm_timeout);
+    }
+
+    let button = button.downgrade();
+    let msg = msg.clone();
+    let pending = pending.clone();
+    glib::spawn_future_local(async move {
+      let set_message = |text: &str| {
+        if let Some(msg) = msg.upgrade() {
+          msg.set_label(text);
+        }
+      };
+
+      let logind = match Logind::system().await {
+        Ok(logind) => logind,
+        Err(err) => {
+          error!("failed to connect to system bus: {err}");
+          set_message(&format!("Unable to {}", action.verb()));
+          return;
+        }
+      };
+
+      match logind.blocking_inhibitors(action).await {
+        Ok(who) if !who.is_empty() => {
+          if let Some(button) = button.upgrade() {
+            disarm(&pending, &button);
+          }
+          set_message(&inhibited_message(&who, action));
+          return;
+        }
+        Ok(_) => {}
+        Err(err) => warn!("failed to list inhibitors: {err}"),
+      }
+
+      if !confirmed {
+        return;
+      }
+
+      set_message("");
+      if let Err(err) = logind.perform(action).await {
+        error!("{} failed: {err}", action.method());
+        set_message(&format!("Unable to {}", action.verb()));
+      }
+    });
+  });
+}
+
+/// Ends the confirmation of a destructive action. Returns whether it was
+/// armed.
+fn disarm(pending: &RefCell<Option<glib::SourceId>>, button: &gtk4::Button) -> bool {
+  button.remove_css_class("confirm");
+  match pending.borrow_mut().take() {
+    Some(source) => {
+      source.remove();
+      true
+    }
+    None => false,
+  }
+}
+
+/// Waits `confirm_timeout` for a second click on a destructive action
+fn arm(
+  pending: &Rc<RefCell<Option<glib::SourceId>>>,
+  button: &gtk4::Button,
+  msg: &glib::WeakRef<gtk4::Label>,
+  action: PowerAction,
+  confirm_timeout: Duration,
+) {
+  button.add_css_class("confirm");
+  if let Some(msg) = msg.upgrade() {
+    msg.set_label(&format!("Click again to {}", action.verb()));
+  }
+
+  let source = {
+    let button = button.downgrade();
+    let msg = msg.clone();
+    let pending = pending.clone();
+    glib::timeout_add_local_once(confirm_timeout, move || {
+      pending.borrow_mut().take();
+      if let Some(button) = button.upgrade() {
+        button.remove_css_class("confirm");
+      }
+
+      if let Some(msg) = msg.upgrade() {
+        msg.set_label("");
+      }
+    })
+  };
+
+  pending.replace(Some(source));
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testbus::{self, TestBus};
+
+  const LOGIND_XML: &str = r#"
+<node>
+  <interface name="org.freedesktop.login1.Manager">
+    <method name="CanSuspend"><arg type="s" direction="out"/></method>
+    <method name="CanReboot"><arg type="s" direction="out"/></method>
+    <method name="CanPowerOff"><arg type="s" direction="out"/></method>
+    <method name="ListInhibitors"><arg type="a(ssssuu)" direction="out"/></method>
+    <method name="Suspend"><arg type="b" direction="in"/></method>
+    <method name="Reboot"><arg type="b" direction="in"/></method>
+    <method name="PowerOff"><arg type="b" direction="in"/></method>
+  </interface>
+</node>
+"#;
+
+  type Inhibitor = (
+    &'static str,
+    &'static str,
+    &'static str,
+    &'static str,
+    u32,
+    u32,
+  );
+
+  struct FakeLogind {
+    /// Owns the fake service
+    _service: gio::DBusConnection,
+    client: Logind,
+    /// The actions it was asked to perform
+    performed: Rc<RefCell<Vec<String>>>,
+  }
+
+  async fn fake_logind(bus: &TestBus, inhibitors: Vec<Inhibitor>) -> FakeLogind {
+    let service = bus.connect().await;
+    testbus::own_name(&service, LOGIND_NAME).await;
+
+    let performed = Rc::new(RefCell::new(Vec::new()));
+    {
+      let performed = performed.clone();
+      testbus::export(
+        &service,
+        LOGIND_PATH,
+        LOGIND_XML,
+        move |method, params| match method {
+          "CanSuspend" => Some(("yes",).to_variant()),
+          "CanReboot" => Some(("challenge",).to_variant()),
+          "CanPowerOff" => None,
+          "ListInhibitors" => Some((inhibitors.clone(),).to_variant()),
+          _ => {
+            assert_eq!(params.get::<(bool,)>(), Some((false,)));
+            performed.borrow_mut().push(method.to_string());
+            Some(().to_variant())
+          }
+        },
+      );
+    }
+
+    FakeLogind {
+      _service: service,
+      client: Logind::new(bus.connect().await),
+      performed,
+    }
+  }
+
+  #[test]
+  fn can_only_accepts_yes() {
+    let bus = TestBus::up();
+    testbus::run(|| async {
+      let logind = fake_logind(&bus, Vec::new()).await.client;
+      assert!(logind.can(PowerAction::Suspend).await);
+      assert!(!logind.can(PowerAction::Reboot).await);
+      assert!(!logind.can(PowerAction::PowerOff).await);
+    });
+  }
+
+  #[test]
+  fn blocking_inhibitors_match_the_action() {
+    let bus = TestBus::up();
+    testbus::run(|| async {
+      let inhibitors = vec![
+        ("sleep:shutdown", "Updater", "installing", "block", 0, 1),
+        ("sleep:shutdown", "Updater", "downloading", "block", 0, 1),
+        ("sleep", "Player", "playing", "delay", 1000, 2),
+        ("shutdown", "Editor", "unsaved", "block", 1000, 3),
+        ("idle", "Video", "watching", "block", 1000, 4),
+      ];
+      let fake = fake_logind(&bus, inhibitors).await;
+      let logind = &fake.client;
+
+      let suspend = logind.blocking_inhibitors(PowerAction::Suspend).await;
+      assert_eq!(suspend.unwrap(), ["Updater"]);
+      let reboot = logind.blocking_inhibitors(PowerAction::Reboot).await;
+      assert_eq!(reboot.unwrap(), ["Updater", "Editor"]);
+    });
+  }
+
+  #[test]
+  fn perform_calls_the_action_without_interaction() {
+    let bus = TestBus::up();
+    testbus::run(|| async {
+      let fake = fake_logind(&bus, Vec::new()).await;
+      fake.client.perform(PowerAction::Suspend).await.unwrap();
+      fake.client.perform(PowerAction::PowerOff).await.unwrap();
+      assert_eq!(*fake.performed.borrow(), ["Suspend", "PowerOff"]);
+    });
+  }
+
+  #[test]
+  fn inhibited_message_lists_everyone() {
+    let who = [
+      "Updater".to_string(),
+      "Editor".to_string(),
+      "Player".to_string(),
+    ];
+    assert_eq!(
+      inhibited_message(&who[..1], PowerAction::Reboot),
+      "Updater is preventing restart"
+    );
+    assert_eq!(
+      inhibited_message(&who, PowerAction::PowerOff),
+      "Updater, Editor and Player are preventing shutdown"
+    );
+  }
+}
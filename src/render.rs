@@ -0,0 +1,346 @@
+use std::{
+  cell::Cell,
+  fmt,
+  rc::Rc,
+  sync::Arc,
+  time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Context, Result};
+use futures_signals::signal::Mutable;
+use gtk4::{gdk::Display, gio, glib, graphene, gsk, prelude::*, Application};
+use toml::Spanned;
+use tracing::warn;
+
+use crate::{
+  account,
+  cli::RenderArgs,
+  clock,
+  config::Config,
+  create_window, faillock,
+  locker::{Activity, Failure},
+  summary, theme, wallpaper,
+};
+
+/// Separate from the locker's, so rendering never activates a resident
+/// instance
+const APPLICATION_ID: &str = "lol.happens.dash3.render";
+const AUTH_ERROR: &str = "pam_authenticate: AUTH_ERR";
+/// How long the rendered lock lasted, for the locked since line and the
+/// summary
+const LOCKED_FOR: Duration = Duration::from_secs(2 * 3600 + 12 * 60);
+/// When the failure in the summary happened, before it was rendered
+const FAILED_AGO: Duration = Duration::from_secs(40 * 60);
+const LOCKOUT: Duration = Duration::from_secs(5 * 60);
+
+/// The authentication state to render
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum RenderState {
+  /// Waiting for a password
+  Prompt,
+  /// Checking a password
+  Checking,
+  /// After a failed attempt, with the fail delay if one is configured
+  Failed,
+  /// Locked out by pam_faillock
+  LockedOut,
+  /// The summary shown on unlock
+  Summary,
+}
+
+/// The logical size of the rendered screen
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Size {
+  width: i32,
+  height: i32,
+}
+
+impl fmt::Display for Size {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}x{}", self.width, self.height)
+  }
+}
+
+/// Parses a WIDTHxHEIGHT size for the command line
+pub fn parse_size(s: &str) -> Result<Size, String> {
+  let invalid = || format!("invalid size {s:?}, expected WIDTHxHEIGHT");
+  let (width, height) = s.split_once('x').ok_or_else(invalid)?;
+  let (Ok(width), Ok(height)) = (width.parse::<i32>(), height.parse::<i32>()) else {
+    return Err(invalid());
+  };
+
+  if width <= 0 || height <= 0 {
+    return Err(invalid());
+  }
+
+  Ok(Size { width, height })
+}
+
+fn parse_time(time: &str) -> Result<glib::DateTime> {
+  glib::DateTime::from_iso8601(time, Some(&glib::TimeZone::local()))
+    .map_err(|_| anyhow!("invalid time {time:?}, expected YYYY-MM-DDTHH:MM:SS"))
+}
+
+/// Failed attempts and PAM messages that look like a real lock
+fn activity(now: SystemTime) -> Activity {
+  Activity {
+    locked_at: Some(now - LOCKED_FOR),
+    unlocked_at: Some(now),
+    failures: vec![Failure {
+      at: now - FAILED_AGO,
+      error: AUTH_ERROR.to_string(),
+    }],
+    pam_errors: Vec::new(),
+  }
+}
+
+/// Builds the window once the parts the lock screen loads in the background,
+/// the account, avatar and wallpaper, are ready, so they show up right away
+async fn build(
+  app: &Application,
+  config: Arc<Config>,
+  args: &RenderArgs,
+) -> Result<gtk4::ApplicationWindow> {
+  let time = args.time.as_deref().map(parse_time).transpose()?;
+  let now = time.as_ref().map_or_else(SystemTime::now, |time| {
+    UNIX_EPOCH + Duration::from_secs(time.to_unix().max(0) as u64)
+  });
+  clock::simulate(time, LOCKED_FOR);
+
+  account::resolve(config.clone()).await;
+  if config.widgets.avatar {
+    // Without an output, the avatar is drawn for a scale of 1
+    account::preload_avatar(*config.appearance.avatar_size.get_ref(), 1).await;
+  }
+
+  let scale = args.scale as i32;
+  let (width, height) = (args.size.width * scale, args.size.height * scale);
+  let still = {
+    let config = config.clone();
+    gio::spawn_blocking(move || wallpaper::render_still(&config, width, height))
+      .await
+      .map_err(|_| anyhow!("the wallpaper renderer panicked"))?
+  };
+  let background = match still {
+    Some(Ok(pixels)) => {
+      let picture = wallpaper::picture();
+      picture.set_paintable(Some(&pixels.into_texture()));
+      Some(picture)
+    }
+    Some(Err(err)) => {
+      warn!("failed to render the wallpaper: {err}");
+      None
+    }
+    None => None,
+  };
+
+  // Nothing is typed into the window, so passwords go nowhere
+  let (pw_tx, _) = flume::unbounded();
+  let is_loading = Mutable::new(args.state == RenderState::Checking);
+  let window = create_window(app, &config, None, background, is_loading, pw_tx);
+
+  match args.state {
+    RenderState::Prompt | RenderState::Checking => {}
+    RenderState::Failed => {
+      let fail_delay = config.timeouts.fail_delay.as_ref();
+      faillock::simulate(
+        1,
+        false,
+        fail_delay.map(|delay| Duration::from_secs(*delay.get_ref())),
+      );
+    }
+    RenderState::LockedOut => faillock::simulate(0, true, Some(LOCKOUT)),
+    RenderState::Summary => {
+      summary::show_overlay(&config, &activity(now));
+    }
+  }
+
+  Ok(window)
+}
+
+/// Draws the contents of the window into a texture on the CPU and saves it.
+/// Presenting the window would show it on the compositor, so its contents
+/// are mapped and laid out on their own instead. Realizing them creates the
+/// window's surface, which stays hidden without a role.
+fn render(window: &gtk4::ApplicationWindow, args: &RenderArgs) -> Result<()> {
+  let content = window
+    .child()
+    .ok_or_else(|| anyhow!("the window has no contents"))?;
+  content.map();
+  content.allocate(args.size.width, args.size.height, -1, None);
+
+  let (width, height) = (args.size.width as f32, args.size.height as f32);
+  let scale = args.scale as f32;
+  let snapshot = gtk4::Snapshot::new();
+  snapshot.scale(scale, scale);
+
+  // The window draws its own background only once it is mapped
+  #[allow(deprecated)]
+  snapshot.render_background(
+    &window.style_context(),
+    0.0,
+    0.0,
+    width as f64,
+    height as f64,
+  );
+  window.snapshot_child(&content, &snapshot);
+  let node = snapshot
+    .to_node()
+    .ok_or_else(|| anyhow!("the window drew nothing"))?;
+
+  let renderer = gsk::CairoRenderer::new();
+  renderer.realize_for_display(&window.display())?;
+  let viewport = graphene::Rect::new(0.0, 0.0, width * scale, height * scale);
+  let texture = renderer.render_texture(&node, Some(&viewport));
+  renderer.unrealize();
+
+  texture
+    .save_to_png(&args.output)
+    .with_context(|| format!("failed to write {}", args.output.display()))
+}
+
+async fn draw(app: &Application, config: Arc<Config>, args: &RenderArgs) -> Result<()> {
+  let display = Display::default().ok_or_else(|| anyhow!("GTK has no display"))?;
+  theme::load(&display, &config, false);
+
+  let window = build(app, config, args).await?;
+  let rendered = render(&window, args);
+
+  // The app exits with its last window
+  window.destroy();
+  rendered
+}
+
+/// Renders the lock screen in the requested state to a PNG, drawing on the
+/// CPU. GTK still needs a display connection to build the widgets, a
+/// headless compositor will do, but no window is shown on it.
+pub fn run(args: &RenderArgs, config: &Config) -> glib::ExitCode {
+  // Has to be set before GTK initializes, so nothing is drawn on the GPU
+  std::env::set_var("GSK_RENDERER", "cairo");
+  if let Err(err) = gtk4::init() {
+    eprintln!("dash3: unable to render without a display, try a headless compositor: {err}");
+    return glib::ExitCode::FAILURE;
+  }
+
+  let mut config = config.clone();
+  if let Some(scheme) = &args.theme {
+    config.theme.scheme = Some(Spanned::new(0..0, scheme.clone()));
+  }
+  let config = Arc::new(config);
+
+  let app = Application::builder()
+    .application_id(APPLICATION_ID)
+    .flags(gio::ApplicationFlags::NON_UNIQUE)
+    .build();
+
+  let rendered = Rc::new(Cell::new(false));
+  {
+    let rendered = rendered.clone();
+    let args = args.clone();
+    app.connect_activate(move |app| {
+      // Keeps the app running while the window is built and drawn
+      let hold = app.hold();
+      let app = app.clone();
+      let config = config.clone();
+      let rendered = rendered.clone();
+      let args = args.clone();
+      glib::spawn_future_local(async move {
+        let _hold = hold;
+        match draw(&app, config, &args).await {
+          Ok(()) => rendered.set(true),
+          Err(err) => eprintln!("dash3: {err}"),
+        }
+      });
+    });
+  }
+
+  app.run_with_args::<&str>(&[]);
+
+  if rendered.get() {
+    glib::ExitCode::SUCCESS
+  } else {
+    glib::ExitCode::FAILURE
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::path::{Path, PathBuf};
+
+  use gtk4::gdk;
+
+  use super::*;
+
+  /// Set to rewrite the reference images with the current renders
+  const BLESS_VAR: &str = "DASH3_BLESS";
+
+  fn pixels(path: &Path) -> (i32, i32, Vec<u8>) {
+    let texture = gdk::Texture::from_filename(path).unwrap();
+    let stride = texture.width() as usize * 4;
+    let mut data = vec![0; stride * texture.height() as usize];
+    texture.download(&mut data, stride);
+    (texture.width(), texture.height(), data)
+  }
+
+  /// Renders the failed state with the widgets that don't depend on the
+  /// machine and compares it to `tests/golden/failed.png`. Fonts differ
+  /// between machines, so the reference images are rendered with
+  /// `DASH3_BLESS=1` where the tests run, and committed. Run with
+  /// `cargo test -- --ignored` in a session or under a headless compositor.
+  #[test]
+  #[ignore = "needs a display"]
+  fn failed_state_matches_the_reference() {
+    assert!(
+      std::env::var_os("WAYLAND_DISPLAY").is_some() || std::env::var_os("DISPLAY").is_some(),
+      "rendering needs a display, run under a headless compositor"
+    );
+
+    let mut config = Config::default();
+    let widgets = &mut config.widgets;
+    widgets.avatar = false;
+    widgets.name = false;
+    widgets.battery = false;
+    widgets.media = false;
+    widgets.network = false;
+    widgets.keyboard = false;
+
+    let output = std::env::temp_dir().join(format!("dash3-render-{}.png", std::process::id()));
+    let args = RenderArgs {
+      theme: None,
+      size: Size {
+        width: 800,
+        height: 600,
+      },
+      scale: 1,
+      state: RenderState::Failed,
+      time: Some("2024-03-01T14:03:00".to_string()),
+      output: output.clone(),
+    };
+    assert_eq!(run(&args, &config), glib::ExitCode::SUCCESS);
+
+    let golden = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/golden/failed.png");
+    if std::env::var_os(BLESS_VAR).is_some() {
+      std::fs::create_dir_all(golden.parent().unwrap()).unwrap();
+      std::fs::rename(&output, &golden)
+        .or_else(|_| std::fs::copy(&output, &golden).map(|_| ()))
+        .unwrap();
+      eprintln!("wrote {}", golden.display());
+      return;
+    }
+
+    assert!(
+      golden.exists(),
+      "{} is missing, render it with {BLESS_VAR}=1",
+      golden.display()
+    );
+
+    let matches = pixels(&output) == pixels(&golden);
+    assert!(
+      matches,
+      "{} differs from {}, rerun with {BLESS_VAR}=1 if the change is intended",
+      output.display(),
+      golden.display()
+    );
+    let _ = std::fs::remove_file(&output);
+  }
+}
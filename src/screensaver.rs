@@ -0,0 +1,309 @@
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+use gtk4::{gio, glib, prelude::*};
+use tracing::{error, info, warn};
+
+use crate::locker::{LockEvent, LockReason, LockState, Locker};
+
+const NAME: &str = "org.freedesktop.ScreenSaver";
+const INTERFACE: &str = "org.freedesktop.ScreenSaver";
+
+/// Applications use either of these paths, so the interface is exported on both
+const PATHS: [&str; 2] = ["/org/freedesktop/ScreenSaver", "/ScreenSaver"];
+
+const INTERFACE_XML: &str = r#"
+<node>
+  <interface name="org.freedesktop.ScreenSaver">
+    <method name="Lock"/>
+    <method name="SimulateUserActivity"/>
+    <method name="GetActive">
+      <arg type="b" direction="out"/>
+    </method>
+    <method name="GetActiveTime">
+      <arg type="u" direction="out"/>
+    </method>
+    <method name="SetActive">
+      <arg type="b" name="e" direction="in"/>
+      <arg type="b" direction="out"/>
+    </method>
+    <method name="Inhibit">
+      <arg type="s" name="application_name" direction="in"/>
+      <arg type="s" name="reason_for_inhibit" direction="in"/>
+      <arg type="u" name="cookie" direction="out"/>
+    </method>
+    <method name="UnInhibit">
+      <arg type="u" name="cookie" direction="in"/>
+    </method>
+    <signal name="ActiveChanged">
+      <arg type="b"/>
+    </signal>
+  </interface>
+</node>
+"#;
+
+struct Inhibitor {
+  sender: String,
+  application: String,
+  reason: String,
+}
+
+#[derive(Default)]
+struct InhibitorsInner {
+  next_cookie: u32,
+  inhibitors: HashMap<u32, Inhibitor>,
+  watchers: HashMap<String, gio::WatcherId>,
+  released: Vec<Rc<dyn Fn()>>,
+}
+
+/// Idle inhibitors registered by applications over D-Bus. Inhibitors are
+/// dropped automatically when the application leaves the bus.
+#[derive(Clone, Default)]
+pub struct Inhibitors {
+  inner: Rc<RefCell<InhibitorsInner>>,
+}
+
+impl Inhibitors {
+  pub fn is_inhibited(&self) -> bool {
+    !self.inner.borrow().inhibitors.is_empty()
+  }
+
+  /// Calls `f` whenever the last inhibitor is released
+  pub fn connect_released(&self, f: impl Fn() + 'static) {
+    self.inner.borrow_mut().released.push(Rc::new(f));
+  }
+
+  fn notify_if_released(&self) {
+    if self.is_inhibited() {
+      return;
+    }
+
+    // Cloned, so callbacks may use the inhibitors
+    let released = self.inner.borrow().released.clone();
+    released.iter().for_each(|f| f());
+  }
+
+  fn add(&self, conn: &gio::DBusConnection, sender: &str, application: &str, reason: &str) -> u32 {
+    let mut inner = self.inner.borrow_mut();
+    inner.next_cookie = inner.next_cookie.wrapping_add(1).max(1);
+    let cookie = inner.next_cookie;
+
+    info!("{application} ({sender}) inhibits idle: {reason}");
+    inner.inhibitors.insert(
+      cookie,
+      Inhibitor {
+        sender: sender.to_string(),
+        application: application.to_string(),
+        reason: reason.to_string(),
+      },
+    );
+
+    if !inner.watchers.contains_key(sender) {
+      let inhibitors = self.clone();
+      let watcher = gio::bus_watch_name_on_connection(
+        conn,
+        sender,
+        gio::BusNameWatcherFlags::NONE,
+        |_, _, _| {},
+        move |_, name| inhibitors.remove_sender(name),
+      );
+
+      inner.watchers.insert(sender.to_string(), watcher);
+    }
+
+    cookie
+  }
+
+  fn remove(&self, sender: &str, cookie: u32) {
+    if self.remove_cookie(sender, cookie) {
+      self.notify_if_released();
+    }
+  }
+
+  fn remove_cookie(&self, sender: &str, cookie: u32) -> bool {
+    let mut inner = self.inner.borrow_mut();
+    match inner.inhibitors.get(&cookie) {
+      Some(inhibitor) if inhibitor.sender == sender => {
+        info!("{} released idle inhibitor", inhibitor.application);
+        inner.inhibitors.remove(&cookie);
+      }
+      _ => {
+        warn!("{sender} tried to release unknown inhibitor {cookie}");
+        return false;
+      }
+    }
+
+    if !inner.inhibitors.values().any(|i| i.sender == sender) {
+      if let Some(watcher) = inner.watchers.remove(sender) {
+        gio::bus_unwatch_name(watcher);
+      }
+    }
+
+    true
+  }
+
+  fn remove_sender(&self, sender: &str) {
+    let mut inner = self.inner.borrow_mut();
+    let inhibited = !inner.inhibitors.is_empty();
+    inner.inhibitors.retain(|_, inhibitor| {
+      if inhibitor.sender == sender {
+        info!(
+          "{} left the bus, dropping inhibitor: {}",
+          inhibitor.application, inhibitor.reason
+        );
+      }
+
+      inhibitor.sender != sender
+    });
+
+    if let Some(watcher) = inner.watchers.remove(sender) {
+      gio::bus_unwatch_name(watcher);
+    }
+
+    drop(inner);
+    if inhibited {
+      self.notify_if_released();
+    }
+  }
+}
+
+fn handle_method_call(
+  conn: &gio::DBusConnection,
+  locker: &Locker,
+  inhibitors: &Inhibitors,
+  sender: &str,
+  method: &str,
+  params: glib::Variant,
+  invocation: gio::DBusMethodInvocation,
+) {
+  match method {
+    "Lock" => {
+      locker.lock(LockReason::ScreenSaver);
+      invocation.return_value(None);
+    }
+    "SimulateUserActivity" => invocation.return_value(None),
+    "GetActive" => {
+      let active = locker.state() == LockState::Locked;
+      invocation.return_value(Some(&(active,).to_variant()));
+    }
+    "GetActiveTime" => {
+      let secs = locker
+        .locked_at()
+        .map(|at| at.elapsed().as_secs() as u32)
+        .unwrap_or(0);
+      invocation.return_value(Some(&(secs,).to_variant()));
+    }
+    "SetActive" => {
+      let Some((active,)) = params.get::<(bool,)>() else {
+        invocation.return_dbus_error("org.freedesktop.DBus.Error.InvalidArgs", "expected (b)");
+        return;
+      };
+
+      // Deactivating would mean unlocking without authentication
+      if active {
+        locker.lock(LockReason::ScreenSaver);
+      }
+
+      invocation.return_value(Some(&(active,).to_variant()));
+    }
+    "Inhibit" => {
+      let Some((application, reason)) = params.get::<(String, String)>() else {
+        invocation.return_dbus_error("org.freedesktop.DBus.Error.InvalidArgs", "expected (ss)");
+        return;
+      };
+
+      let cookie = inhibitors.add(conn, sender, &application, &reason);
+      invocation.return_value(Some(&(cookie,).to_variant()));
+    }
+    "UnInhibit" => {
+      let Some((cookie,)) = params.get::<(u32,)>() else {
+        invocation.return_dbus_error("org.freedesktop.DBus.Error.InvalidArgs", "expected (u)");
+        return;
+      };
+
+      inhibitors.remove(sender, cookie);
+      invocation.return_value(None);
+    }
+    _ => invocation.return_dbus_error(
+      "org.freedesktop.DBus.Error.UnknownMethod",
+      &format!("unknown method {method}"),
+    ),
+  }
+}
+
+fn register(conn: &gio::DBusConnection, locker: &Locker, inhibitors: &Inhibitors) {
+  let node = match gio::DBusNodeInfo::for_xml(INTERFACE_XML) {
+    Ok(node) => node,
+    Err(err) => {
+      error!("invalid screensaver interface: {err}");
+      return;
+    }
+  };
+
+  let interface = node.lookup_interface(INTERFACE).unwrap();
+  for path in PATHS {
+    let locker = locker.clone();
+    let inhibitors = inhibitors.clone();
+    let res = conn
+      .register_object(path, &interface)
+      .method_call(
+        move |conn, sender, _path, _interface, method, params, invocation| {
+          handle_method_call(
+            &conn,
+            &locker,
+            &inhibitors,
+            sender,
+            method,
+            params,
+            invocation,
+          )
+        },
+      )
+      .build();
+
+    if let Err(err) = res {
+      error!("failed to register {path}: {err}");
+    }
+  }
+
+  let events = locker.subscribe();
+  let mut was_active = locker.state() == LockState::Locked;
+  let conn = conn.clone();
+  glib::spawn_future_local(async move {
+    while let Ok(event) = events.recv_async().await {
+      let active = match event {
+        LockEvent::Locked => true,
+        LockEvent::Unlocked | LockEvent::Finished => false,
+        _ => continue,
+      };
+
+      if active == was_active {
+        continue;
+      }
+
+      was_active = active;
+      for path in PATHS {
+        if let Err(err) = conn.emit_signal(
+          None,
+          path,
+          INTERFACE,
+          "ActiveChanged",
+          Some(&(active,).to_variant()),
+        ) {
+          warn!("failed to emit ActiveChanged: {err}");
+        }
+      }
+    }
+  });
+}
+
+/// Implements org.freedesktop.ScreenSaver on the session bus
+pub fn serve(locker: Locker, inhibitors: Inhibitors) -> gio::OwnerId {
+  gio::bus_own_name(
+    gio::BusType::Session,
+    NAME,
+    gio::BusNameOwnerFlags::NONE,
+    move |conn, _| register(&conn, &locker, &inhibitors),
+    |_, name| info!("acquired {name}"),
+    |_, name| warn!("unable to own {name}, is another screensaver running?"),
+  )
+}
@@ -0,0 +1,499 @@
+use std::{
+  collections::HashMap,
+  os::fd::AsRawFd,
+  time::{Duration, Instant},
+};
+
+use anyhow::{bail, Result};
+use futures_signals::signal::{Mutable, SignalExt};
+use gtk4::{gio, glib, prelude::*};
+use smithay_client_toolkit::shm::{raw::RawPool, Shm, ShmHandler};
+use tracing::warn;
+use wayland_client::{
+  backend::WaylandError,
+  globals::{registry_queue_init, GlobalList, GlobalListContents},
+  protocol::{wl_buffer::WlBuffer, wl_output::WlOutput, wl_registry::WlRegistry, wl_shm::Format},
+  Connection, Dispatch, EventQueue, Proxy, QueueHandle, WEnum,
+};
+use wayland_protocols::ext::{
+  image_capture_source::v1::client::{
+    ext_image_capture_source_v1::ExtImageCaptureSourceV1,
+    ext_output_image_capture_source_manager_v1::ExtOutputImageCaptureSourceManagerV1,
+  },
+  image_copy_capture::v1::client::{
+    ext_image_copy_capture_frame_v1::{self, ExtImageCopyCaptureFrameV1},
+    ext_image_copy_capture_manager_v1::{self, ExtImageCopyCaptureManagerV1},
+    ext_image_copy_capture_session_v1::{self, ExtImageCopyCaptureSessionV1},
+  },
+};
+use wayland_protocols_wlr::screencopy::v1::client::{
+  zwlr_screencopy_frame_v1::{self, ZwlrScreencopyFrameV1},
+  zwlr_screencopy_manager_v1::ZwlrScreencopyManagerV1,
+};
+
+use crate::{config::ScreenshotConfig, effects::Pixels, wallpaper};
+
+/// Capturing is skipped for outputs that take longer than this, so a stuck
+/// compositor doesn't hold up the lock
+const CAPTURE_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CaptureState {
+  /// Waiting for the compositor to describe the buffer
+  Pending,
+  Copying,
+  Ready,
+  Failed,
+}
+
+/// The protocol objects of a capture
+enum Frame {
+  Wlr(ZwlrScreencopyFrameV1),
+  Ext {
+    source: ExtImageCaptureSourceV1,
+    session: ExtImageCopyCaptureSessionV1,
+    /// Created once the buffer constraints are known
+    frame: Option<ExtImageCopyCaptureFrameV1>,
+    /// From the buffer_size event
+    size: Option<(i32, i32)>,
+  },
+}
+
+impl Frame {
+  fn destroy(self) {
+    match self {
+      Frame::Wlr(frame) => frame.destroy(),
+      Frame::Ext {
+        source,
+        session,
+        frame,
+        ..
+      } => {
+        if let Some(frame) = frame {
+          frame.destroy();
+        }
+        session.destroy();
+        source.destroy();
+      }
+    }
+  }
+}
+
+struct Capture {
+  output: WlOutput,
+  frame: Frame,
+  state: CaptureState,
+  /// Format, width, height and stride of the shm buffer to copy into
+  buffer_info: Option<(Format, i32, i32, i32)>,
+  y_invert: bool,
+  pool: Option<RawPool>,
+  buffer: Option<WlBuffer>,
+}
+
+/// ext-image-copy-capture, preferred over wlr-screencopy where both exist
+struct ExtManagers {
+  sources: ExtOutputImageCaptureSourceManagerV1,
+  copy: ExtImageCopyCaptureManagerV1,
+}
+
+enum Managers {
+  Ext(ExtManagers),
+  Wlr(ZwlrScreencopyManagerV1),
+}
+
+impl Managers {
+  fn bind(globals: &GlobalList, qh: &QueueHandle<ScreenshotState>) -> Result<Self> {
+    let sources = globals.bind::<ExtOutputImageCaptureSourceManagerV1, _, _>(qh, 1..=1, ());
+    let copy = globals.bind::<ExtImageCopyCaptureManagerV1, _, _>(qh, 1..=1, ());
+    match (sources, copy) {
+      (Ok(sources), Ok(copy)) => return Ok(Managers::Ext(ExtManagers { sources, copy })),
+      (Ok(sources), Err(_)) => sources.destroy(),
+      (Err(_), Ok(copy)) => copy.destroy(),
+      (Err(_), Err(_)) => {}
+    }
+
+    match globals.bind::<ZwlrScreencopyManagerV1, _, _>(qh, 1..=3, ()) {
+      Ok(manager) => Ok(Managers::Wlr(manager)),
+      Err(_) => bail!(
+        "compositor supports neither ext_image_copy_capture_manager_v1 nor \
+         zwlr_screencopy_manager_v1"
+      ),
+    }
+  }
+
+  fn capture(&self, output: &WlOutput, index: usize, qh: &QueueHandle<ScreenshotState>) -> Frame {
+    match self {
+      Managers::Ext(managers) => {
+        let source = managers.sources.create_source(output, qh, ());
+        let session = managers.copy.create_session(
+          &source,
+          ext_image_copy_capture_manager_v1::Options::empty(),
+          qh,
+          index,
+        );
+        Frame::Ext {
+          source,
+          session,
+          frame: None,
+          size: None,
+        }
+      }
+      Managers::Wlr(manager) => Frame::Wlr(manager.capture_output(0, output, qh, index)),
+    }
+  }
+
+  fn destroy(self) {
+    match self {
+      Managers::Ext(managers) => {
+        managers.copy.destroy();
+        managers.sources.destroy();
+      }
+      Managers::Wlr(manager) => manager.destroy(),
+    }
+  }
+}
+
+struct ScreenshotState {
+  shm: Shm,
+  captures: Vec<Capture>,
+}
+
+impl ScreenshotState {
+  fn copy(&mut self, index: usize, qh: &QueueHandle<Self>) {
+    let capture = &mut self.captures[index];
+    if capture.state != CaptureState::Pending {
+      return;
+    }
+
+    let Some((format, width, height, stride)) = capture.buffer_info else {
+      warn!("compositor offered no shm buffer for screencopy");
+      capture.state = CaptureState::Failed;
+      return;
+    };
+
+    let mut pool = match RawPool::new((stride * height) as usize, &self.shm) {
+      Ok(pool) => pool,
+      Err(err) => {
+        warn!("failed to create screencopy buffer: {err}");
+        capture.state = CaptureState::Failed;
+        return;
+      }
+    };
+
+    let buffer = pool.create_buffer(0, width, height, stride, format, (), qh);
+    match &mut capture.frame {
+      Frame::Wlr(frame) => frame.copy(&buffer),
+      Frame::Ext { session, frame, .. } => {
+        let new_frame = session.create_frame(qh, index);
+        new_frame.attach_buffer(&buffer);
+        new_frame.damage_buffer(0, 0, width, height);
+        new_frame.capture();
+        *frame = Some(new_frame);
+      }
+    }
+
+    capture.state = CaptureState::Copying;
+    capture.pool = Some(pool);
+    capture.buffer = Some(buffer);
+  }
+}
+
+/// Converts a captured frame to RGBA. Both supported formats are stored as
+/// little endian BGRA in memory.
+fn to_pixels(capture: &mut Capture) -> Option<Pixels> {
+  let (format, width, height, stride) = capture.buffer_info?;
+  let src = capture.pool.as_mut()?.mmap();
+
+  let (width, height, stride) = (width as usize, height as usize, stride as usize);
+  let mut data = vec![0; width * height * 4];
+  for (y, row) in data.chunks_exact_mut(width * 4).enumerate() {
+    let src_y = if capture.y_invert { height - 1 - y } else { y };
+    let src_row = &src[src_y * stride..src_y * stride + width * 4];
+    for (dst, src) in row.chunks_exact_mut(4).zip(src_row.chunks_exact(4)) {
+      let alpha = if format == Format::Xrgb8888 {
+        255
+      } else {
+        src[3]
+      };
+      dst.copy_from_slice(&[src[2], src[1], src[0], alpha]);
+    }
+  }
+
+  Some(Pixels {
+    width: width as i32,
+    height: height as i32,
+    stride: width * 4,
+    data,
+  })
+}
+
+/// Blocks on the connection until events arrive, then dispatches them.
+/// Returns false once `deadline` has passed.
+fn wait_for_events(
+  event_queue: &mut EventQueue<ScreenshotState>,
+  state: &mut ScreenshotState,
+  deadline: Instant,
+) -> Result<bool> {
+  event_queue.flush()?;
+
+  // Events already read by another queue are dispatched right away
+  if let Some(guard) = event_queue.prepare_read() {
+    let timeout = deadline.saturating_duration_since(Instant::now());
+    let mut fd = libc::pollfd {
+      fd: guard.connection_fd().as_raw_fd(),
+      events: libc::POLLIN,
+      revents: 0,
+    };
+
+    let rc = unsafe { libc::poll(&mut fd, 1, timeout.as_millis().min(i32::MAX as u128) as i32) };
+    if rc < 0 {
+      let err = std::io::Error::last_os_error();
+      if err.kind() != std::io::ErrorKind::Interrupted {
+        return Err(err.into());
+      }
+    } else if rc == 0 {
+      return Ok(false);
+    } else {
+      match guard.read() {
+        Ok(_) => {}
+        Err(WaylandError::Io(err)) if err.kind() == std::io::ErrorKind::WouldBlock => {}
+        Err(err) => return Err(err.into()),
+      }
+    }
+  }
+
+  event_queue.dispatch_pending(state)?;
+  Ok(Instant::now() < deadline)
+}
+
+/// Captures the current contents of each output, with ext-image-copy-capture
+/// or wlr-screencopy. Only the raw frames are copied, so the lock isn't held
+/// up by the effects, see `picture`. Outputs that failed to capture are left
+/// out.
+pub fn capture(conn: &Connection, outputs: &[WlOutput]) -> Result<HashMap<WlOutput, Pixels>> {
+  let (globals, mut event_queue) = registry_queue_init::<ScreenshotState>(conn)?;
+  let qh = event_queue.handle();
+
+  let managers = Managers::bind(&globals, &qh)?;
+  let mut state = ScreenshotState {
+    shm: Shm::bind(&globals, &qh)?,
+    captures: Vec::new(),
+  };
+
+  for (index, output) in outputs.iter().enumerate() {
+    state.captures.push(Capture {
+      output: output.clone(),
+      frame: managers.capture(output, index, &qh),
+      state: CaptureState::Pending,
+      buffer_info: None,
+      y_invert: false,
+      pool: None,
+      buffer: None,
+    });
+  }
+
+  let deadline = Instant::now() + CAPTURE_TIMEOUT;
+  while state
+    .captures
+    .iter()
+    .any(|c| matches!(c.state, CaptureState::Pending | CaptureState::Copying))
+  {
+    if !wait_for_events(&mut event_queue, &mut state, deadline)? {
+      break;
+    }
+  }
+
+  let mut screenshots = HashMap::new();
+  for mut capture in state.captures.drain(..) {
+    capture.frame.destroy();
+    if let Some(buffer) = capture.buffer.take() {
+      buffer.destroy();
+    }
+
+    if capture.state != CaptureState::Ready {
+      warn!("failed to capture output {:?}", capture.output.id());
+      continue;
+    }
+
+    if let Some(pixels) = to_pixels(&mut capture) {
+      screenshots.insert(capture.output, pixels);
+    }
+  }
+
+  managers.destroy();
+  event_queue.roundtrip(&mut state)?;
+  Ok(screenshots)
+}
+
+/// Applies the configured effects, in the order they should compose
+fn apply_effects(pixels: &mut Pixels, config: &ScreenshotConfig) {
+  pixels.pixelate(config.pixelate.as_ref().map_or(0, |p| *p.get_ref()));
+  pixels.blur(config.blur.as_ref().map_or(0, |b| *b.get_ref()));
+  pixels.vignette(config.vignette.as_ref().map_or(0.0, |v| *v.get_ref()));
+  pixels.darken(config.darken.as_ref().map_or(0.0, |d| *d.get_ref()));
+}
+
+/// A background for a raw capture. It stays empty until the compositor
+/// confirmed the lock, then the effects are applied on a worker thread and
+/// the result is swapped in. Must be called on the main thread.
+pub fn picture(
+  mut screenshot: Pixels,
+  config: &ScreenshotConfig,
+  locked: &Mutable<bool>,
+) -> gtk4::Picture {
+  let picture = wallpaper::picture();
+  let config = config.clone();
+  // Ends without a value if the lock is given up before it was confirmed
+  let locked = locked.signal().wait_for(true);
+
+  let weak = picture.downgrade();
+  glib::spawn_future_local(async move {
+    if locked.await.is_none() {
+      return;
+    }
+
+    let processed = gio::spawn_blocking(move || {
+      apply_effects(&mut screenshot, &config);
+      screenshot
+    })
+    .await;
+
+    let Ok(screenshot) = processed else {
+      warn!("applying screenshot effects panicked");
+      return;
+    };
+
+    if let Some(picture) = weak.upgrade() {
+      picture.set_paintable(Some(&screenshot.into_texture()));
+    }
+  });
+
+  picture
+}
+
+impl ShmHandler for ScreenshotState {
+  fn shm_state(&mut self) -> &mut Shm {
+    &mut self.shm
+  }
+}
+
+impl Dispatch<WlRegistry, GlobalListContents> for ScreenshotState {
+  fn event(
+    _state: &mut Self,
+    _proxy: &WlRegistry,
+    _event: <WlRegistry as Proxy>::Event,
+    _data: &GlobalListContents,
+    _conn: &Connection,
+    _qh: &QueueHandle<Self>,
+  ) {
+  }
+}
+
+impl Dispatch<ZwlrScreencopyFrameV1, usize> for ScreenshotState {
+  fn event(
+    state: &mut Self,
+    proxy: &ZwlrScreencopyFrameV1,
+    event: zwlr_screencopy_frame_v1::Event,
+    index: &usize,
+    _conn: &Connection,
+    qh: &QueueHandle<Self>,
+  ) {
+    let capture = &mut state.captures[*index];
+    match event {
+      zwlr_screencopy_frame_v1::Event::Buffer {
+        format: WEnum::Value(format @ (Format::Argb8888 | Format::Xrgb8888)),
+        width,
+        height,
+        stride,
+      } => {
+        capture.buffer_info = Some((format, width as i32, height as i32, stride as i32));
+
+        // Before version 3, there is no buffer_done and only shm is offered
+        if proxy.version() < 3 {
+          state.copy(*index, qh);
+        }
+      }
+      zwlr_screencopy_frame_v1::Event::BufferDone => state.copy(*index, qh),
+      zwlr_screencopy_frame_v1::Event::Flags { flags } => {
+        capture.y_invert = matches!(
+          flags,
+          WEnum::Value(flags) if flags.contains(zwlr_screencopy_frame_v1::Flags::YInvert)
+        );
+      }
+      zwlr_screencopy_frame_v1::Event::Ready { .. } => capture.state = CaptureState::Ready,
+      zwlr_screencopy_frame_v1::Event::Failed => capture.state = CaptureState::Failed,
+      _ => {}
+    }
+  }
+}
+
+impl Dispatch<ExtImageCopyCaptureSessionV1, usize> for ScreenshotState {
+  fn event(
+    state: &mut Self,
+    _proxy: &ExtImageCopyCaptureSessionV1,
+    event: ext_image_copy_capture_session_v1::Event,
+    index: &usize,
+    _conn: &Connection,
+    qh: &QueueHandle<Self>,
+  ) {
+    let capture = &mut state.captures[*index];
+    let Frame::Ext { size, .. } = &mut capture.frame else {
+      return;
+    };
+
+    match event {
+      ext_image_copy_capture_session_v1::Event::BufferSize { width, height } => {
+        *size = Some((width as i32, height as i32));
+      }
+      ext_image_copy_capture_session_v1::Event::ShmFormat {
+        format: WEnum::Value(format @ (Format::Argb8888 | Format::Xrgb8888)),
+      } => {
+        // The stride follows from the size, which may come later
+        if capture.buffer_info.is_none() {
+          capture.buffer_info = Some((format, 0, 0, 0));
+        }
+      }
+      ext_image_copy_capture_session_v1::Event::Done => {
+        capture.buffer_info = match (capture.buffer_info, *size) {
+          (Some((format, ..)), Some((width, height))) => Some((format, width, height, width * 4)),
+          _ => None,
+        };
+        state.copy(*index, qh);
+      }
+      ext_image_copy_capture_session_v1::Event::Stopped => {
+        if capture.state != CaptureState::Ready {
+          capture.state = CaptureState::Failed;
+        }
+      }
+      _ => {}
+    }
+  }
+}
+
+impl Dispatch<ExtImageCopyCaptureFrameV1, usize> for ScreenshotState {
+  fn event(
+    state: &mut Self,
+    _proxy: &ExtImageCopyCaptureFrameV1,
+    event: ext_image_copy_capture_frame_v1::Event,
+    index: &usize,
+    _conn: &Connection,
+    _qh: &QueueHandle<Self>,
+  ) {
+    let capture = &mut state.captures[*index];
+    match event {
+      ext_image_copy_capture_frame_v1::Event::Ready => capture.state = CaptureState::Ready,
+      ext_image_copy_capture_frame_v1::Event::Failed { reason } => {
+        warn!("compositor failed to capture output: {reason:?}");
+        capture.state = CaptureState::Failed;
+      }
+      _ => {}
+    }
+  }
+}
+
+smithay_client_toolkit::delegate_shm!(ScreenshotState);
+wayland_client::delegate_noop!(ScreenshotState: ZwlrScreencopyManagerV1);
+wayland_client::delegate_noop!(ScreenshotState: ExtOutputImageCaptureSourceManagerV1);
+wayland_client::delegate_noop!(ScreenshotState: ExtImageCopyCaptureManagerV1);
+wayland_client::delegate_noop!(ScreenshotState: ExtImageCaptureSourceV1);
+wayland_client::delegate_noop!(ScreenshotState: ignore WlBuffer);
@@ -0,0 +1,18 @@
+//! Where the control socket lives. Also compiled into dash3ctl, so it only
+//! depends on std and anyhow.
+
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Result};
+
+const DIR: &str = "dash3";
+const SOCKET: &str = "control.sock";
+
+/// `$XDG_RUNTIME_DIR/dash3/control.sock`. The directory is only accessible
+/// by the user.
+pub fn path() -> Result<PathBuf> {
+  let runtime_dir =
+    std::env::var_os("XDG_RUNTIME_DIR").ok_or_else(|| anyhow!("XDG_RUNTIME_DIR is not set"))?;
+
+  Ok(PathBuf::from(runtime_dir).join(DIR).join(SOCKET))
+}
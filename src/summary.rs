@@ -0,0 +1,261 @@
+use std::{
+  cell::RefCell,
+  collections::HashMap,
+  sync::Arc,
+  time::{Duration, SystemTime},
+};
+
+use anyhow::Result;
+use gtk4::{gio, glib, prelude::*};
+use tracing::{info, warn};
+
+use crate::{
+  clock,
+  config::Config,
+  locker::{Activity, LockEvent, Locker},
+  logging,
+};
+
+const NOTIFICATIONS_NAME: &str = "org.freedesktop.Notifications";
+const NOTIFICATIONS_PATH: &str = "/org/freedesktop/Notifications";
+const NOTIFICATIONS: &str = "org.freedesktop.Notifications";
+const NOTIFICATION_ICON: &str = "system-lock-screen";
+
+/// Failed attempts listed by time, older ones are only counted
+const MAX_LISTED_FAILURES: usize = 5;
+
+thread_local! {
+  static WIDGETS: RefCell<Vec<Widget>> = const { RefCell::new(Vec::new()) };
+}
+
+fn local_time(time: SystemTime) -> Option<glib::DateTime> {
+  let secs = time.duration_since(SystemTime::UNIX_EPOCH).ok()?.as_secs();
+  glib::DateTime::from_unix_local(secs as i64).ok()
+}
+
+/// A title like "Locked for 2h 12m" and a line for each kind of activity.
+/// With `redact`, PAM's messages are masked like in the log.
+fn describe(config: &Config, activity: &Activity, redact: bool) -> (String, Vec<String>) {
+  let unlocked_at = activity.unlocked_at.unwrap_or_else(SystemTime::now);
+  let title = match activity.locked_at {
+    Some(locked_at) => {
+      let locked_for = unlocked_at.duration_since(locked_at).unwrap_or_default();
+      let elapsed = glib::TimeSpan::from_seconds(locked_for.as_secs() as i64);
+      format!("Locked for {}", clock::format_elapsed(elapsed))
+    }
+    None => "Unlocked".to_string(),
+  };
+
+  let mut lines = Vec::new();
+  let failures = &activity.failures;
+  if failures.is_empty() {
+    lines.push("No failed attempts".to_string());
+  } else {
+    let mut times: Vec<String> = failures
+      .iter()
+      .rev()
+      .take(MAX_LISTED_FAILURES)
+      .rev()
+      .filter_map(|failure| local_time(failure.at))
+      .map(|time| clock::short_time(&config.clock, &time))
+      .collect();
+    if failures.len() > MAX_LISTED_FAILURES {
+      times.insert(0, "…".to_string());
+    }
+
+    lines.push(match failures.len() {
+      1 => format!("1 failed attempt at {}", times.join(", ")),
+      count => format!("{count} failed attempts at {}", times.join(", ")),
+    });
+  }
+
+  // The same message usually repeats with every attempt
+  let mut errors: Vec<(&str, usize)> = Vec::new();
+  let messages = activity
+    .pam_errors
+    .iter()
+    .chain(failures.iter().map(|failure| &failure.error));
+  for message in messages {
+    match errors
+      .iter_mut()
+      .find(|(error, _)| *error == message.as_str())
+    {
+      Some((_, count)) => *count += 1,
+      None => errors.push((message.as_str(), 1)),
+    }
+  }
+
+  lines.extend(errors.into_iter().map(|(error, count)| {
+    let error = if redact {
+      logging::sanitize(error)
+    } else {
+      error.to_string()
+    };
+
+    match count {
+      1 => error,
+      count => format!("{error} (×{count})"),
+    }
+  }));
+
+  (title, lines)
+}
+
+fn should_summarize(config: &Config, activity: &Activity) -> bool {
+  !config.summary.only_on_failure || activity.has_failures()
+}
+
+/// The summary card of one window
+struct Widget {
+  card: glib::WeakRef<gtk4::Box>,
+  title: gtk4::Label,
+  body: gtk4::Label,
+}
+
+/// Shows the summary on all lock windows. Returns how long the unlock should
+/// wait for it to be read, or `None` if it isn't shown.
+pub fn show_overlay(config: &Config, activity: &Activity) -> Option<Duration> {
+  if !config.summary.overlay || !should_summarize(config, activity) {
+    return None;
+  }
+
+  let (title, lines) = describe(config, activity, false);
+  let shown = WIDGETS.with_borrow_mut(|widgets| {
+    widgets.retain(|widget| widget.card.upgrade().is_some());
+    for widget in widgets.iter() {
+      let Some(card) = widget.card.upgrade() else {
+        continue;
+      };
+
+      widget.title.set_label(&title);
+      widget.body.set_label(&lines.join("\n"));
+      card.set_visible(true);
+    }
+
+    !widgets.is_empty()
+  });
+
+  shown.then(|| Duration::from_millis(*config.summary.overlay_duration_ms.get_ref()))
+}
+
+/// Layers the hidden summary card over the contents of a lock window
+pub fn overlay(content: &impl IsA<gtk4::Widget>) -> gtk4::Overlay {
+  let title = gtk4::Label::builder()
+    .css_classes(["unlock-summary-title"])
+    .build();
+  let body = gtk4::Label::builder()
+    .css_classes(["unlock-summary-body"])
+    .justify(gtk4::Justification::Center)
+    .wrap(true)
+    .build();
+
+  let card = gtk4::Box::builder()
+    .orientation(gtk4::Orientation::Vertical)
+    .halign(gtk4::Align::Center)
+    .valign(gtk4::Align::Center)
+    .spacing(8)
+    .css_classes(["unlock-summary"])
+    .visible(false)
+    .build();
+  card.append(&title);
+  card.append(&body);
+
+  let overlay = gtk4::Overlay::builder().child(content).build();
+  overlay.add_overlay(&card);
+
+  WIDGETS.with_borrow_mut(|widgets| {
+    widgets.push(Widget {
+      card: card.downgrade(),
+      title,
+      body,
+    })
+  });
+
+  overlay
+}
+
+async fn notify(summary: &str, body: &str) -> Result<()> {
+  let conn = gio::bus_get_future(gio::BusType::Session).await?;
+  conn
+    .call_future(
+      Some(NOTIFICATIONS_NAME),
+      NOTIFICATIONS_PATH,
+      NOTIFICATIONS,
+      "Notify",
+      Some(
+        &(
+          "dash3",
+          0u32,
+          NOTIFICATION_ICON,
+          summary,
+          body,
+          Vec::<String>::new(),
+          HashMap::<String, glib::Variant>::new(),
+          -1i32,
+        )
+          .to_variant(),
+      ),
+      Some(glib::VariantTy::new("(u)")?),
+      gio::DBusCallFlags::NONE,
+      -1,
+    )
+    .await?;
+
+  Ok(())
+}
+
+/// Sends the summary as a desktop notification after each unlock. PAM's
+/// messages leave the lock screen this way, so they are only sent verbatim
+/// if the PAM transcript is logged too.
+pub fn follow(locker: &Locker, config: Arc<Config>) {
+  if !config.summary.notify {
+    return;
+  }
+
+  let locker = locker.clone();
+  let events = locker.subscribe();
+  glib::spawn_future_local(async move {
+    while let Ok(event) = events.recv_async().await {
+      if !matches!(event, LockEvent::Unlocked) {
+        continue;
+      }
+
+      let activity = locker.activity();
+      if !should_summarize(&config, &activity) {
+        continue;
+      }
+
+      let redact = !config.logging.pam_transcript;
+      let (title, lines) = describe(&config, &activity, redact);
+      info!("sending unlock summary: {title}");
+      glib::spawn_future_local(async move {
+        if let Err(err) = notify(&title, &lines.join("\n")).await {
+          warn!("failed to send the unlock summary notification: {err}");
+        }
+      });
+    }
+  });
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn notifications_redact_pam_messages() {
+    let config = Config::default();
+    let activity = Activity {
+      pam_errors: vec!["Code sent to alice@example.com, 2 tries left".to_string()],
+      ..Activity::default()
+    };
+
+    let (_, lines) = describe(&config, &activity, true);
+    assert_eq!(
+      lines,
+      ["No failed attempts", "Code sent to <redacted> # tries left"]
+    );
+
+    let (_, lines) = describe(&config, &activity, false);
+    assert_eq!(lines[1], activity.pam_errors[0]);
+  }
+}
@@ -0,0 +1,123 @@
+//! A private message bus for tests of the D-Bus clients, so fake services can
+//! be registered without touching the system or session bus
+
+use std::{
+  ffi::CStr,
+  future::Future,
+  sync::{Mutex, MutexGuard},
+};
+
+use gtk4::{gio, glib, prelude::*};
+
+/// `g_test_dbus_up` points `DBUS_SESSION_BUS_ADDRESS` at the bus it started,
+/// so buses are brought up one at a time
+static BUS_LOCK: Mutex<()> = Mutex::new(());
+
+/// A dbus-daemon started through GTestDBus, stopped on drop
+pub struct TestBus {
+  bus: *mut gio::ffi::GTestDBus,
+  _lock: MutexGuard<'static, ()>,
+}
+
+impl TestBus {
+  pub fn up() -> Self {
+    let lock = BUS_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+    unsafe {
+      let bus = gio::ffi::g_test_dbus_new(gio::ffi::G_TEST_DBUS_NONE);
+      gio::ffi::g_test_dbus_up(bus);
+      TestBus { bus, _lock: lock }
+    }
+  }
+
+  fn address(&self) -> String {
+    unsafe {
+      let address = gio::ffi::g_test_dbus_get_bus_address(self.bus);
+      CStr::from_ptr(address).to_string_lossy().into_owned()
+    }
+  }
+
+  pub async fn connect(&self) -> gio::DBusConnection {
+    gio::DBusConnection::for_address_future(
+      &self.address(),
+      gio::DBusConnectionFlags::AUTHENTICATION_CLIENT
+        | gio::DBusConnectionFlags::MESSAGE_BUS_CONNECTION,
+      None,
+    )
+    .await
+    .expect("failed to connect to the test bus")
+  }
+}
+
+impl Drop for TestBus {
+  fn drop(&mut self) {
+    unsafe {
+      gio::ffi::g_test_dbus_down(self.bus);
+      glib::gobject_ffi::g_object_unref(self.bus as *mut _);
+    }
+  }
+}
+
+/// Takes `name` on the bus, so clients calling it reach `conn`
+pub async fn own_name(conn: &gio::DBusConnection, name: &str) {
+  // DBUS_NAME_FLAG_DO_NOT_QUEUE
+  conn
+    .call_future(
+      Some("org.freedesktop.DBus"),
+      "/org/freedesktop/DBus",
+      "org.freedesktop.DBus",
+      "RequestName",
+      Some(&(name, 4u32).to_variant()),
+      Some(glib::VariantTy::new("(u)").unwrap()),
+      gio::DBusCallFlags::NONE,
+      -1,
+    )
+    .await
+    .expect("failed to own the name");
+}
+
+/// Exports the interface described by `xml` at `path`, answering calls with
+/// `handle(method, params)`. A `None` reply returns a D-Bus error.
+pub fn export(
+  conn: &gio::DBusConnection,
+  path: &str,
+  xml: &str,
+  handle: impl Fn(&str, glib::Variant) -> Option<glib::Variant> + 'static,
+) -> gio::RegistrationId {
+  export_with_properties(conn, path, xml, handle, |property| {
+    panic!("unexpected property {property}")
+  })
+}
+
+/// Like `export`, also answering reads of the properties in `xml` with
+/// `property(name)`. GDBus implements `org.freedesktop.DBus.Properties` on
+/// top of it.
+pub fn export_with_properties(
+  conn: &gio::DBusConnection,
+  path: &str,
+  xml: &str,
+  handle: impl Fn(&str, glib::Variant) -> Option<glib::Variant> + 'static,
+  property: impl Fn(&str) -> glib::Variant + 'static,
+) -> gio::RegistrationId {
+  let node = gio::DBusNodeInfo::for_xml(xml).expect("invalid introspection XML");
+  let interface = node.interfaces()[0].clone();
+  conn
+    .register_object(path, &interface)
+    .method_call(
+      move |_, _, _, _, method, params, invocation| match handle(method, params) {
+        Some(reply) => invocation.return_value(Some(&reply)),
+        None => invocation.return_dbus_error("org.freedesktop.DBus.Error.Failed", method),
+      },
+    )
+    .property(move |_, _, _, _, name| property(name))
+    .build()
+    .expect("failed to export the fake service")
+}
+
+/// Runs `test` to completion on a fresh main context, which also dispatches
+/// the calls to exported objects
+pub fn run<F: Future<Output = ()>>(test: impl FnOnce() -> F) {
+  let context = glib::MainContext::new();
+  context
+    .with_thread_default(|| context.block_on(test()))
+    .expect("failed to acquire the main context");
+}
@@ -0,0 +1,506 @@
+use std::{
+  cell::{Cell, RefCell},
+  collections::HashSet,
+  fmt::Write as _,
+  io,
+  path::{Path, PathBuf},
+  rc::Rc,
+  sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, Result};
+use gtk4::{
+  gdk::Display, gio, glib, prelude::*, style_context_add_provider_for_display, CssProvider,
+  STYLE_PROVIDER_PRIORITY_APPLICATION, STYLE_PROVIDER_PRIORITY_USER,
+};
+use notify::Watcher;
+use tracing::{error, info, warn};
+
+use crate::{
+  assets::RESOURCE_PREFIX,
+  config::{self, Config, ThemeConfig},
+};
+
+/// The built-in theme with its default variables, compiled at build time. Used
+/// if the themed stylesheet somehow fails to compile.
+const DEFAULT_CSS: &str = grass::include!("src/styles.scss");
+const DEFAULT_SCSS: &str = include_str!("styles.scss");
+/// The built-in theme in the source tree, compiled instead of the embedded
+/// copy when previewing so it can be edited live
+const SOURCE_SCSS: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/src/styles.scss");
+
+const USER_STYLESHEET: &str = "style.scss";
+
+/// Colour schemes bundled as resources under `schemes/`
+pub const SCHEMES: [&str; 4] = ["dark", "light", "nord", "solarized"];
+
+const PORTAL_NAME: &str = "org.freedesktop.portal.Desktop";
+const PORTAL_PATH: &str = "/org/freedesktop/portal/desktop";
+const PORTAL_SETTINGS: &str = "org.freedesktop.portal.Settings";
+const APPEARANCE_NAMESPACE: &str = "org.freedesktop.appearance";
+const COLOR_SCHEME_KEY: &str = "color-scheme";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ColorScheme {
+  Dark,
+  Light,
+}
+
+impl ColorScheme {
+  /// Maps the portal's `color-scheme` value. dash3 is dark unless light is
+  /// explicitly preferred.
+  fn from_portal(value: u32) -> Self {
+    match value {
+      2 => ColorScheme::Light,
+      _ => ColorScheme::Dark,
+    }
+  }
+}
+
+/// Records every file grass reads, so `@use`d and `@import`ed files can be
+/// watched along with the stylesheet itself
+#[derive(Debug, Default)]
+struct TrackingFs {
+  read: RefCell<HashSet<PathBuf>>,
+}
+
+impl grass::Fs for TrackingFs {
+  fn is_dir(&self, path: &Path) -> bool {
+    grass::StdFs.is_dir(path)
+  }
+
+  fn is_file(&self, path: &Path) -> bool {
+    grass::StdFs.is_file(path)
+  }
+
+  fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+    self.read.borrow_mut().insert(canonical(path));
+    grass::StdFs.read(path)
+  }
+
+  fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+    grass::StdFs.canonicalize(path)
+  }
+}
+
+fn canonical(path: &Path) -> PathBuf {
+  std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+fn scheme_source(name: &str) -> String {
+  let path = format!("{RESOURCE_PREFIX}/schemes/{name}.scss");
+  match gio::resources_lookup_data(&path, gio::ResourceLookupFlags::NONE) {
+    Ok(data) => String::from_utf8_lossy(&data).into_owned(),
+    Err(err) => {
+      error!("failed to load scheme {name}: {err}");
+      String::new()
+    }
+  }
+}
+
+/// Generic families are keywords, quoting them would look for a font of that
+/// name
+const GENERIC_FAMILIES: [&str; 13] = [
+  "serif",
+  "sans-serif",
+  "monospace",
+  "cursive",
+  "fantasy",
+  "system-ui",
+  "emoji",
+  "math",
+  "fangsong",
+  "ui-serif",
+  "ui-sans-serif",
+  "ui-monospace",
+  "ui-rounded",
+];
+
+/// Quotes each name of a comma-separated font family list, like
+/// `Inter, sans-serif`, leaving generic families as they are
+fn font_family(family: &str) -> String {
+  family
+    .split(',')
+    .map(str::trim)
+    .filter(|name| !name.is_empty())
+    .map(|name| {
+      if GENERIC_FAMILIES.contains(&name.to_ascii_lowercase().as_str()) {
+        name.to_string()
+      } else {
+        format!("\"{name}\"")
+      }
+    })
+    .collect::<Vec<_>>()
+    .join(", ")
+}
+
+/// SCSS variable declarations for the selected scheme and any overrides from
+/// the config, prepended to every stylesheet
+fn variables(theme: &ThemeConfig, preference: ColorScheme) -> String {
+  let scheme = theme.scheme.as_ref().unwrap_or(match preference {
+    ColorScheme::Dark => &theme.dark_scheme,
+    ColorScheme::Light => &theme.light_scheme,
+  });
+
+  let mut vars = scheme_source(scheme.get_ref());
+  for (name, value) in [
+    ("accent", &theme.accent),
+    ("background", &theme.background),
+    ("foreground", &theme.foreground),
+  ] {
+    if let Some(value) = value {
+      let _ = writeln!(vars, "${name}: {};", value.get_ref());
+    }
+  }
+
+  if let Some(radius) = theme.radius {
+    let _ = writeln!(vars, "$radius: {radius}px;");
+  }
+
+  if let Some(family) = &theme.font_family {
+    let _ = writeln!(vars, "$font-family: {};", font_family(family.get_ref()));
+  }
+
+  if let Some(size) = theme.font_size {
+    let _ = writeln!(vars, "$font-size: {size}pt;");
+  }
+
+  vars
+}
+
+/// Compiles a stylesheet with the theme variables prepended, returning the
+/// files it depends on even if compilation failed
+fn compile(path: &Path, vars: &str) -> (grass::Result<String>, HashSet<PathBuf>) {
+  let fs = TrackingFs::default();
+  let mut deps = HashSet::from([canonical(path)]);
+
+  let source = match std::fs::read_to_string(path) {
+    Ok(source) => source,
+    Err(err) => {
+      let err = io::Error::new(err.kind(), format!("{}: {err}", path.display()));
+      return (Err(err.into()), deps);
+    }
+  };
+
+  let mut options = grass::Options::default().fs(&fs);
+  if let Some(dir) = path.parent() {
+    options = options.load_path(dir);
+  }
+
+  let css = grass::from_string(format!("{vars}\n{source}"), &options);
+  deps.extend(fs.read.into_inner());
+  (css, deps)
+}
+
+/// The configured user stylesheet, or the default one if it exists
+pub fn user_stylesheet(config: &Config) -> Option<PathBuf> {
+  match &config.appearance.stylesheet {
+    Some(path) => Some(path.get_ref().clone()),
+    None => config::user_dir()
+      .map(|dir| dir.join(USER_STYLESHEET))
+      .filter(|path| path.is_file()),
+  }
+}
+
+/// Reads the dark preference from GTK's settings.ini, for systems without a
+/// settings portal
+fn settings_file_preference() -> Option<ColorScheme> {
+  let path = config::config_home()?.join("gtk-4.0/settings.ini");
+  let settings = std::fs::read_to_string(path).ok()?;
+
+  settings.lines().find_map(|line| {
+    let (key, value) = line.split_once('=')?;
+    if key.trim() != "gtk-application-prefer-dark-theme" {
+      return None;
+    }
+
+    match value.trim() {
+      "1" | "true" => Some(ColorScheme::Dark),
+      "0" | "false" => Some(ColorScheme::Light),
+      _ => None,
+    }
+  })
+}
+
+async fn portal_preference(conn: &gio::DBusConnection) -> Result<ColorScheme> {
+  let reply = conn
+    .call_future(
+      Some(PORTAL_NAME),
+      PORTAL_PATH,
+      PORTAL_SETTINGS,
+      "ReadOne",
+      Some(&(APPEARANCE_NAMESPACE, COLOR_SCHEME_KEY).to_variant()),
+      Some(glib::VariantTy::new("(v)")?),
+      gio::DBusCallFlags::NONE,
+      -1,
+    )
+    .await?;
+
+  let (value,) = reply
+    .get::<(glib::Variant,)>()
+    .ok_or_else(|| anyhow!("unexpected reply {reply}"))?;
+  let value = value
+    .get::<u32>()
+    .ok_or_else(|| anyhow!("unexpected color-scheme {value}"))?;
+
+  Ok(ColorScheme::from_portal(value))
+}
+
+/// A stylesheet on disk, recompiled when it or one of its dependencies changes
+struct WatchedStylesheet {
+  path: PathBuf,
+  provider: CssProvider,
+  deps: Arc<Mutex<HashSet<PathBuf>>>,
+  watcher: RefCell<Option<notify::RecommendedWatcher>>,
+  // Directories are watched rather than files, since editors usually
+  // replace files when saving
+  watched_dirs: RefCell<HashSet<PathBuf>>,
+}
+
+struct Theme {
+  config: ThemeConfig,
+  preference: Cell<ColorScheme>,
+  default_provider: CssProvider,
+  /// The built-in theme from the source tree, when previewing
+  source: Option<WatchedStylesheet>,
+  user: Option<WatchedStylesheet>,
+}
+
+impl Theme {
+  fn reload(&self) {
+    let vars = variables(&self.config, self.preference.get());
+
+    if let Some(source) = &self.source {
+      source.reload(&vars);
+    } else {
+      self.reload_default(&vars);
+    }
+
+    if let Some(user) = &self.user {
+      user.reload(&vars);
+    }
+  }
+
+  fn reload_default(&self, vars: &str) {
+    match grass::from_string(
+      format!("{vars}\n{DEFAULT_SCSS}"),
+      &grass::Options::default(),
+    ) {
+      Ok(css) => self.default_provider.load_from_string(&css),
+      Err(err) => {
+        error!("failed to compile the built-in theme: {err}");
+        self.default_provider.load_from_string(DEFAULT_CSS);
+      }
+    }
+  }
+
+  fn set_preference(&self, preference: ColorScheme) {
+    if self.preference.replace(preference) != preference {
+      info!("switching to {preference:?} colour scheme");
+      self.reload();
+    }
+  }
+}
+
+impl WatchedStylesheet {
+  fn new(path: PathBuf, provider: CssProvider) -> Self {
+    WatchedStylesheet {
+      path,
+      provider,
+      deps: Arc::default(),
+      watcher: RefCell::new(None),
+      watched_dirs: RefCell::default(),
+    }
+  }
+
+  /// Recompiles the stylesheet. If it fails to compile, the last good CSS
+  /// stays loaded.
+  fn reload(&self, vars: &str) {
+    let (css, deps) = compile(&self.path, vars);
+    match css {
+      Ok(css) => {
+        info!("loaded {}", self.path.display());
+        self.provider.load_from_string(&css);
+      }
+      Err(err) => error!("failed to compile {}: {err}", self.path.display()),
+    }
+
+    if let Some(watcher) = self.watcher.borrow_mut().as_mut() {
+      let dirs = deps
+        .iter()
+        .filter_map(|dep| dep.parent())
+        .map(Path::to_path_buf)
+        .collect::<HashSet<_>>();
+
+      // Imports that were removed are no longer followed
+      let mut watched_dirs = self.watched_dirs.borrow_mut();
+      for dir in watched_dirs.difference(&dirs) {
+        let _ = watcher.unwatch(dir);
+      }
+      watched_dirs.retain(|dir| dirs.contains(dir));
+
+      for dir in dirs {
+        if watched_dirs.contains(&dir) {
+          continue;
+        }
+
+        match watcher.watch(&dir, notify::RecursiveMode::NonRecursive) {
+          Ok(()) => {
+            watched_dirs.insert(dir);
+          }
+          Err(err) => warn!("failed to watch {}: {err}", dir.display()),
+        }
+      }
+    }
+
+    *self.deps.lock().unwrap() = deps;
+  }
+}
+
+fn watch_stylesheet(theme: &Rc<Theme>, select: fn(&Theme) -> Option<&WatchedStylesheet>) {
+  let Some(sheet) = select(theme) else {
+    return;
+  };
+
+  let (reload_tx, reload_rx) = flume::unbounded::<()>();
+  let deps = sheet.deps.clone();
+  let watcher = notify::recommended_watcher(move |ev: notify::Result<notify::Event>| match ev {
+    Ok(ev) => {
+      if ev.kind.is_access() {
+        return;
+      }
+
+      let deps = deps.lock().unwrap();
+      if ev.paths.iter().any(|path| deps.contains(path)) {
+        let _ = reload_tx.send(());
+      }
+    }
+    Err(err) => warn!("stylesheet watcher error: {err}"),
+  });
+
+  match watcher {
+    Ok(watcher) => {
+      sheet.watcher.replace(Some(watcher));
+    }
+    Err(err) => {
+      warn!("failed to watch stylesheets, hot reload is disabled: {err}");
+      return;
+    }
+  }
+
+  let theme = theme.clone();
+  glib::spawn_future_local(async move {
+    while let Ok(()) = reload_rx.recv_async().await {
+      // A single save often produces several events
+      while reload_rx.try_recv().is_ok() {}
+      if let Some(sheet) = select(&theme) {
+        sheet.reload(&variables(&theme.config, theme.preference.get()));
+      }
+    }
+  });
+}
+
+/// Follows the system's light or dark preference through the settings portal
+fn follow_preference(theme: &Rc<Theme>) {
+  let theme = theme.clone();
+  glib::spawn_future_local(async move {
+    let conn = match gio::bus_get_future(gio::BusType::Session).await {
+      Ok(conn) => conn,
+      Err(err) => {
+        warn!("failed to connect to session bus: {err}");
+        return;
+      }
+    };
+
+    match portal_preference(&conn).await {
+      Ok(preference) => theme.set_preference(preference),
+      Err(err) => {
+        info!("settings portal unavailable: {err}");
+        return;
+      }
+    }
+
+    conn.signal_subscribe(
+      Some(PORTAL_NAME),
+      Some(PORTAL_SETTINGS),
+      Some("SettingChanged"),
+      Some(PORTAL_PATH),
+      Some(APPEARANCE_NAMESPACE),
+      gio::DBusSignalFlags::NONE,
+      move |_, _, _, _, _, params| {
+        let Some((_, key, value)) = params.get::<(String, String, glib::Variant)>() else {
+          return;
+        };
+
+        if key != COLOR_SCHEME_KEY {
+          return;
+        }
+
+        if let Some(value) = value.get::<u32>() {
+          theme.set_preference(ColorScheme::from_portal(value));
+        }
+      },
+    );
+  });
+}
+
+/// Installs the built-in theme and the user stylesheet on top of it. Both are
+/// compiled with the colour scheme and theme variables from the config, and
+/// recompiled when the system switches between light and dark. The user
+/// stylesheet is also recompiled whenever it or one of its dependencies
+/// changes. When previewing from a source checkout, so is the built-in theme.
+pub fn load(display: &Display, config: &Config, preview: bool) {
+  let default_provider = CssProvider::new();
+  style_context_add_provider_for_display(
+    display,
+    &default_provider,
+    STYLE_PROVIDER_PRIORITY_APPLICATION,
+  );
+
+  let user = user_stylesheet(config).map(|path| {
+    let provider = CssProvider::new();
+    style_context_add_provider_for_display(display, &provider, STYLE_PROVIDER_PRIORITY_USER);
+
+    WatchedStylesheet::new(path, provider)
+  });
+
+  let source = Some(PathBuf::from(SOURCE_SCSS))
+    .filter(|path| preview && path.is_file())
+    .map(|path| {
+      info!("previewing the built-in theme from {}", path.display());
+      // Stays loaded until the source compiles
+      default_provider.load_from_string(DEFAULT_CSS);
+      WatchedStylesheet::new(path, default_provider.clone())
+    });
+
+  let theme = Rc::new(Theme {
+    config: config.theme.clone(),
+    preference: Cell::new(settings_file_preference().unwrap_or(ColorScheme::Dark)),
+    default_provider,
+    source,
+    user,
+  });
+
+  watch_stylesheet(&theme, |theme| theme.source.as_ref());
+  watch_stylesheet(&theme, |theme| theme.user.as_ref());
+  theme.reload();
+
+  if config.theme.scheme.is_none() {
+    follow_preference(&theme);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn font_family_quotes_each_name_but_generic_families() {
+    assert_eq!(font_family("Inter"), "\"Inter\"");
+    assert_eq!(
+      font_family("Noto Sans, Inter,sans-serif"),
+      "\"Noto Sans\", \"Inter\", sans-serif"
+    );
+    assert_eq!(font_family("Monospace"), "Monospace");
+  }
+}
@@ -0,0 +1,441 @@
+use std::{
+  cell::RefCell,
+  collections::HashMap,
+  path::{Path, PathBuf},
+  time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Result};
+use gtk4::{
+  gdk,
+  gdk_pixbuf::{Colorspace, InterpType, Pixbuf},
+  gio, glib,
+  prelude::*,
+};
+use smithay_client_toolkit::output::OutputInfo;
+use tracing::warn;
+
+use crate::{
+  animation,
+  config::{Config, WallpaperConfig, WallpaperMode, WallpaperRotation},
+  effects::Pixels,
+};
+
+const IMAGE_EXTENSIONS: [&str; 6] = ["png", "jpg", "jpeg", "webp", "bmp", "gif"];
+
+/// Upper bound on how long a rotating wallpaper may lag behind the image it
+/// should be showing
+const ROTATION_CHECK: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct CacheKey {
+  /// Index of the `[[wallpaper]]` entry
+  entry: usize,
+  width: i32,
+  height: i32,
+}
+
+thread_local! {
+  /// The last image rendered for each wallpaper entry and output size
+  static CACHE: RefCell<HashMap<CacheKey, (PathBuf, gdk::Texture)>> = RefCell::new(HashMap::new());
+}
+
+/// Still images in a directory, sorted by file name
+pub fn images(dir: &Path) -> Vec<PathBuf> {
+  let Ok(entries) = std::fs::read_dir(dir) else {
+    return Vec::new();
+  };
+
+  let mut images: Vec<_> = entries
+    .filter_map(|entry| entry.ok())
+    .map(|entry| entry.path())
+    .filter(|path| {
+      path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| IMAGE_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()))
+    })
+    .collect();
+
+  images.sort();
+  images
+}
+
+/// The image that should be showing right now. It is derived from the clock,
+/// so all outputs agree and the next lock continues where the last one left
+/// off.
+fn current_image(wallpaper: &WallpaperConfig) -> Option<PathBuf> {
+  let path = wallpaper.path.get_ref();
+  if !path.is_dir() {
+    return Some(path.clone());
+  }
+
+  let images = images(path);
+  if images.is_empty() {
+    return None;
+  }
+
+  let index = match wallpaper.rotation {
+    WallpaperRotation::Slideshow => {
+      let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+      (secs / wallpaper.interval()) as usize % images.len()
+    }
+    WallpaperRotation::TimeOfDay => {
+      let now = glib::DateTime::now_local().ok()?;
+      let secs = now.hour() * 3600 + now.minute() * 60 + now.second();
+      secs as usize * images.len() / 86400
+    }
+  };
+
+  images.into_iter().nth(index)
+}
+
+/// The size of the output in physical pixels
+fn output_size(output: &OutputInfo) -> Option<(i32, i32)> {
+  if let Some((width, height)) = output.logical_size {
+    return Some((width * output.scale_factor, height * output.scale_factor));
+  }
+
+  output
+    .modes
+    .iter()
+    .find(|mode| mode.current)
+    .map(|mode| mode.dimensions)
+}
+
+/// Where a scaled image lands on the canvas
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Placement {
+  /// The visible area, as x, y, width and height on the canvas
+  dest: (i32, i32, i32, i32),
+  /// Position of the scaled image's top left corner, which may be outside
+  /// the canvas
+  offset: (f64, f64),
+  scale: f64,
+}
+
+/// How much `mode` scales an image of `image` size, as width and height, on
+/// a `canvas`. Tiles are not scaled.
+fn scale(mode: WallpaperMode, image: (f64, f64), canvas: (f64, f64)) -> f64 {
+  let (x, y) = (canvas.0 / image.0, canvas.1 / image.1);
+  match mode {
+    WallpaperMode::Fill => x.max(y),
+    WallpaperMode::Fit => x.min(y),
+    WallpaperMode::Center | WallpaperMode::Tile => 1.0,
+  }
+}
+
+/// Centers the image scaled by `scale` on the canvas, clipped to it. None if
+/// nothing of it is visible.
+fn place(image: (f64, f64), canvas: (f64, f64), scale: f64) -> Option<Placement> {
+  let (scaled_width, scaled_height) = (image.0 * scale, image.1 * scale);
+  let x = (canvas.0 - scaled_width) / 2.0;
+  let y = (canvas.1 - scaled_height) / 2.0;
+
+  let dest_x = x.max(0.0).round() as i32;
+  let dest_y = y.max(0.0).round() as i32;
+  let dest_width = (x + scaled_width).min(canvas.0).round() as i32 - dest_x;
+  let dest_height = (y + scaled_height).min(canvas.1).round() as i32 - dest_y;
+  (dest_width > 0 && dest_height > 0).then_some(Placement {
+    dest: (dest_x, dest_y, dest_width, dest_height),
+    offset: (x, y),
+    scale,
+  })
+}
+
+/// The copies of an image repeated from the top left corner of the canvas,
+/// as x, y, width and height, cut off at its edges
+fn tiles(image: (i32, i32), canvas: (i32, i32)) -> Vec<(i32, i32, i32, i32)> {
+  let (image_width, image_height) = (image.0.max(1), image.1.max(1));
+  (0..canvas.1)
+    .step_by(image_height as usize)
+    .flat_map(|y| {
+      (0..canvas.0).step_by(image_width as usize).map(move |x| {
+        (
+          x,
+          y,
+          image_width.min(canvas.0 - x),
+          image_height.min(canvas.1 - y),
+        )
+      })
+    })
+    .collect()
+}
+
+/// Scales the image at `path` onto a `width` by `height` canvas
+pub fn render(
+  path: &Path,
+  mode: WallpaperMode,
+  width: i32,
+  height: i32,
+  blur_radius: u32,
+  darken: f64,
+) -> Result<Pixels> {
+  let image = Pixbuf::from_file(path)?;
+  let image = image.apply_embedded_orientation().unwrap_or(image);
+  let image = image.add_alpha(false, 0, 0, 0)?;
+
+  let canvas = Pixbuf::new(Colorspace::Rgb, true, 8, width, height)
+    .ok_or_else(|| anyhow!("failed to allocate a {width}x{height} image"))?;
+  canvas.fill(0);
+
+  match mode {
+    WallpaperMode::Tile => {
+      for (x, y, tile_width, tile_height) in tiles((image.width(), image.height()), (width, height))
+      {
+        image.copy_area(0, 0, tile_width, tile_height, &canvas, x, y);
+      }
+    }
+    mode => {
+      let image_size = (image.width() as f64, image.height() as f64);
+      let canvas_size = (width as f64, height as f64);
+      if let Some(placement) = place(
+        image_size,
+        canvas_size,
+        scale(mode, image_size, canvas_size),
+      ) {
+        let (dest_x, dest_y, dest_width, dest_height) = placement.dest;
+        image.scale(
+          &canvas,
+          dest_x,
+          dest_y,
+          dest_width,
+          dest_height,
+          placement.offset.0,
+          placement.offset.1,
+          placement.scale,
+          placement.scale,
+          InterpType::Bilinear,
+        );
+      }
+    }
+  }
+
+  let mut pixels = Pixels {
+    width,
+    height,
+    stride: canvas.rowstride() as usize,
+    data: canvas.read_pixel_bytes().to_vec(),
+  };
+
+  pixels.blur(blur_radius);
+  pixels.darken(darken);
+  Ok(pixels)
+}
+
+/// Drops the images rendered for sizes none of `outputs` has anymore, like
+/// those of unplugged outputs or from before a resolution change. Must be
+/// called on the main thread.
+pub fn retain_cache(outputs: &[OutputInfo]) {
+  let sizes = outputs.iter().filter_map(output_size).collect::<Vec<_>>();
+  CACHE.with_borrow_mut(|cache| cache.retain(|key, _| sizes.contains(&(key.width, key.height))));
+}
+
+fn update(picture: &gtk4::Picture, wallpaper: &WallpaperConfig, key: CacheKey) {
+  let Some(path) = current_image(wallpaper) else {
+    return;
+  };
+
+  let cached = CACHE.with_borrow(|cache| {
+    cache
+      .get(&key)
+      .filter(|(cached, _)| *cached == path)
+      .map(|(_, texture)| texture.clone())
+  });
+
+  if let Some(texture) = cached {
+    picture.set_paintable(Some(&texture));
+    return;
+  }
+
+  let picture = picture.downgrade();
+  let mode = wallpaper.mode;
+  let blur_radius = wallpaper.blur.as_ref().map_or(0, |b| *b.get_ref());
+  let darken = wallpaper.darken.as_ref().map_or(0.0, |d| *d.get_ref());
+  glib::spawn_future_local(async move {
+    let render_path = path.clone();
+    let rendered = gio::spawn_blocking(move || {
+      render(
+        &render_path,
+        mode,
+        key.width,
+        key.height,
+        blur_radius,
+        darken,
+      )
+    })
+    .await;
+
+    let rendered = match rendered {
+      Ok(Ok(rendered)) => rendered,
+      Ok(Err(err)) => {
+        warn!("failed to load wallpaper {}: {err}", path.display());
+        return;
+      }
+      Err(_) => {
+        warn!("wallpaper renderer panicked on {}", path.display());
+        return;
+      }
+    };
+
+    let texture = rendered.into_texture();
+    CACHE.with_borrow_mut(|cache| cache.insert(key, (path, texture.clone())));
+
+    if let Some(picture) = picture.upgrade() {
+      picture.set_paintable(Some(&texture));
+    }
+  });
+}
+
+/// Renders the wallpaper of an unnamed output, or else the first one, at
+/// `width` by `height`. Animated wallpapers are left to `background`.
+pub fn render_still(config: &Config, width: i32, height: i32) -> Option<Result<Pixels>> {
+  let wallpaper = config
+    .wallpapers
+    .iter()
+    .find(|wallpaper| wallpaper.matches(None, None))
+    .or(config.wallpapers.first())?;
+
+  let path = current_image(wallpaper)?;
+  if animation::is_animated(&path) {
+    return None;
+  }
+
+  let blur_radius = wallpaper.blur.as_ref().map_or(0, |b| *b.get_ref());
+  let darken = wallpaper.darken.as_ref().map_or(0.0, |d| *d.get_ref());
+  Some(render(
+    &path,
+    wallpaper.mode,
+    width,
+    height,
+    blur_radius,
+    darken,
+  ))
+}
+
+/// An empty picture covering the window, for a texture rendered at the size
+/// of the output
+pub fn picture() -> gtk4::Picture {
+  gtk4::Picture::builder()
+    .can_shrink(true)
+    .content_fit(gtk4::ContentFit::Fill)
+    .css_classes(["wallpaper"])
+    .build()
+}
+
+/// Returns a picture showing the wallpaper configured for `output`, if any.
+/// Images are decoded and scaled in the background and shown once ready, and
+/// rotating wallpapers keep updating for as long as the picture exists.
+pub fn background(config: &Config, output: Option<&OutputInfo>) -> Option<gtk4::Picture> {
+  let name = output.and_then(|output| output.name.as_deref());
+  let description = output.and_then(|output| output.description.as_deref());
+  let (entry, wallpaper) = config
+    .wallpapers
+    .iter()
+    .enumerate()
+    .find(|(_, wallpaper)| wallpaper.matches(name, description))?;
+
+  let path = wallpaper.path.get_ref();
+  if path.is_file() && animation::is_animated(path) {
+    return Some(animation::background(
+      path,
+      wallpaper.mode,
+      name,
+      &config.animation,
+    ));
+  }
+
+  let Some((width, height)) = output.and_then(output_size) else {
+    warn!("size of output {name:?} is unknown, not showing a wallpaper");
+    return None;
+  };
+
+  let picture = picture();
+  let key = CacheKey {
+    entry,
+    width,
+    height,
+  };
+
+  update(&picture, wallpaper, key);
+
+  if wallpaper.path.get_ref().is_dir() {
+    let check = match wallpaper.rotation {
+      WallpaperRotation::Slideshow => Duration::from_secs(wallpaper.interval()).min(ROTATION_CHECK),
+      WallpaperRotation::TimeOfDay => ROTATION_CHECK,
+    };
+
+    let wallpaper = wallpaper.clone();
+    let picture = picture.downgrade();
+    glib::timeout_add_local(check, move || {
+      let Some(picture) = picture.upgrade() else {
+        return glib::ControlFlow::Break;
+      };
+
+      update(&picture, &wallpaper, key);
+      glib::ControlFlow::Continue
+    });
+  }
+
+  Some(picture)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn fill_covers_and_fit_shows_everything() {
+    let (image, canvas) = ((100.0, 50.0), (100.0, 100.0));
+
+    let fill = place(image, canvas, scale(WallpaperMode::Fill, image, canvas)).unwrap();
+    assert_eq!(
+      fill,
+      Placement {
+        dest: (0, 0, 100, 100),
+        offset: (-50.0, 0.0),
+        scale: 2.0,
+      }
+    );
+
+    let fit = place(image, canvas, scale(WallpaperMode::Fit, image, canvas)).unwrap();
+    assert_eq!(
+      fit,
+      Placement {
+        dest: (0, 25, 100, 50),
+        offset: (0.0, 25.0),
+        scale: 1.0,
+      }
+    );
+  }
+
+  #[test]
+  fn center_keeps_the_size() {
+    let (image, canvas) = ((40.0, 20.0), (100.0, 100.0));
+    let center = place(image, canvas, scale(WallpaperMode::Center, image, canvas)).unwrap();
+    assert_eq!(center.dest, (30, 40, 40, 20));
+
+    // Larger than the canvas, so only its middle shows
+    let center = place((300.0, 300.0), canvas, 1.0).unwrap();
+    assert_eq!(center.dest, (0, 0, 100, 100));
+    assert_eq!(center.offset, (-100.0, -100.0));
+  }
+
+  #[test]
+  fn tiles_are_cut_off_at_the_edges() {
+    assert_eq!(
+      tiles((40, 30), (100, 50)),
+      [
+        (0, 0, 40, 30),
+        (40, 0, 40, 30),
+        (80, 0, 20, 30),
+        (0, 30, 40, 20),
+        (40, 30, 40, 20),
+        (80, 30, 20, 20),
+      ]
+    );
+  }
+}